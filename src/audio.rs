@@ -1,56 +1,217 @@
-// use crate::actions::{set_movement_actions, Actions};
-// use crate::loading::AudioAssets;
-// use crate::GameState;
-// use bevy::prelude::*;
-// use bevy_kira_audio::prelude::*;
-//
-// pub struct InternalAudioPlugin;
-//
-// // This plugin is responsible to control the game audio
-// impl Plugin for InternalAudioPlugin {
-//     fn build(&self, app: &mut App) {
-//         app.add_plugins(AudioPlugin)
-//             .add_systems(OnEnter(GameState::Playing), start_audio)
-//             .add_systems(
-//                 Update,
-//                 control_flying_sound
-//                     .after(set_movement_actions)
-//                     .run_if(in_state(GameState::Playing)),
-//             );
-//     }
-// }
-//
-// #[derive(Resource)]
-// struct FlyingAudio(Handle<AudioInstance>);
-//
-// fn start_audio(mut commands: Commands, audio_assets: Res<AudioAssets>, audio: Res<Audio>) {
-//     audio.pause();
-//     let handle = audio
-//         .play(audio_assets.flying.clone())
-//         .looped()
-//         .with_volume(0.3)
-//         .handle();
-//     commands.insert_resource(FlyingAudio(handle));
-// }
-//
-// fn control_flying_sound(
-//     actions: Res<Actions>,
-//     audio: Res<FlyingAudio>,
-//     mut audio_instances: ResMut<Assets<AudioInstance>>,
-// ) {
-//     if let Some(instance) = audio_instances.get_mut(&audio.0) {
-//         match instance.state() {
-//             PlaybackState::Paused { .. } => {
-//                 if actions.player_movement.is_some() {
-//                     instance.resume(AudioTween::default());
-//                 }
-//             }
-//             PlaybackState::Playing { .. } => {
-//                 if actions.player_movement.is_none() {
-//                     instance.pause(AudioTween::default());
-//                 }
-//             }
-//             _ => {}
-//         }
-//     }
-// }
+use crate::loading::AudioAssets;
+use crate::GameState;
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
+use bevy_asset_loader::prelude::*;
+use bevy_kira_audio::prelude::*;
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::{
+    Confirmed, ConnectEvent, DisconnectEvent, Rollback, RollbackState,
+};
+
+/// Collisions with a smaller impulse than this are silent
+const IMPACT_MIN_IMPULSE: f32 = 5.0;
+/// Collisions with this impulse or more are played at full volume
+const IMPACT_MAX_IMPULSE: f32 = 150.0;
+/// The same pair of entities can't trigger a new impact sound before this delay
+const IMPACT_COOLDOWN: Duration = Duration::from_millis(150);
+
+pub struct InternalAudioPlugin;
+
+// This plugin is responsible to control the game audio: the music, the UI sounds and the volumes
+impl Plugin for InternalAudioPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(AudioPlugin)
+            .add_audio_channel::<Music>()
+            .add_audio_channel::<Sfx>()
+            .init_resource::<AudioVolumes>()
+            .add_systems(OnEnter(GameState::Menu), start_music)
+            .add_systems(
+                Update,
+                (
+                    apply_volumes.run_if(resource_changed::<AudioVolumes>),
+                    play_click_sound.run_if(resource_exists::<AudioAssets>),
+                ),
+            );
+    }
+}
+
+/// This plugin plays the sounds of the game itself: collisions, connection and disconnection
+pub struct GameAudioPlugin;
+
+impl Plugin for GameAudioPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<InternalAudioPlugin>() {
+            app.add_plugins(InternalAudioPlugin);
+        }
+        // the game app doesn't go through the loading state, so load the sounds directly
+        if !app.world.contains_resource::<AudioAssets>() {
+            app.init_collection::<AudioAssets>();
+        }
+        app.init_resource::<PendingImpacts>()
+            .init_resource::<RecentImpacts>()
+            .add_systems(Startup, start_music)
+            .add_systems(FixedPostUpdate, collect_impacts)
+            .add_systems(Update, (play_impact_sounds, play_connection_sounds));
+    }
+}
+
+#[derive(Resource)]
+pub struct Music;
+
+#[derive(Resource)]
+pub struct Sfx;
+
+/// Volumes between 0.0 and 1.0. The volume of each channel is multiplied by the master volume.
+/// They are changed in the menu, and given to the game app when it starts (see `game::run`)
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct AudioVolumes {
+    pub master: f64,
+    pub sfx: f64,
+    pub music: f64,
+}
+
+impl Default for AudioVolumes {
+    fn default() -> Self {
+        Self {
+            master: 0.8,
+            sfx: 1.0,
+            music: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeKind {
+    Master,
+    Sfx,
+    Music,
+}
+
+impl AudioVolumes {
+    pub fn get(&self, kind: VolumeKind) -> f64 {
+        match kind {
+            VolumeKind::Master => self.master,
+            VolumeKind::Sfx => self.sfx,
+            VolumeKind::Music => self.music,
+        }
+    }
+
+    /// Change a volume by `delta`, staying between 0.0 and 1.0
+    pub fn change(&mut self, kind: VolumeKind, delta: f64) {
+        let volume = match kind {
+            VolumeKind::Master => &mut self.master,
+            VolumeKind::Sfx => &mut self.sfx,
+            VolumeKind::Music => &mut self.music,
+        };
+        *volume = (*volume + delta).clamp(0.0, 1.0);
+    }
+}
+
+fn start_music(audio_assets: Res<AudioAssets>, music: Res<AudioChannel<Music>>) {
+    // the menu can be entered several times, but the music keeps playing
+    if music.is_playing_sound() {
+        return;
+    }
+    music.play(audio_assets.flying.clone()).looped();
+}
+
+fn apply_volumes(
+    volumes: Res<AudioVolumes>,
+    music: Res<AudioChannel<Music>>,
+    sfx: Res<AudioChannel<Sfx>>,
+) {
+    music.set_volume(volumes.master * volumes.music);
+    sfx.set_volume(volumes.master * volumes.sfx);
+}
+
+fn play_click_sound(
+    audio_assets: Res<AudioAssets>,
+    sfx: Res<AudioChannel<Sfx>>,
+    interactions: Query<&Interaction, (Changed<Interaction>, With<Button>)>,
+) {
+    for interaction in interactions.iter() {
+        if matches!(interaction, Interaction::Pressed) {
+            sfx.play(audio_assets.click.clone());
+        }
+    }
+}
+
+/// Collisions that happened on ticks that were simulated for the first time
+#[derive(Resource, Default)]
+struct PendingImpacts(Vec<(Entity, Entity, f32)>);
+
+/// When each pair of entities last played an impact sound
+#[derive(Resource, Default)]
+struct RecentImpacts(HashMap<(Entity, Entity), Duration>);
+
+/// Collect the collisions that started during this tick between entities that are displayed on screen.
+///
+/// During a rollback, the ticks that we already played are simulated again, and so are their collisions;
+/// we ignore those so that the sounds are not played twice.
+fn collect_impacts(
+    rollback: Option<Res<Rollback>>,
+    mut collisions: EventReader<Collision>,
+    displayed: Query<(), Without<Confirmed>>,
+    mut pending: ResMut<PendingImpacts>,
+) {
+    if rollback.is_some_and(|r| matches!(r.state, RollbackState::ShouldRollback { .. })) {
+        collisions.clear();
+        return;
+    }
+    for Collision(contacts) in collisions.read() {
+        if contacts.during_previous_frame {
+            // we only want a sound when the contact starts
+            continue;
+        }
+        if !displayed.contains(contacts.entity1) || !displayed.contains(contacts.entity2) {
+            continue;
+        }
+        pending.0.push((
+            contacts.entity1,
+            contacts.entity2,
+            contacts.total_normal_impulse.abs(),
+        ));
+    }
+}
+
+fn play_impact_sounds(
+    time: Res<Time>,
+    audio_assets: Res<AudioAssets>,
+    sfx: Res<AudioChannel<Sfx>>,
+    mut pending: ResMut<PendingImpacts>,
+    mut recent: ResMut<RecentImpacts>,
+) {
+    let now = time.elapsed();
+    recent
+        .0
+        .retain(|_, played_at| now.saturating_sub(*played_at) < IMPACT_COOLDOWN);
+    for (entity1, entity2, impulse) in pending.0.drain(..) {
+        if impulse < IMPACT_MIN_IMPULSE {
+            continue;
+        }
+        let pair = (entity1.min(entity2), entity1.max(entity2));
+        if recent.0.contains_key(&pair) {
+            continue;
+        }
+        recent.0.insert(pair, now);
+        let volume = ((impulse - IMPACT_MIN_IMPULSE) / (IMPACT_MAX_IMPULSE - IMPACT_MIN_IMPULSE))
+            .clamp(0.1, 1.0);
+        sfx.play(audio_assets.impact.clone())
+            .with_volume(volume as f64);
+    }
+}
+
+fn play_connection_sounds(
+    audio_assets: Res<AudioAssets>,
+    sfx: Res<AudioChannel<Sfx>>,
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+) {
+    for _ in connections.read() {
+        sfx.play(audio_assets.connect.clone());
+    }
+    for _ in disconnections.read() {
+        sfx.play(audio_assets.disconnect.clone());
+    }
+}
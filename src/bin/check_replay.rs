@@ -0,0 +1,32 @@
+//! Re-simulates saved replays to check that the simulation is still deterministic,
+//! see `bevy_game::determinism`
+use bevy_game::determinism::check_replay_file;
+use clap::Parser;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Replay files to check
+    #[arg(required = true)]
+    replays: Vec<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let mut failed = false;
+    for path in &args.replays {
+        match check_replay_file(path) {
+            Ok(frames) => println!("{}: ok, {frames} frames", path.display()),
+            Err(e) => {
+                eprintln!("{}: {e:#}", path.display());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
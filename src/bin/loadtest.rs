@@ -0,0 +1,7 @@
+//! Headless load test of the server, see `bevy_game::loadtest`
+use bevy_game::loadtest::{run_loadtest, LoadTestArgs};
+use clap::Parser;
+
+fn main() {
+    run_loadtest(LoadTestArgs::parse());
+}
@@ -0,0 +1,167 @@
+//! Server-side bots.
+//!
+//! A bot is a player without a network client: the AI writes its `ActionState<PlayerActions>`
+//! before the `movement` system runs, so it moves exactly like the players of the clients.
+//! Each bot defends one side of the arena: it goes behind the ball and pushes it towards the other side,
+//! while staying away from the walls.
+use bevy::prelude::*;
+use bevy_xpbd_2d::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::client::{Confirmed, Predicted};
+
+use super::protocol::*;
+use super::server::movement;
+use super::settings::{BotDifficulty, BotSettings, PhysicsSettings, ReplicationPolicy};
+use super::shared::{FixedSet, WALL_SIZE};
+
+/// The bots start to turn away from a wall when they are closer than this
+const WALL_MARGIN: f32 = PLAYER_SIZE * 1.5;
+/// Directions smaller than this don't press any key, so that the bots don't jitter around their target
+const DEAD_ZONE: f32 = 0.2;
+/// The bot pushes the ball when it is aligned with it within this angle (as a cosine)
+const ALIGNED_COS: f32 = 0.8;
+
+impl BotDifficulty {
+    /// Number of ticks between two decisions of the bot
+    fn reaction_ticks(self) -> u16 {
+        match self {
+            BotDifficulty::Easy => 16,
+            BotDifficulty::Medium => 8,
+            BotDifficulty::Hard => 2,
+        }
+    }
+
+    /// Maximum error on the point that the bot aims at, in world units
+    fn aim_error(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 60.0,
+            BotDifficulty::Medium => 25.0,
+            BotDifficulty::Hard => 5.0,
+        }
+    }
+}
+
+pub struct BotPlugin {
+    pub settings: BotSettings,
+}
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BotConfig(self.settings));
+        app.add_systems(Startup, spawn_bots);
+        app.add_systems(
+            FixedUpdate,
+            drive_bots.in_set(FixedSet::Main).before(movement),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct BotConfig(BotSettings);
+
+/// AI state of a bot; only exists on the server
+#[derive(Component)]
+pub struct Bot {
+    /// The side of the arena that the bot defends: -1.0 for the left, 1.0 for the right
+    pub side: f32,
+    pub difficulty: BotDifficulty,
+    /// Ticks until the next decision
+    cooldown: u16,
+    /// Random error added to the aim point, changed at every decision
+    aim_offset: Vec2,
+}
+
+fn spawn_bots(
+    mut commands: Commands,
+    config: Res<BotConfig>,
+    physics: Res<PhysicsSettings>,
+    policy: Res<ReplicationPolicy>,
+) {
+    let count = config.0.count;
+    for index in 0..count {
+        let side = if index % 2 == 0 { -1.0 } else { 1.0 };
+        // spread the bots of each side vertically
+        let row = (index / 2) as f32;
+        let rows = count.div_ceil(2) as f32;
+        let y = (row + 0.5) / rows * WALL_SIZE - WALL_SIZE / 2.0;
+        commands.spawn((
+            BotBundle::new(
+                index,
+                Vec2::new(side * WALL_SIZE / 2.0, y),
+                &physics,
+                &policy,
+            ),
+            Bot {
+                side,
+                difficulty: config.0.difficulty,
+                cooldown: 0,
+                aim_offset: Vec2::ZERO,
+            },
+        ));
+        info!(?index, ?side, difficulty = ?config.0.difficulty, "spawned bot");
+    }
+}
+
+/// Direction that the bot wants to move in
+fn bot_direction(bot: &Bot, position: Vec2, ball: Vec2) -> Vec2 {
+    // the bot pushes the ball towards the other side
+    let attack = Vec2::new(-bot.side, 0.0);
+    let to_ball = ball - position;
+    let target = if to_ball.normalize_or_zero().dot(attack) > ALIGNED_COS {
+        // behind the ball and aligned with it: push it
+        ball + bot.aim_offset
+    } else {
+        // go back behind the ball first
+        ball - attack * (BALL_SIZE + PLAYER_SIZE) + bot.aim_offset
+    };
+    let mut direction = (target - position).normalize_or_zero();
+
+    // steer away from the walls
+    let limit = WALL_SIZE - WALL_MARGIN;
+    if position.x > limit {
+        direction.x -= 1.0;
+    } else if position.x < -limit {
+        direction.x += 1.0;
+    }
+    if position.y > limit {
+        direction.y -= 1.0;
+    } else if position.y < -limit {
+        direction.y += 1.0;
+    }
+    direction
+}
+
+fn drive_bots(
+    balls: Query<&Position, (With<BallMarker>, Without<Confirmed>, Without<Predicted>)>,
+    mut bots: Query<(&mut Bot, &Position, &mut ActionState<PlayerActions>)>,
+) {
+    let Some(ball) = balls.iter().next() else {
+        return;
+    };
+    for (mut bot, position, mut action_state) in bots.iter_mut() {
+        if bot.cooldown > 0 {
+            bot.cooldown -= 1;
+            continue;
+        }
+        bot.cooldown = bot.difficulty.reaction_ticks();
+        let error = bot.difficulty.aim_error();
+        bot.aim_offset = Vec2::new(
+            rand::random::<f32>() * 2.0 - 1.0,
+            rand::random::<f32>() * 2.0 - 1.0,
+        ) * error;
+
+        let direction = bot_direction(&bot, position.0, ball.0);
+        for (action, pressed) in [
+            (PlayerActions::Up, direction.y > DEAD_ZONE),
+            (PlayerActions::Down, direction.y < -DEAD_ZONE),
+            (PlayerActions::Left, direction.x < -DEAD_ZONE),
+            (PlayerActions::Right, direction.x > DEAD_ZONE),
+        ] {
+            if pressed {
+                action_state.press(&action);
+            } else {
+                action_state.release(&action);
+            }
+        }
+    }
+}
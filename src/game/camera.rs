@@ -0,0 +1,244 @@
+//! Camera that keeps the action on screen.
+//!
+//! The camera either follows the boxes of the local client or frames every box and the ball.
+//! It zooms to fit them (but never further out than the whole arena) and shakes on hard collisions.
+//! Spectators can also move it by hand, or follow the boxes of any player (see `spectator`).
+//! It works the same in HostServer and Client mode, because in both cases the entities that
+//! are displayed on screen are the ones without a `Confirmed` component.
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::*;
+use lightyear::prelude::ClientId;
+
+use super::protocol::*;
+use super::shared::WALL_SIZE;
+use super::spectator::Spectating;
+
+/// Empty space kept around the framed entities, in world units
+const FRAME_MARGIN: f32 = 150.0;
+/// The camera never shows less than this many world units vertically
+const MIN_VIEW_HEIGHT: f32 = 400.0;
+/// How fast the camera catches up with its target; higher is snappier
+const SMOOTHING: f32 = 4.0;
+/// Zoom limits of the free camera
+const MIN_FREE_SCALE: f32 = 0.25;
+const MAX_FREE_SCALE: f32 = 4.0;
+/// Maximum offset of the camera when shaking, in world units
+const MAX_SHAKE_OFFSET: f32 = 12.0;
+/// Maximum rotation of the camera when shaking, in radians
+const MAX_SHAKE_ANGLE: f32 = 0.02;
+/// How much trauma is removed per second
+const TRAUMA_DECAY: f32 = 1.5;
+/// Collisions with a smaller impulse than this don't shake the camera
+const SHAKE_IMPULSE_THRESHOLD: f32 = 50.0;
+/// Converts the collision impulse above the threshold into trauma
+const SHAKE_IMPULSE_FACTOR: f32 = 0.005;
+
+pub struct GameCameraPlugin;
+
+impl Plugin for GameCameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, init_camera);
+        app.add_systems(
+            Update,
+            (
+                // the spectators have their own camera controls
+                toggle_camera_mode.run_if(not(resource_exists::<Spectating>)),
+                add_collision_trauma,
+                move_camera,
+            )
+                .chain(),
+        );
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    /// Frame every box and the ball
+    #[default]
+    FrameAll,
+    /// Only frame the boxes of the local client
+    FollowLocal,
+    /// Only frame the boxes of this player
+    FollowPlayer(ClientId),
+    /// Moved by hand with `pan` and `zoom`
+    Free,
+}
+
+#[derive(Component, Default)]
+pub struct CameraController {
+    pub mode: CameraMode,
+    /// Smoothed position that the camera is looking at
+    focus: Vec2,
+    /// Smoothed scale of the orthographic projection
+    scale: f32,
+    /// Amount of shake, between 0.0 and 1.0
+    trauma: f32,
+}
+
+impl CameraController {
+    /// Add some shake to the camera; the shake is quadratic in the trauma so that small hits are subtle
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).min(1.0);
+    }
+
+    /// Move the free camera, in world units
+    pub fn pan(&mut self, delta: Vec2) {
+        self.focus += delta;
+    }
+
+    /// Scale the view of the free camera; a factor above 1.0 zooms out
+    pub fn zoom(&mut self, factor: f32) {
+        self.scale = (self.scale * factor).clamp(MIN_FREE_SCALE, MAX_FREE_SCALE);
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+}
+
+fn init_camera(mut commands: Commands) {
+    commands.spawn((
+        Camera2dBundle::default(),
+        CameraController {
+            scale: 1.0,
+            ..default()
+        },
+    ));
+}
+
+fn toggle_camera_mode(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut controllers: Query<&mut CameraController>,
+) {
+    if !keys.just_pressed(KeyCode::KeyC) {
+        return;
+    }
+    for mut controller in controllers.iter_mut() {
+        controller.mode = match controller.mode {
+            CameraMode::FrameAll => CameraMode::FollowLocal,
+            _ => CameraMode::FrameAll,
+        };
+    }
+}
+
+fn add_collision_trauma(
+    mut collisions: EventReader<Collision>,
+    displayed: Query<(), Without<Confirmed>>,
+    mut controllers: Query<&mut CameraController>,
+) {
+    let max_impulse = collisions
+        .read()
+        .filter(|Collision(contacts)| {
+            displayed.contains(contacts.entity1) && displayed.contains(contacts.entity2)
+        })
+        .map(|Collision(contacts)| contacts.total_normal_impulse.abs())
+        .fold(0.0, f32::max);
+    if max_impulse <= SHAKE_IMPULSE_THRESHOLD {
+        return;
+    }
+    for mut controller in controllers.iter_mut() {
+        controller.add_trauma((max_impulse - SHAKE_IMPULSE_THRESHOLD) * SHAKE_IMPULSE_FACTOR);
+    }
+}
+
+fn move_camera(
+    time: Res<Time>,
+    connection: Option<Res<ClientConnection>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    players: Query<(&Position, &PlayerId), Without<Confirmed>>,
+    balls: Query<&Position, (With<BallMarker>, Without<Confirmed>)>,
+    mut cameras: Query<(
+        &mut CameraController,
+        &mut Transform,
+        &mut OrthographicProjection,
+    )>,
+) {
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    if window.width() <= 0.0 || window.height() <= 0.0 {
+        // the window is minimized
+        return;
+    }
+    let local_id = connection.map(|connection| connection.id());
+    let dt = time.delta_seconds();
+
+    for (mut controller, mut transform, mut projection) in cameras.iter_mut() {
+        let followed = match controller.mode {
+            CameraMode::FollowLocal => local_id,
+            CameraMode::FollowPlayer(client_id) => Some(client_id),
+            _ => None,
+        };
+        let points: Vec<Vec2> = match controller.mode {
+            CameraMode::FrameAll => players
+                .iter()
+                .map(|(position, _)| position.0)
+                .chain(balls.iter().map(|position| position.0))
+                .collect(),
+            CameraMode::FollowLocal | CameraMode::FollowPlayer(_) => players
+                .iter()
+                .filter(|(_, player_id)| Some(player_id.0) == followed)
+                .map(|(position, _)| position.0)
+                .collect(),
+            CameraMode::Free => Vec::new(),
+        };
+        let (target_focus, target_scale) = if controller.mode == CameraMode::Free {
+            (controller.focus, controller.scale)
+        } else {
+            frame(&points, window.width(), window.height())
+        };
+
+        // exponential smoothing, independent of the frame rate
+        let t = 1.0 - (-SMOOTHING * dt).exp();
+        controller.focus = controller.focus.lerp(target_focus, t);
+        controller.scale += (target_scale - controller.scale) * t;
+        controller.trauma = (controller.trauma - TRAUMA_DECAY * dt).max(0.0);
+
+        let shake = controller.trauma * controller.trauma;
+        let offset = Vec2::new(
+            rand::random::<f32>() * 2.0 - 1.0,
+            rand::random::<f32>() * 2.0 - 1.0,
+        ) * MAX_SHAKE_OFFSET
+            * shake;
+        let angle = (rand::random::<f32>() * 2.0 - 1.0) * MAX_SHAKE_ANGLE * shake;
+
+        let focus = controller.focus + offset;
+        transform.translation.x = focus.x;
+        transform.translation.y = focus.y;
+        transform.rotation = Quat::from_rotation_z(angle);
+        projection.scale = controller.scale;
+    }
+}
+
+/// Compute the center and projection scale needed to fit all the points on a window of the given size.
+///
+/// The framed area is clamped to the arena, so the camera never zooms out further than needed to see
+/// the whole arena.
+fn frame(points: &[Vec2], window_width: f32, window_height: f32) -> (Vec2, f32) {
+    let arena = Rect::from_center_half_size(Vec2::ZERO, Vec2::splat(WALL_SIZE + FRAME_MARGIN));
+    let bounds = if points.is_empty() {
+        arena
+    } else {
+        let bounds = points
+            .iter()
+            .fold(
+                Rect::from_center_size(points[0], Vec2::ZERO),
+                |rect, point| rect.union_point(*point),
+            )
+            .inset(FRAME_MARGIN);
+        let bounds = bounds.intersect(arena);
+        if bounds.is_empty() {
+            arena
+        } else {
+            bounds
+        }
+    };
+    let size = bounds.size().max(Vec2::new(
+        MIN_VIEW_HEIGHT * window_width / window_height,
+        MIN_VIEW_HEIGHT,
+    ));
+    let scale = (size.x / window_width).max(size.y / window_height);
+    (bounds.center(), scale)
+}
@@ -4,6 +4,7 @@ use std::str::FromStr;
 use bevy::app::PluginGroupBuilder;
 use bevy::ecs::schedule::{LogLevel, ScheduleBuildSettings};
 use bevy::prelude::*;
+use bevy::render::RenderPlugin;
 use bevy::utils::Duration;
 use bevy_xpbd_2d::parry::shape::ShapeType::Ball;
 use bevy_xpbd_2d::prelude::*;
@@ -15,14 +16,44 @@ use lightyear::prelude::client::LeafwingInputPlugin;
 pub use lightyear::prelude::client::*;
 use lightyear::prelude::*;
 
+use super::latency::LatencyPlugin;
+use super::leaderboard::LeaderboardPlugin;
+use super::lobby_screen::LobbyScreenPlugin;
+use super::net_stats::NetworkStatsPlugin;
 use super::protocol::*;
-use super::shared::{color_from_id, shared_config, shared_movement_behaviour, FixedSet};
+use super::scoreboard::ScoreboardPlugin;
+use super::settings::{PhysicsSettings, RemoteInputStrategy, ReplicationPolicy};
+use super::shared::{
+    color_from_id, shared_config, shared_movement_behaviour, shared_movement_behaviour_scaled,
+    FixedSet,
+};
+use super::spectator::{Spectating, SpectatorPlugin};
 use super::{shared, ClientTransports, SharedSettings};
 
-pub struct ExampleClientPlugin;
+pub struct ExampleClientPlugin {
+    pub remote_input: RemoteInputStrategy,
+    /// Watch the game without spawning any box
+    pub spectator: bool,
+    /// Display name sent to the server when we join
+    pub name: String,
+    /// Color requested when we join; the server picks one if `None` or if it is taken
+    pub color: Option<Color>,
+}
+
+/// What we ask the server for when we join
+#[derive(Resource)]
+struct JoinProfile {
+    name: String,
+    color: Option<Color>,
+}
 
 impl Plugin for ExampleClientPlugin {
     fn build(&self, app: &mut App) {
+        app.insert_resource(self.remote_input);
+        app.insert_resource(JoinProfile {
+            name: self.name.clone(),
+            color: self.color,
+        });
         // add the LeafwingInputPlugin to be able to send leafwing ActionStates to the server
         app.add_plugins(LeafwingInputPlugin::<MyProtocol, PlayerActions>::new(
             LeafwingInputConfig::<PlayerActions> {
@@ -43,13 +74,30 @@ impl Plugin for ExampleClientPlugin {
         //     (AdminActions::Reset, KeyCode::KeyR),
         // ]));
 
+        // the RTT is used by the stats panel and the adaptive input delay
+        app.add_plugins(LatencyPlugin);
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_plugins(NetworkStatsPlugin);
+        }
+        app.add_plugins((ScoreboardPlugin, LeaderboardPlugin, LobbyScreenPlugin));
+        if self.spectator {
+            app.add_plugins(SpectatorPlugin);
+        }
         app.add_systems(Startup, init);
         app.add_systems(
             PreUpdate,
-            handle_connection
+            (handle_connection, handle_join)
+                .chain()
                 .after(MainSet::Receive)
                 .before(PredictionSet::SpawnPrediction),
         );
+        // keep track of when we received the inputs of the other players, before we roll back with them
+        app.add_systems(
+            PreUpdate,
+            track_remote_inputs
+                .after(MainSet::Receive)
+                .before(PredictionSet::CheckRollback),
+        );
         // all actions related-system that can be rolled back should be in FixedUpdate schedule
         app.add_systems(FixedUpdate, player_movement.in_set(FixedSet::Main));
         app.add_systems(
@@ -72,9 +120,24 @@ pub fn init(mut client: ClientConnection) {
 
 /// Listen for events to know when the client is connected, and spawn a text entity
 /// to display the client id
-pub fn handle_connection(mut commands: Commands, mut connection_event: EventReader<ConnectEvent>) {
+pub fn handle_connection(
+    mut commands: Commands,
+    mut connection_event: EventReader<ConnectEvent>,
+    mut connection: ResMut<ClientConnectionManager>,
+    spectating: Option<Res<Spectating>>,
+    profile: Res<JoinProfile>,
+) {
     for event in connection_event.read() {
         let client_id = event.client_id();
+        // the server only gives us a slot (or a place in the queue) after this request
+        let request = JoinRequest {
+            spectator: spectating.is_some(),
+            name: profile.name.clone(),
+            color: profile.color,
+        };
+        if let Err(e) = connection.send_message::<Channel1, JoinRequest>(request) {
+            error!(?e, "failed to send the join request");
+        }
         commands.spawn(TextBundle::from_section(
             format!("Client {}", client_id),
             TextStyle {
@@ -83,29 +146,100 @@ pub fn handle_connection(mut commands: Commands, mut connection_event: EventRead
                 ..default()
             },
         ));
-        let y = (client_id.to_bits() as f32 * 50.0) % 500.0 - 250.0;
-        // we will spawn two cubes per player, once is controlled with WASD, the other with arrows
-        commands.spawn(PlayerBundle::new(
-            client_id,
-            Vec2::new(-50.0, y),
-            InputMap::new([
-                (PlayerActions::Up, KeyCode::KeyW),
-                (PlayerActions::Down, KeyCode::KeyS),
-                (PlayerActions::Left, KeyCode::KeyA),
-                (PlayerActions::Right, KeyCode::KeyD),
-            ]),
+        commands.spawn((
+            JoinStatusText,
+            TextBundle::from_section(
+                "Joining...",
+                TextStyle {
+                    font_size: 24.0,
+                    color: Color::GRAY,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(36.0),
+                left: Val::Px(0.0),
+                ..default()
+            }),
+        ));
+    }
+}
+
+/// Text showing whether we are waiting for a player slot
+#[derive(Component)]
+pub struct JoinStatusText;
+
+/// The server decides if we get a player slot: only then do we spawn our boxes
+pub fn handle_join(
+    mut commands: Commands,
+    connection: Res<ClientConnection>,
+    spectating: Option<Res<Spectating>>,
+    mut accepted: EventReader<MessageEvent<JoinAccepted>>,
+    mut queued: EventReader<MessageEvent<JoinQueued>>,
+    mut rejected: EventReader<MessageEvent<JoinRejected>>,
+    mut status: Query<&mut Text, With<JoinStatusText>>,
+    physics: Res<PhysicsSettings>,
+    policy: Res<ReplicationPolicy>,
+) {
+    let mut set_status = |message: String| {
+        for mut text in status.iter_mut() {
+            text.sections[0].value = message.clone();
+        }
+    };
+    for event in queued.read() {
+        let position = event.message().0;
+        info!(?position, "server full, waiting in the join queue");
+        set_status(format!(
+            "Server full: waiting for a slot (position {position})"
         ));
-        commands.spawn((PlayerBundle::new(
-            client_id,
-            Vec2::new(50.0, y),
-            InputMap::new([
-                (PlayerActions::Up, KeyCode::ArrowUp),
-                (PlayerActions::Down, KeyCode::ArrowDown),
-                (PlayerActions::Left, KeyCode::ArrowLeft),
-                (PlayerActions::Right, KeyCode::ArrowRight),
-            ]),
-        ),));
     }
+    for event in rejected.read() {
+        let reason = event.message().0;
+        warn!(%reason, "the server rejected us");
+        set_status(format!("Rejected: {reason}"));
+    }
+    let Some(accepted) = accepted.read().last() else {
+        return;
+    };
+    set_status(String::new());
+    if spectating.is_some() {
+        info!("joined as a spectator");
+        return;
+    }
+    let client_id = connection.id();
+    let color = accepted
+        .message()
+        .color
+        .unwrap_or_else(|| color_from_id(client_id));
+    let y = (client_id.to_bits() as f32 * 50.0) % 500.0 - 250.0;
+    // we will spawn two cubes per player, once is controlled with WASD, the other with arrows
+    commands.spawn(PlayerBundle::new(
+        client_id,
+        Vec2::new(-50.0, y),
+        color,
+        InputMap::new([
+            (PlayerActions::Up, KeyCode::KeyW),
+            (PlayerActions::Down, KeyCode::KeyS),
+            (PlayerActions::Left, KeyCode::KeyA),
+            (PlayerActions::Right, KeyCode::KeyD),
+        ]),
+        &physics,
+        &policy,
+    ));
+    commands.spawn((PlayerBundle::new(
+        client_id,
+        Vec2::new(50.0, y),
+        color,
+        InputMap::new([
+            (PlayerActions::Up, KeyCode::ArrowUp),
+            (PlayerActions::Down, KeyCode::ArrowDown),
+            (PlayerActions::Left, KeyCode::ArrowLeft),
+            (PlayerActions::Right, KeyCode::ArrowRight),
+        ]),
+        &physics,
+        &policy,
+    ),));
 }
 
 /// Blueprint pattern: when the ball gets replicated from the server, add all the components
@@ -119,6 +253,7 @@ pub fn handle_connection(mut commands: Commands, mut connection_event: EventRead
 /// by the physics engine? Actually this shouldn't matter because we run interpolation in PostUpdate...
 fn add_ball_physics(
     mut commands: Commands,
+    physics: Res<PhysicsSettings>,
     mut ball_query: Query<
         Entity,
         (
@@ -130,7 +265,9 @@ fn add_ball_physics(
     >,
 ) {
     for entity in ball_query.iter_mut() {
-        commands.entity(entity).insert(PhysicsBundle::ball());
+        commands
+            .entity(entity)
+            .insert(PhysicsBundle::ball(&physics.ball));
     }
 }
 
@@ -139,6 +276,7 @@ fn add_ball_physics(
 fn add_player_physics(
     connection: Res<ClientConnection>,
     mut commands: Commands,
+    physics: Res<PhysicsSettings>,
     mut player_query: Query<
         (Entity, &PlayerId),
         (
@@ -160,15 +298,55 @@ fn add_player_physics(
             continue;
         }
         info!(?entity, ?player_id, "adding physics to predicted player");
-        commands.entity(entity).insert(PhysicsBundle::player());
+        commands.entity(entity).insert((
+            PhysicsBundle::player(&physics.player),
+            RemoteInputTick::default(),
+        ));
+    }
+}
+
+/// Tick of the last input that we received for the predicted box of another player
+#[derive(Component, Default)]
+pub struct RemoteInputTick(Option<Tick>);
+
+/// When the server sends us a new input of another player, remember on which tick it was pressed
+fn track_remote_inputs(
+    updated: Query<&Confirmed, (With<PlayerId>, Changed<ActionState<PlayerActions>>)>,
+    mut remote_inputs: Query<&mut RemoteInputTick, With<Predicted>>,
+) {
+    for confirmed in updated.iter() {
+        let Some(predicted) = confirmed.predicted else {
+            continue;
+        };
+        if let Ok(mut remote_input) = remote_inputs.get_mut(predicted) {
+            remote_input.0 = Some(confirmed.tick);
+        }
     }
 }
 
-// The client input only gets applied to predicted entities that we own
-// This works because we only predict the user's controlled entity.
-// If we were predicting more entities, we would have to only apply movement to the player owned one.
+/// How much of the last received input of another player we apply, `age` ticks after it was pressed
+fn remote_input_scale(strategy: RemoteInputStrategy, age: Option<i16>) -> f32 {
+    match strategy {
+        RemoteInputStrategy::Repeat => 1.0,
+        RemoteInputStrategy::VelocityOnly => 0.0,
+        RemoteInputStrategy::Decay { ticks } => {
+            if ticks == 0 {
+                return 0.0;
+            }
+            let age = age.unwrap_or(0).max(0) as f32;
+            (1.0 - age / ticks as f32).max(0.0)
+        }
+    }
+}
+
+// The client input gets applied to all the predicted players.
+// Our own players use the local inputs; the other players use their last received input,
+// depending on the `RemoteInputStrategy`.
 fn player_movement(
     tick_manager: Res<TickManager>,
+    rollback: Option<Res<Rollback>>,
+    connection: Res<ClientConnection>,
+    strategy: Res<RemoteInputStrategy>,
     mut velocity_query: Query<
         (
             Entity,
@@ -176,18 +354,38 @@ fn player_movement(
             &Position,
             &mut LinearVelocity,
             &ActionState<PlayerActions>,
+            Option<&RemoteInputTick>,
         ),
         With<Predicted>,
     >,
 ) {
-    for (entity, player_id, position, velocity, action_state) in velocity_query.iter_mut() {
-        if !action_state.get_pressed().is_empty() {
-            info!(?entity, tick = ?tick_manager.tick(), ?position, actions = ?action_state.get_pressed(), "applying movement to predicted player");
-            // note that we also apply the input to the other predicted clients! even though
-            //  their inputs are only replicated with a delay!
-            // TODO: add input decay?
-            shared_movement_behaviour(velocity, action_state);
+    // during a rollback we re-simulate past ticks, so the inputs must be as old as they were back then
+    let mut tick = tick_manager.tick();
+    if let Some(rollback) = rollback {
+        if let RollbackState::ShouldRollback { current_tick } = rollback.state {
+            tick = current_tick;
+        }
+    }
+    let client_id = connection.id();
+    for (entity, player_id, position, velocity, action_state, remote_input) in
+        velocity_query.iter_mut()
+    {
+        if action_state.get_pressed().is_empty() {
+            continue;
+        }
+        let scale = if player_id.0 == client_id {
+            1.0
+        } else {
+            let age = remote_input
+                .and_then(|r| r.0)
+                .map(|received| tick - received);
+            remote_input_scale(*strategy, age)
+        };
+        if scale <= 0.0 {
+            continue;
         }
+        info!(?entity, ?tick, ?position, ?scale, actions = ?action_state.get_pressed(), "applying movement to predicted player");
+        shared_movement_behaviour_scaled(velocity, action_state, scale);
     }
 }
 
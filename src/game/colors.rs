@@ -0,0 +1,201 @@
+//! Colors of the players.
+//!
+//! The client asks for a color in its `JoinRequest`, and the server arbitrates: the players only get colors
+//! of the server palette, so the requested color is snapped to the closest one (a client can't pick a
+//! translucent color, or one that disappears on the background). Two players can't have colors that are
+//! hard to tell apart either, so a color too close to one that is already taken is replaced by the
+//! closest free color of the palette.
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Colors closer than this in the Oklab color space are considered the same.
+/// Every palette keeps its colors further apart than this
+const MIN_DISTANCE: f32 = 0.1;
+
+/// Bright colors, easy to tell apart with normal color vision
+const CLASSIC: [Color; 8] = [
+    Color::rgb(0.9, 0.1, 0.1),
+    Color::rgb(0.1, 0.8, 0.1),
+    Color::rgb(0.1, 0.3, 0.9),
+    Color::rgb(0.95, 0.95, 0.1),
+    Color::rgb(0.1, 0.85, 0.85),
+    Color::rgb(0.85, 0.1, 0.85),
+    Color::rgb(1.0, 0.5, 0.0),
+    Color::rgb(0.95, 0.95, 0.95),
+];
+
+/// Okabe & Ito, "Color Universal Design" (without black, which is invisible on the background)
+const OKABE_ITO: [Color; 7] = [
+    Color::rgb(0.902, 0.624, 0.0),
+    Color::rgb(0.337, 0.706, 0.914),
+    Color::rgb(0.0, 0.620, 0.451),
+    Color::rgb(0.941, 0.894, 0.259),
+    Color::rgb(0.0, 0.447, 0.698),
+    Color::rgb(0.835, 0.369, 0.0),
+    Color::rgb(0.800, 0.475, 0.655),
+];
+
+/// Paul Tol's "bright" qualitative scheme
+const TOL_BRIGHT: [Color; 7] = [
+    Color::rgb(0.267, 0.467, 0.667),
+    Color::rgb(0.400, 0.800, 0.933),
+    Color::rgb(0.133, 0.533, 0.200),
+    Color::rgb(0.800, 0.733, 0.267),
+    Color::rgb(0.933, 0.400, 0.467),
+    Color::rgb(0.667, 0.200, 0.467),
+    Color::rgb(0.733, 0.733, 0.733),
+];
+
+/// The colors that can be picked in the menu, and that the server assigns
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Palette {
+    #[default]
+    Classic,
+    /// Safe for the most common kinds of color blindness
+    OkabeIto,
+    /// Safe for the most common kinds of color blindness
+    TolBright,
+}
+
+impl Palette {
+    pub const ALL: [Palette; 3] = [Palette::Classic, Palette::OkabeIto, Palette::TolBright];
+
+    pub fn colors(self) -> &'static [Color] {
+        match self {
+            Palette::Classic => &CLASSIC,
+            Palette::OkabeIto => &OKABE_ITO,
+            Palette::TolBright => &TOL_BRIGHT,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Palette::Classic => "CLASSIC",
+            Palette::OkabeIto => "OKABE-ITO",
+            Palette::TolBright => "TOL BRIGHT",
+        }
+    }
+
+    /// The next palette, to cycle through them in the menu
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|p| *p == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// Coordinates of the color in the Oklab color space, where distances match the perceived differences
+fn oklab(color: Color) -> Vec3 {
+    let [r, g, b, _] = color.as_linear_rgba_f32();
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    Vec3::new(
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    )
+}
+
+/// Perceived difference between two colors
+fn distance(a: Color, b: Color) -> f32 {
+    oklab(a).distance(oklab(b))
+}
+
+/// True if the players could mistake the two colors for each other
+pub fn too_similar(a: Color, b: Color) -> bool {
+    distance(a, b) < MIN_DISTANCE
+}
+
+/// The color of the palette closest to the given one
+fn closest(color: Color, colors: impl Iterator<Item = Color>) -> Option<Color> {
+    colors.min_by(|a, b| distance(*a, color).total_cmp(&distance(*b, color)))
+}
+
+/// The color that a player gets: the palette color closest to the requested one if nobody has a similar
+/// color, otherwise the free color of the palette closest to it (or the first free one if nothing was
+/// requested). If every color of the palette is taken, the one that is the furthest from the taken colors
+/// is used.
+pub fn assign_color(requested: Option<Color>, taken: &[Color], palette: Palette) -> Color {
+    let colors = palette.colors();
+    let requested = requested.and_then(|color| closest(color, colors.iter().copied()));
+    let is_free = |color: &Color| !taken.iter().any(|other| too_similar(*color, *other));
+    if let Some(requested) = requested.filter(is_free) {
+        return requested;
+    }
+    let mut free = colors.iter().copied().filter(is_free);
+    let closest = match requested {
+        Some(requested) => closest(requested, free),
+        None => free.next(),
+    };
+    closest.unwrap_or_else(|| {
+        // every color is taken: pick the one that is the least similar to the taken ones
+        let gap = |color: &Color| {
+            taken
+                .iter()
+                .map(|other| distance(*color, *other))
+                .fold(f32::MAX, f32::min)
+        };
+        colors
+            .iter()
+            .copied()
+            .max_by(|a, b| gap(a).total_cmp(&gap(b)))
+            .unwrap_or(Color::WHITE)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palettes_have_distinct_colors() {
+        for palette in Palette::ALL {
+            let colors = palette.colors();
+            for (i, a) in colors.iter().enumerate() {
+                for b in &colors[i + 1..] {
+                    assert!(!too_similar(*a, *b), "{palette:?}: {a:?} and {b:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn free_requested_color_is_kept() {
+        let red = Palette::Classic.colors()[0];
+        let blue = Palette::Classic.colors()[2];
+        assert_eq!(assign_color(Some(red), &[blue], Palette::Classic), red);
+    }
+
+    #[test]
+    fn requested_color_is_snapped_to_the_palette() {
+        let red = Palette::Classic.colors()[0];
+        let pure_red = Color::rgb(1.0, 0.0, 0.0);
+        assert_eq!(assign_color(Some(pure_red), &[], Palette::Classic), red);
+        // translucent or black colors would be invisible on the background
+        let translucent_red = Color::rgba(1.0, 0.0, 0.0, 0.0);
+        assert_eq!(
+            assign_color(Some(translucent_red), &[], Palette::Classic),
+            red
+        );
+        let black = assign_color(Some(Color::BLACK), &[], Palette::OkabeIto);
+        assert!(Palette::OkabeIto.colors().contains(&black));
+    }
+
+    #[test]
+    fn similar_color_is_replaced() {
+        let red = Color::rgb(1.0, 0.0, 0.0);
+        let other_red = Color::rgb(0.9, 0.1, 0.1);
+        assert!(too_similar(red, other_red));
+        let assigned = assign_color(Some(other_red), &[red], Palette::Classic);
+        assert!(!too_similar(assigned, red));
+        // the replacement is the closest free color, orange
+        assert_eq!(assigned, Palette::Classic.colors()[6]);
+    }
+
+    #[test]
+    fn full_palette_still_gives_a_color() {
+        let taken = Palette::OkabeIto.colors().to_vec();
+        let assigned = assign_color(None, &taken, Palette::OkabeIto);
+        assert!(taken.contains(&assigned));
+    }
+}
@@ -0,0 +1,264 @@
+//! Overlay to diagnose mispredictions visually.
+//!
+//! When enabled (toggle with F2), it draws:
+//! - the `Confirmed` server state next to every predicted entity, linked by a line
+//! - the visual correction vectors, from the displayed position to the corrected position
+//! - a red flash on the predicted entities whenever a rollback happens
+//! - the number of rollbacks per second, and how many corrections the local and remote players needed
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::*;
+use lightyear::prelude::TickManager;
+use std::collections::VecDeque;
+
+use super::protocol::*;
+
+/// How long the predicted entities flash after a rollback
+const FLASH_DURATION: Duration = Duration::from_millis(200);
+/// Over how much time the rollbacks are counted
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const CONFIRMED_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.4);
+const CORRECTION_COLOR: Color = Color::YELLOW;
+const FLASH_COLOR: Color = Color::RED;
+
+pub struct PredictionDebugPlugin;
+
+impl Plugin for PredictionDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PredictionOverlay>();
+        app.init_resource::<RollbackStats>();
+        app.init_resource::<MispredictionCounters>();
+        app.add_systems(Startup, spawn_overlay_text);
+        // record rollbacks right after lightyear decided that one is needed
+        app.add_systems(
+            PreUpdate,
+            record_rollback
+                .after(PredictionSet::CheckRollback)
+                .before(PredictionSet::Rollback)
+                .run_if(resource_exists::<Rollback>),
+        );
+        app.add_systems(
+            Update,
+            (
+                toggle_prediction_overlay,
+                count_mispredictions,
+                update_overlay_text,
+            )
+                .chain(),
+        );
+        app.add_systems(
+            PostUpdate,
+            (draw_confirmed, draw_corrections, draw_rollback_flash)
+                .after(InterpolationSet::Interpolate)
+                .after(PredictionSet::VisualCorrection)
+                .run_if(resource_equals(PredictionOverlay(true))),
+        );
+    }
+}
+
+/// If true, the prediction debug overlay is displayed
+#[derive(Resource, Default, PartialEq)]
+pub struct PredictionOverlay(pub bool);
+
+#[derive(Resource, Default)]
+pub struct RollbackStats {
+    /// When the recent rollbacks happened
+    recent: VecDeque<Duration>,
+    /// When the last rollback happened
+    last: Option<Duration>,
+    /// How many ticks the last rollback had to re-simulate
+    last_depth: u16,
+}
+
+impl RollbackStats {
+    pub fn per_second(&self) -> usize {
+        self.recent.len()
+    }
+
+    pub fn depth(&self) -> u16 {
+        self.last_depth
+    }
+}
+
+/// Number of mispredicted positions, to compare how well the different `RemoteInputStrategy` perform
+#[derive(Resource, Default, Debug)]
+pub struct MispredictionCounters {
+    /// Corrections of the boxes of the local client
+    pub local: u32,
+    /// Corrections of the boxes of the other players
+    pub remote: u32,
+    /// Sum of the distances between the predicted and the corrected positions of the other players
+    pub remote_distance: f32,
+}
+
+#[derive(Component)]
+struct OverlayText;
+
+fn toggle_prediction_overlay(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut overlay: ResMut<PredictionOverlay>,
+) {
+    if keys.just_pressed(KeyCode::F2) {
+        overlay.0 = !overlay.0;
+    }
+}
+
+fn record_rollback(
+    time: Res<Time>,
+    tick_manager: Res<TickManager>,
+    rollback: Res<Rollback>,
+    mut stats: ResMut<RollbackStats>,
+) {
+    let now = time.elapsed();
+    if let RollbackState::ShouldRollback { current_tick } = rollback.state {
+        stats.recent.push_back(now);
+        stats.last = Some(now);
+        stats.last_depth = (tick_manager.tick() - current_tick).max(0) as u16;
+    }
+    while stats
+        .recent
+        .front()
+        .is_some_and(|t| now.saturating_sub(*t) > RATE_WINDOW)
+    {
+        stats.recent.pop_front();
+    }
+}
+
+/// A `Correction` is added to a predicted entity every time a rollback changed its position
+fn count_mispredictions(
+    connection: Option<Res<ClientConnection>>,
+    corrections: Query<(&PlayerId, &Correction<Position>), Added<Correction<Position>>>,
+    mut counters: ResMut<MispredictionCounters>,
+) {
+    let local_id = connection.map(|connection| connection.id());
+    for (player_id, correction) in corrections.iter() {
+        if Some(player_id.0) == local_id {
+            counters.local += 1;
+        } else {
+            counters.remote += 1;
+            if let Some(final_correction) = &correction.final_correction {
+                counters.remote_distance +=
+                    correction.original_prediction.distance(final_correction.0);
+            }
+        }
+    }
+}
+
+fn spawn_overlay_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            left: Val::Px(8.0),
+            ..default()
+        }),
+        OverlayText,
+    ));
+}
+
+fn update_overlay_text(
+    time: Res<Time>,
+    overlay: Res<PredictionOverlay>,
+    stats: Res<RollbackStats>,
+    counters: Res<MispredictionCounters>,
+    mut text_query: Query<(&mut Text, &mut Visibility), With<OverlayText>>,
+) {
+    for (mut text, mut visibility) in text_query.iter_mut() {
+        *visibility = if overlay.0 {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        let average_remote_error = if counters.remote > 0 {
+            counters.remote_distance / counters.remote as f32
+        } else {
+            0.0
+        };
+        text.sections[0].value = format!(
+            "Rollbacks/s: {}\nCorrections: {} local, {} remote (avg {:.1})",
+            stats.per_second(),
+            counters.local,
+            counters.remote,
+            average_remote_error,
+        );
+        text.sections[0].style.color = if is_flashing(&time, &stats) {
+            FLASH_COLOR
+        } else {
+            Color::WHITE
+        };
+    }
+}
+
+fn is_flashing(time: &Time, stats: &RollbackStats) -> bool {
+    stats
+        .last
+        .is_some_and(|last| time.elapsed().saturating_sub(last) < FLASH_DURATION)
+}
+
+/// Draw the server state of every predicted entity, linked to the predicted state
+fn draw_confirmed(
+    mut gizmos: Gizmos,
+    predicted: Query<(&Predicted, &Position, Has<BallMarker>)>,
+    confirmed: Query<(&Position, Option<&Rotation>), With<Confirmed>>,
+) {
+    for (predicted, position, is_ball) in predicted.iter() {
+        let Some((confirmed_position, confirmed_rotation)) = predicted
+            .confirmed_entity
+            .and_then(|entity| confirmed.get(entity).ok())
+        else {
+            continue;
+        };
+        if is_ball {
+            gizmos.circle_2d(confirmed_position.0, BALL_SIZE, CONFIRMED_COLOR);
+        } else {
+            gizmos.rect_2d(
+                confirmed_position.0,
+                confirmed_rotation.map_or(0.0, |r| r.as_radians()),
+                Vec2::ONE * PLAYER_SIZE,
+                CONFIRMED_COLOR,
+            );
+        }
+        gizmos.line_2d(position.0, confirmed_position.0, CONFIRMED_COLOR);
+    }
+}
+
+/// Draw the remaining visual correction: from the position on screen to the corrected position
+fn draw_corrections(mut gizmos: Gizmos, corrections: Query<(&Position, &Correction<Position>)>) {
+    for (position, correction) in corrections.iter() {
+        if let Some(final_correction) = &correction.final_correction {
+            gizmos.arrow_2d(position.0, final_correction.0, CORRECTION_COLOR);
+        }
+    }
+}
+
+fn draw_rollback_flash(
+    mut gizmos: Gizmos,
+    time: Res<Time>,
+    stats: Res<RollbackStats>,
+    predicted: Query<(&Position, Option<&Rotation>, Has<BallMarker>), With<Predicted>>,
+) {
+    if !is_flashing(&time, &stats) {
+        return;
+    }
+    for (position, rotation, is_ball) in predicted.iter() {
+        if is_ball {
+            gizmos.circle_2d(position.0, BALL_SIZE + 4.0, FLASH_COLOR);
+        } else {
+            gizmos.rect_2d(
+                position.0,
+                rotation.map_or(0.0, |r| r.as_radians()),
+                Vec2::ONE * (PLAYER_SIZE + 8.0),
+                FLASH_COLOR,
+            );
+        }
+    }
+}
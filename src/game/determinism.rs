@@ -0,0 +1,368 @@
+//! Headless harness that checks that the simulation is deterministic.
+//!
+//! The replays recorded by the server contain the inputs that the server applied on every tick, and the
+//! resulting positions. The harness re-runs the `SharedPlugin` physics and the server `movement` system
+//! from the recorded inputs, and compares the positions with the recorded ones tick by tick.
+//! A divergence means that a change in the physics or in the ordering of `FixedSet::Main` /
+//! `FixedSet::Physics` would also make the predictions of the clients diverge from the server.
+//!
+//! The entities that the server teleported on a tick are not compared: they restart from their
+//! recorded state, like the entities that appear.
+//!
+//! The harness doesn't run the lag compensation, so the replays must be recorded with a predicted ball.
+//!
+//! The replays checked in to `tests/replays` are verified by the tests; any other saved replay can be
+//! checked with `cargo run --bin check_replay -- <replay files>`.
+use bevy::asset::ron;
+use bevy::prelude::*;
+use bevy::transform::TransformPlugin;
+use bevy::utils::HashMap;
+use bevy_xpbd_2d::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::{Mode, Tick, TickManager};
+use std::fmt;
+use std::path::Path;
+
+use super::protocol::*;
+use super::replay::{actions_from_mask, EntityFrame, EntityKind, Replay, ReplayFrame};
+use super::server::movement;
+use super::settings::{PhysicsSettings, ReplicationPolicy, Settings, SharedSettings};
+use super::shared::{shared_config, FixedSet, SharedPlugin};
+
+/// Positions that are closer than this are considered equal
+const POSITION_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeterminismError {
+    /// The replay was recorded with other settings, so it can't be compared
+    SettingsMismatch { recorded: u64, current: u64 },
+    /// The re-simulated position of an entity is different from the recorded one
+    Divergence {
+        tick: Tick,
+        /// Index of the frame in the replay
+        frame: usize,
+        /// Id of the entity in the replay
+        id: u64,
+        recorded: Vec2,
+        simulated: Vec2,
+    },
+}
+
+impl fmt::Display for DeterminismError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeterminismError::SettingsMismatch { recorded, current } => write!(
+                f,
+                "the replay was recorded with the settings hash {recorded:#x}, but the current one is {current:#x}"
+            ),
+            DeterminismError::Divergence {
+                tick,
+                frame,
+                id,
+                recorded,
+                simulated,
+            } => write!(
+                f,
+                "entity {id} diverged on tick {tick:?} (frame {frame}): recorded {recorded}, simulated {simulated}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeterminismError {}
+
+/// Re-simulate the replay from its inputs, and return the first divergence from the recorded positions
+pub fn verify_replay(replay: &Replay, settings: &SharedSettings) -> Result<(), DeterminismError> {
+    let current = settings.simulation_hash();
+    if replay.header.settings_hash != current {
+        return Err(DeterminismError::SettingsMismatch {
+            recorded: replay.header.settings_hash,
+            current,
+        });
+    }
+    let mut harness = Harness::new(settings.physics, settings.replication);
+    for (index, frame) in replay.frames.iter().enumerate() {
+        harness.step(replay, index, frame)?;
+    }
+    Ok(())
+}
+
+/// Load a saved replay and re-simulate it with the settings of `assets/settings.ron`.
+/// Returns the number of checked frames
+pub fn check_replay_file(path: &Path) -> anyhow::Result<usize> {
+    let settings_str = include_str!("../../assets/settings.ron");
+    let settings = ron::de::from_str::<Settings>(settings_str)?.shared;
+    let replay = Replay::load(path)?;
+    verify_replay(&replay, &settings)?;
+    Ok(replay.frames.len())
+}
+
+/// Headless app that only contains the simulation
+struct Harness {
+    app: App,
+    physics: PhysicsSettings,
+    /// Simulated entity of each recorded entity id
+    entities: HashMap<u64, Entity>,
+}
+
+impl Harness {
+    fn new(physics: PhysicsSettings, replication: ReplicationPolicy) -> Self {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin));
+        app.add_plugins(SharedPlugin {
+            physics,
+            replication,
+        });
+        app.insert_resource(TickManager::from_config(shared_config(Mode::Separate).tick));
+        app.add_systems(FixedUpdate, movement.in_set(FixedSet::Main));
+        app.finish();
+        app.cleanup();
+        // run the startup systems (i.e. spawn the walls)
+        app.update();
+        Self {
+            app,
+            physics,
+            entities: HashMap::default(),
+        }
+    }
+
+    /// Apply the inputs of the frame, run one tick and compare the positions.
+    /// The entities that appear or are teleported in this frame start from their recorded state.
+    fn step(
+        &mut self,
+        replay: &Replay,
+        index: usize,
+        frame: &ReplayFrame,
+    ) -> Result<(), DeterminismError> {
+        // despawn the entities that disappeared
+        self.entities.retain(|id, entity| {
+            let present = frame.entities.iter().any(|e| e.id == *id);
+            if !present {
+                self.app.world.despawn(*entity);
+            }
+            present
+        });
+        let new_entities: Vec<&EntityFrame> = frame
+            .entities
+            .iter()
+            .filter(|e| !self.entities.contains_key(&e.id))
+            .collect();
+        if index == 0 || new_entities.len() == frame.entities.len() {
+            // nothing to compare yet, just set up the initial state
+            for entity_frame in new_entities {
+                self.spawn(replay, entity_frame);
+            }
+            return Ok(());
+        }
+
+        for input in &frame.inputs {
+            let Some(entity) = self.entities.get(&input.id) else {
+                continue;
+            };
+            if let Some(mut action_state) = self
+                .app
+                .world
+                .get_mut::<ActionState<PlayerActions>>(*entity)
+            {
+                *action_state = ActionState::default();
+                for action in actions_from_mask(input.pressed) {
+                    action_state.press(&action);
+                }
+            }
+        }
+        self.app.world.run_schedule(FixedMain);
+
+        for entity_frame in &frame.entities {
+            let Some(&entity) = self.entities.get(&entity_frame.id) else {
+                continue;
+            };
+            if entity_frame.teleported {
+                // the state doesn't come from the simulation
+                self.app.world.despawn(entity);
+                self.spawn(replay, entity_frame);
+                continue;
+            }
+            let simulated = self.app.world.get::<Position>(entity).unwrap().0;
+            if simulated.distance(entity_frame.position) > POSITION_TOLERANCE {
+                return Err(DeterminismError::Divergence {
+                    tick: frame.tick,
+                    frame: index,
+                    id: entity_frame.id,
+                    recorded: entity_frame.position,
+                    simulated,
+                });
+            }
+        }
+        for entity_frame in new_entities {
+            self.spawn(replay, entity_frame);
+        }
+        Ok(())
+    }
+
+    fn spawn(&mut self, replay: &Replay, frame: &EntityFrame) {
+        let mut entity = self.app.world.spawn((
+            Position(frame.position),
+            Rotation::from_radians(frame.rotation),
+            LinearVelocity(frame.linear_velocity),
+            AngularVelocity(frame.angular_velocity),
+        ));
+        match frame.kind {
+            EntityKind::Player => {
+                entity.insert((
+                    PhysicsBundle::player(&self.physics.player),
+                    ActionState::<PlayerActions>::default(),
+                ));
+                if let Some(player) = frame
+                    .owner
+                    .and_then(|owner| replay.header.players.get(owner as usize))
+                {
+                    entity.insert(PlayerId(player.client_id));
+                }
+            }
+            EntityKind::Ball => {
+                entity.insert((PhysicsBundle::ball(&self.physics.ball), BallMarker));
+            }
+        }
+        self.entities.insert(frame.id, entity.id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::game::replay::{InputFrame, ReplayHeader, ReplayPlayer};
+    use crate::game::shared::{color_from_id, MAP_NAME};
+
+    /// Replays checked in to catch determinism regressions
+    const REPLAYS_DIR: &str = "tests/replays";
+    const RECORD_COMMAND: &str = "cargo test record_scripted_replay -- --ignored";
+
+    fn settings() -> SharedSettings {
+        let settings_str = include_str!("../../assets/settings.ron");
+        ron::de::from_str::<Settings>(settings_str).unwrap().shared
+    }
+
+    /// Build a replay by simulating scripted inputs with the harness itself
+    fn scripted_replay(settings: &SharedSettings, ticks: u16) -> Replay {
+        let client_id = ClientId::Netcode(1);
+        let mut harness = Harness::new(settings.physics, settings.replication);
+        let player = EntityFrame {
+            id: 0,
+            kind: EntityKind::Player,
+            owner: Some(0),
+            position: Vec2::new(-100.0, 0.0),
+            rotation: 0.0,
+            linear_velocity: Vec2::ZERO,
+            angular_velocity: 0.0,
+            teleported: false,
+        };
+        let ball = EntityFrame {
+            id: 1,
+            kind: EntityKind::Ball,
+            owner: None,
+            position: Vec2::ZERO,
+            ..player
+        };
+        let mut replay = Replay {
+            header: ReplayHeader {
+                map: MAP_NAME.to_string(),
+                settings_hash: settings.simulation_hash(),
+                tick_duration: shared_config(Mode::Separate).tick.tick_duration,
+                players: vec![ReplayPlayer {
+                    client_id,
+                    name: "Player".to_string(),
+                    color: color_from_id(client_id),
+                }],
+            },
+            frames: vec![ReplayFrame {
+                tick: Tick(0),
+                entities: vec![player, ball],
+                inputs: vec![],
+            }],
+        };
+        harness.step(&replay, 0, &replay.frames[0].clone()).unwrap();
+
+        for tick in 1..ticks {
+            // push the ball to the right, then move up
+            let pressed = if tick < ticks / 2 { 0b1000 } else { 0b0001 };
+            let mut frame = replay.frames.last().unwrap().clone();
+            frame.tick = Tick(tick);
+            frame.inputs = vec![InputFrame { id: 0, pressed }];
+            harness.step(&replay, tick as usize, &frame).unwrap();
+            for entity_frame in frame.entities.iter_mut() {
+                let entity = harness.entities[&entity_frame.id];
+                entity_frame.position = harness.app.world.get::<Position>(entity).unwrap().0;
+            }
+            replay.frames.push(frame);
+        }
+        replay
+    }
+
+    #[test]
+    fn scripted_replay_is_deterministic() {
+        let settings = settings();
+        let replay = scripted_replay(&settings, 200);
+        // the ball must have been hit, otherwise the replay doesn't test much
+        let ball = replay.frames.last().unwrap().entities[1];
+        assert_ne!(ball.position, Vec2::ZERO);
+
+        // the replay survives a round-trip through the file format
+        let mut bytes = Vec::new();
+        replay.write(&mut bytes).unwrap();
+        let replay = Replay::read(bytes.as_slice()).unwrap();
+
+        verify_replay(&replay, &settings).unwrap();
+    }
+
+    #[test]
+    fn divergence_reports_the_first_diverging_tick() {
+        let settings = settings();
+        let mut replay = scripted_replay(&settings, 50);
+        replay.frames[20].entities[0].position.x += 10.0;
+        replay.frames[30].entities[0].position.x += 10.0;
+        match verify_replay(&replay, &settings) {
+            Err(DeterminismError::Divergence { frame, id, .. }) => {
+                assert_eq!(frame, 20);
+                assert_eq!(id, 0);
+            }
+            result => panic!("expected a divergence, got {result:?}"),
+        }
+    }
+
+    /// Every replay checked in to `tests/replays` must still be re-simulated exactly. The replays
+    /// recorded by the server are checked by the integration tests, without any file
+    #[test]
+    fn recorded_replays_are_deterministic() {
+        let Ok(entries) = std::fs::read_dir(Path::new(REPLAYS_DIR)) else {
+            eprintln!("no {REPLAYS_DIR} directory, record a replay with `{RECORD_COMMAND}`");
+            return;
+        };
+        let mut checked = 0;
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "replay") {
+                if let Err(e) = check_replay_file(&path) {
+                    panic!("{}: {e:#}", path.display());
+                }
+                checked += 1;
+            }
+        }
+        assert!(
+            checked > 0,
+            "no replay to check in {REPLAYS_DIR}, record one with `{RECORD_COMMAND}`"
+        );
+    }
+
+    /// Save the scripted replay to `tests/replays`, to catch the regressions of later changes.
+    /// Run it with [`RECORD_COMMAND`] after a deliberate change of the simulation or of the format
+    #[test]
+    #[ignore]
+    fn record_scripted_replay() {
+        let replay = scripted_replay(&settings(), 200);
+        std::fs::create_dir_all(REPLAYS_DIR).unwrap();
+        replay
+            .save(&Path::new(REPLAYS_DIR).join("scripted.replay"))
+            .unwrap();
+    }
+}
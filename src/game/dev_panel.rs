@@ -0,0 +1,105 @@
+//! Developer panel to change the simulated network conditions of the client's UDP link while the
+//! game runs (toggle with F5).
+//!
+//! The conditioners are the ones of the `ConditionedLink` of the client: the incoming conditioner
+//! delays the packets received from the server, and the outgoing one the packets sent to it.
+//! Only the UDP transport of a client goes through such a link. With the other transports, and for
+//! the local client of a host-server, the panel is greyed out: their conditioners (and the one of the
+//! server) can only be set in the settings, before the game starts.
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use super::link::{ConditionedLink, LinkConditioners};
+use super::settings::{Conditioner, ConditionerPreset};
+
+pub struct DevPanelPlugin {
+    /// The link between the client and the server, if the transport of the client has one
+    pub link: Option<ConditionedLink>,
+}
+
+impl Plugin for DevPanelPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+        if let Some(link) = &self.link {
+            app.insert_resource(link.clone());
+        }
+        app.init_resource::<DevPanel>();
+        app.add_systems(
+            Update,
+            (
+                toggle_dev_panel,
+                dev_panel_ui.run_if(resource_equals(DevPanel(true))),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// If true, the dev panel is displayed
+#[derive(Resource, Default, PartialEq)]
+pub struct DevPanel(pub bool);
+
+fn toggle_dev_panel(keys: Res<ButtonInput<KeyCode>>, mut panel: ResMut<DevPanel>) {
+    if keys.just_pressed(KeyCode::F5) {
+        panel.0 = !panel.0;
+    }
+}
+
+fn dev_panel_ui(mut contexts: EguiContexts, link: Option<Res<ConditionedLink>>) {
+    let current = link
+        .as_ref()
+        .map(|link| link.conditioners())
+        .unwrap_or_default();
+    let mut edited = current;
+    egui::Window::new("Client UDP link conditioner").show(contexts.ctx_mut(), |ui| {
+        if link.is_none() {
+            ui.label(
+                "The packets of this client don't go through a conditioned UDP link (another \
+                 transport, the local client of a host-server, or the link failed to start), so \
+                 they can't be conditioned while the game runs. Set the conditioners in the \
+                 settings instead.",
+            );
+        }
+        ui.add_enabled_ui(link.is_some(), |ui| {
+            ui.label("Presets (both directions)");
+            ui.horizontal(|ui| {
+                for preset in ConditionerPreset::ALL {
+                    if ui.button(preset.name()).clicked() {
+                        let conditioner = Conditioner::from_preset(preset);
+                        edited = LinkConditioners {
+                            incoming: Some(conditioner),
+                            outgoing: Some(conditioner),
+                        };
+                    }
+                }
+            });
+            ui.separator();
+            conditioner_ui(ui, "Incoming", &mut edited.incoming);
+            ui.separator();
+            conditioner_ui(ui, "Outgoing", &mut edited.outgoing);
+        });
+    });
+    let Some(link) = link else {
+        return;
+    };
+    if edited != current {
+        info!(?edited, "changing the link conditioners");
+        link.set_conditioners(edited);
+    }
+}
+
+fn conditioner_ui(ui: &mut egui::Ui, label: &str, conditioner: &mut Option<Conditioner>) {
+    let mut enabled = conditioner.is_some();
+    ui.checkbox(&mut enabled, label);
+    match (enabled, conditioner.as_mut()) {
+        (false, _) => *conditioner = None,
+        (true, None) => *conditioner = Some(Conditioner::from_preset(ConditionerPreset::Lan)),
+        (true, Some(conditioner)) => {
+            ui.add(egui::Slider::new(&mut conditioner.latency_ms, 0..=500).text("latency (ms)"));
+            ui.add(egui::Slider::new(&mut conditioner.jitter_ms, 0..=200).text("jitter (ms)"));
+            ui.add(egui::Slider::new(&mut conditioner.packet_loss, 0.0..=0.5).text("packet loss"));
+        }
+    }
+}
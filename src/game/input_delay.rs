@@ -0,0 +1,185 @@
+//! Adapts the input delay of the client to the measured network conditions.
+//!
+//! A higher input delay than the RTT means that there won't be any mispredictions, but the game feels
+//! less responsive. We pick a delay that covers the RTT and its jitter, within the configured bounds.
+//!
+//! lightyear copies the input delay into its sync manager when the connection manager is created,
+//! and only runs its input delay systems when the delay is not zero. So the delay never goes below
+//! one tick, and it only changes once the target has been different for a while: the client then
+//! reconnects with a new connection manager, which syncs with the new delay.
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_screen_diagnostics::{Aggregate, ScreenDiagnostics};
+use lightyear::prelude::client::*;
+use lightyear::prelude::Protocol;
+
+use super::latency::Latency;
+use super::protocol::*;
+use super::settings::AdaptiveInputDelay;
+
+pub const INPUT_DELAY: DiagnosticPath = DiagnosticPath::const_new("input_delay");
+
+pub struct InputDelayPlugin {
+    /// If None, the input delay stays the same as in the config
+    pub adaptive: Option<AdaptiveInputDelay>,
+}
+
+impl Plugin for InputDelayPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(INPUT_DELAY).with_max_history_length(1));
+        app.add_systems(Startup, setup_diagnostic);
+        app.add_systems(Update, measure_input_delay);
+        if let Some(adaptive) = self.adaptive {
+            app.insert_resource(AdaptiveInputDelayState {
+                settings: adaptive,
+                off_target: Duration::ZERO,
+            });
+            app.add_systems(Update, adapt_input_delay.before(measure_input_delay));
+        }
+    }
+}
+
+#[derive(Resource)]
+struct AdaptiveInputDelayState {
+    settings: AdaptiveInputDelay,
+    /// For how long the target delay has been different from the current one
+    off_target: Duration,
+}
+
+/// Display the input delay next to the KB_in/KB_out diagnostics
+fn setup_diagnostic(onscreen: Option<ResMut<ScreenDiagnostics>>) {
+    let Some(mut onscreen) = onscreen else {
+        return;
+    };
+    onscreen
+        .add("input_delay".to_string(), INPUT_DELAY)
+        .aggregate(Aggregate::Value)
+        .format(|v| format!("{v:.0}"));
+}
+
+fn measure_input_delay(mut diagnostics: Diagnostics, config: Res<ClientConfig>) {
+    diagnostics.add_measurement(&INPUT_DELAY, || config.prediction.input_delay_ticks as f64);
+}
+
+/// Number of ticks needed to cover the RTT plus the jitter margin, clamped between the bounds
+fn target_input_delay(
+    settings: &AdaptiveInputDelay,
+    rtt: Duration,
+    jitter: Duration,
+    tick_duration: Duration,
+) -> u16 {
+    let covered = rtt.as_secs_f32() + settings.jitter_margin * jitter.as_secs_f32();
+    let ticks = (covered / tick_duration.as_secs_f32()).ceil() as u16;
+    settings.clamp(ticks)
+}
+
+fn adapt_input_delay(
+    mut commands: Commands,
+    time: Res<Time>,
+    latency: Res<Latency>,
+    mut state: ResMut<AdaptiveInputDelayState>,
+    mut config: ResMut<ClientConfig>,
+) {
+    // wait for the first pong before adapting
+    if latency.rtt() == Duration::ZERO {
+        state.off_target = Duration::ZERO;
+        return;
+    }
+    let target = target_input_delay(
+        &state.settings,
+        latency.rtt(),
+        latency.jitter(),
+        config.shared.tick.tick_duration,
+    );
+    let current = config.prediction.input_delay_ticks;
+    if target == current {
+        state.off_target = Duration::ZERO;
+        return;
+    }
+    state.off_target += time.delta();
+    if state.off_target < Duration::from_millis(state.settings.step_interval_ms) {
+        return;
+    }
+    info!(
+        rtt = ?latency.rtt(),
+        jitter = ?latency.jitter(),
+        ?current,
+        ?target,
+        "adapting the input delay, reconnecting to sync with it"
+    );
+    config.prediction.input_delay_ticks = target;
+    state.off_target = Duration::ZERO;
+    commands.add(reconnect);
+}
+
+/// Replace the connection manager, whose sync manager keeps the input delay it was created with,
+/// and the connection, like lightyear does for the first connection
+fn reconnect(world: &mut World) {
+    let config = world.resource::<ClientConfig>().clone();
+    if let Err(e) = world.resource_mut::<ClientConnection>().disconnect() {
+        error!(?e, "failed to disconnect");
+    }
+    world.insert_resource(ClientConnectionManager::new(
+        protocol().channel_registry(),
+        config.packet,
+        config.sync,
+        config.ping,
+        config.prediction.input_delay_ticks,
+    ));
+    // the previous connection (and its socket) is dropped before the new one connects
+    world.insert_resource(config.net.build_client());
+    if let Err(e) = world.resource_mut::<ClientConnection>().connect() {
+        error!(?e, "failed to reconnect");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(min_ticks: u16, max_ticks: u16) -> AdaptiveInputDelay {
+        AdaptiveInputDelay {
+            min_ticks,
+            max_ticks,
+            jitter_margin: 2.0,
+            step_interval_ms: 500,
+        }
+    }
+
+    const TICK: Duration = Duration::from_millis(16);
+
+    #[test]
+    fn target_covers_the_rtt_and_the_jitter_margin() {
+        let settings = settings(1, 10);
+        // 50ms + 2 * 5ms = 60ms, rounded up to 4 ticks of 16ms
+        let target = target_input_delay(
+            &settings,
+            Duration::from_millis(50),
+            Duration::from_millis(5),
+            TICK,
+        );
+        assert_eq!(target, 4);
+        // without jitter, 40ms is 2.5 ticks
+        let target = target_input_delay(&settings, Duration::from_millis(40), Duration::ZERO, TICK);
+        assert_eq!(target, 3);
+    }
+
+    #[test]
+    fn target_stays_within_the_bounds() {
+        let settings = settings(2, 5);
+        let target = target_input_delay(&settings, Duration::ZERO, Duration::ZERO, TICK);
+        assert_eq!(target, 2);
+        let target = target_input_delay(&settings, Duration::from_secs(1), Duration::ZERO, TICK);
+        assert_eq!(target, 5);
+    }
+
+    #[test]
+    fn target_never_reaches_zero() {
+        let target = target_input_delay(&settings(0, 5), Duration::ZERO, Duration::ZERO, TICK);
+        assert_eq!(target, 1);
+        // even if the bounds are inverted
+        let target = target_input_delay(&settings(0, 0), Duration::ZERO, Duration::ZERO, TICK);
+        assert_eq!(target, 1);
+    }
+}
@@ -0,0 +1,247 @@
+//! Server-side lag compensation of the hits on the ball.
+//!
+//! When the ball is not predicted, each client sees it in the past (interpolated), but moves its own
+//! boxes in the present (predicted). A hit that looked right on the client can then miss on the server,
+//! where the ball already moved on.
+//! To fix this, the server keeps a short history of the ball positions. For every player, it rewinds the
+//! ball to the tick that the owning client was seeing when it pressed the inputs of the present tick,
+//! according to the `ViewTick` that the client reports; if the box touches the rewound ball but not
+//! the present one, the ball gets the impulse it would have received from the hit the client saw.
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::*;
+use std::collections::VecDeque;
+
+use super::protocol::*;
+use super::server::{movement, DisconnectEvent, MessageEvent, ServerConfig};
+use super::settings::{PhysicsSettings, ReplicationMode, ReplicationPolicy};
+use super::shared::FixedSet;
+
+/// We never rewind the ball further than this in the past, so that players with a huge latency
+/// can't hit the ball where it was a long time ago
+const MAX_REWIND: Duration = Duration::from_millis(250);
+/// A player can only get one compensated hit every this many ticks, so that staying in contact
+/// with the past ball doesn't push it on every tick
+const HIT_COOLDOWN_TICKS: i16 = 8;
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ViewTicks>();
+        app.add_systems(PreUpdate, update_view_ticks);
+        app.add_systems(
+            FixedUpdate,
+            compensate_ball_hits
+                .in_set(FixedSet::Main)
+                .after(movement)
+                .run_if(ball_is_not_predicted),
+        );
+        app.add_systems(
+            FixedPostUpdate,
+            (add_ball_history, record_ball_history).chain(),
+        );
+    }
+}
+
+/// The last `ViewTick` reported by each client
+#[derive(Resource, Default)]
+pub struct ViewTicks(HashMap<ClientId, ViewTick>);
+
+impl ViewTicks {
+    /// Number of ticks between the inputs of the client and the ball that it was seeing when it
+    /// pressed them, i.e. about an RTT plus the interpolation delay. Zero for the clients that didn't
+    /// report their view tick yet
+    pub fn rewind_ticks(&self, client_id: ClientId) -> i16 {
+        self.0
+            .get(&client_id)
+            .map_or(0, |view| (view.input_tick - view.interpolation_tick).max(0))
+    }
+}
+
+/// Positions of the ball on the most recent ticks, oldest first
+#[derive(Component, Default)]
+pub struct BallHistory(VecDeque<(Tick, Vec2)>);
+
+impl BallHistory {
+    /// Position of the ball at `tick`, or at the closest recorded tick before it
+    pub fn at(&self, tick: Tick) -> Option<Vec2> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(recorded, _)| *recorded - tick <= 0)
+            .or(self.0.front())
+            .map(|(_, position)| *position)
+    }
+}
+
+fn ball_is_not_predicted(policy: Res<ReplicationPolicy>) -> bool {
+    policy.ball != ReplicationMode::Predicted
+}
+
+fn add_ball_history(
+    mut commands: Commands,
+    balls: Query<
+        Entity,
+        (
+            With<BallMarker>,
+            Without<BallHistory>,
+            Without<Confirmed>,
+            Without<Predicted>,
+        ),
+    >,
+) {
+    for entity in balls.iter() {
+        commands.entity(entity).insert(BallHistory::default());
+    }
+}
+
+fn record_ball_history(
+    tick_manager: Res<TickManager>,
+    config: Res<ServerConfig>,
+    mut balls: Query<(&Position, &mut BallHistory)>,
+) {
+    let tick = tick_manager.tick();
+    let max_ticks = max_rewind_ticks(config.shared.tick.tick_duration);
+    for (position, mut history) in balls.iter_mut() {
+        history.0.push_back((tick, position.0));
+        while history
+            .0
+            .front()
+            .is_some_and(|(recorded, _)| tick - *recorded > max_ticks)
+        {
+            history.0.pop_front();
+        }
+    }
+}
+
+fn max_rewind_ticks(tick_duration: Duration) -> i16 {
+    (MAX_REWIND.as_secs_f32() / tick_duration.as_secs_f32()).ceil() as i16
+}
+
+fn update_view_ticks(
+    mut reports: EventReader<MessageEvent<ViewTick>>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut view_ticks: ResMut<ViewTicks>,
+) {
+    for report in reports.read() {
+        let client_id = *report.context();
+        // the local client of a host-server sees the ball of the server directly
+        if matches!(client_id, ClientId::Local(_)) {
+            continue;
+        }
+        view_ticks.0.insert(client_id, *report.message());
+    }
+    for disconnection in disconnections.read() {
+        view_ticks.0.remove(disconnection.context());
+    }
+}
+
+/// If the box touches the circle, return the normal of the contact (from the box towards the circle)
+fn box_circle_contact(
+    box_center: Vec2,
+    box_rotation: f32,
+    half_size: Vec2,
+    circle_center: Vec2,
+    radius: f32,
+) -> Option<Vec2> {
+    let local = Vec2::from_angle(-box_rotation).rotate(circle_center - box_center);
+    let closest = local.clamp(-half_size, half_size);
+    if local.distance_squared(closest) > radius * radius {
+        return None;
+    }
+    let normal = Vec2::from_angle(box_rotation).rotate(local - closest);
+    Some(
+        normal
+            .try_normalize()
+            .unwrap_or_else(|| (circle_center - box_center).normalize_or_zero()),
+    )
+}
+
+pub fn compensate_ball_hits(
+    tick_manager: Res<TickManager>,
+    config: Res<ServerConfig>,
+    view_ticks: Res<ViewTicks>,
+    physics: Res<PhysicsSettings>,
+    mut last_hits: Local<HashMap<Entity, Tick>>,
+    players: Query<
+        (
+            Entity,
+            &PlayerId,
+            &Position,
+            &Rotation,
+            &LinearVelocity,
+            &Mass,
+        ),
+        (Without<BallMarker>, Without<Confirmed>, Without<Predicted>),
+    >,
+    mut balls: Query<
+        (&Position, &mut LinearVelocity, &Mass, &BallHistory),
+        (With<BallMarker>, Without<Confirmed>, Without<Predicted>),
+    >,
+) {
+    let tick = tick_manager.tick();
+    let half_size = Vec2::splat(PLAYER_SIZE / 2.0);
+    let restitution = (physics.player.restitution + physics.ball.restitution) / 2.0;
+    last_hits.retain(|_, hit_tick| tick - *hit_tick < HIT_COOLDOWN_TICKS);
+
+    for (ball_position, mut ball_velocity, ball_mass, history) in balls.iter_mut() {
+        for (entity, player_id, position, rotation, velocity, mass) in players.iter() {
+            if last_hits.contains_key(&entity) {
+                continue;
+            }
+            // the physics engine already handles the hits on the present ball
+            if box_circle_contact(
+                position.0,
+                rotation.as_radians(),
+                half_size,
+                ball_position.0,
+                BALL_SIZE,
+            )
+            .is_some()
+            {
+                continue;
+            }
+            // the inputs applied on this tick were pressed while the client showed the ball of
+            // `tick - rewind`
+            let delay = view_ticks
+                .rewind_ticks(player_id.0)
+                .min(max_rewind_ticks(config.shared.tick.tick_duration));
+            if delay == 0 {
+                continue;
+            }
+            let Some(past_ball) = history.at(tick - delay) else {
+                continue;
+            };
+            let Some(normal) = box_circle_contact(
+                position.0,
+                rotation.as_radians(),
+                half_size,
+                past_ball,
+                BALL_SIZE,
+            ) else {
+                continue;
+            };
+            // only hit the ball if the box was moving towards it
+            let approach_speed = (velocity.0 - ball_velocity.0).dot(normal);
+            if approach_speed <= 0.0 {
+                continue;
+            }
+            // impulse of an inelastic collision between the box and the ball
+            let impulse = (1.0 + restitution) * approach_speed * mass.0 * ball_mass.0
+                / (mass.0 + ball_mass.0);
+            ball_velocity.0 += normal * impulse / ball_mass.0;
+            last_hits.insert(entity, tick);
+            info!(
+                ?tick,
+                ?delay,
+                client_id = ?player_id.0,
+                ?past_ball,
+                ?impulse,
+                "lag compensated ball hit"
+            );
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Round-trip time, jitter and interpolation delay of the client.
+//!
+//! The interpolation delay is the one that lightyear applies to the interpolated entities: how far
+//! their interpolation tick (from `InterpolateStatus`) is behind the latest server tick that the
+//! client received.
+//! lightyear keeps the RTT and jitter of its ping manager private, so for those the client sends a
+//! `Ping` with the time of its clock on the unreliable heartbeat channel, and the server echoes it
+//! right away as a `Pong`. The RTT is the average of the last samples, and the jitter their average
+//! deviation from it.
+//! Along with each ping, the client reports the tick of the inputs it sends and its interpolation
+//! tick: the server applies those inputs while the client shows the ball of the interpolation tick,
+//! so it rewinds the ball by the difference for the lag compensation. Both ticks come from the clock
+//! of the client, so the report doesn't depend on how the RTT is split between the two directions.
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_xpbd_2d::prelude::Position;
+use lightyear::prelude::client::*;
+use std::collections::VecDeque;
+
+use super::protocol::*;
+
+/// How often the client sends a ping
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of samples that the statistics are computed over
+const SAMPLES: usize = 16;
+
+pub struct LatencyPlugin;
+
+impl Plugin for LatencyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Latency>();
+        app.add_systems(
+            Update,
+            (
+                track_connection,
+                receive_pongs,
+                measure_interpolation_delay,
+                send_pings,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// The last RTT samples of the client, and its interpolation delay
+#[derive(Resource, Default)]
+pub struct Latency {
+    connected: bool,
+    samples: VecDeque<Duration>,
+    interpolation_delay: Duration,
+    /// `None` until the client interpolates an entity
+    interpolation_tick: Option<Tick>,
+}
+
+impl Latency {
+    pub fn add_sample(&mut self, rtt: Duration) {
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// Average RTT over the last samples; zero before the first pong
+    pub fn rtt(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    /// Average deviation of the samples from the RTT
+    pub fn jitter(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rtt = self.rtt().as_secs_f64();
+        let deviation: f64 = self
+            .samples
+            .iter()
+            .map(|sample| (sample.as_secs_f64() - rtt).abs())
+            .sum();
+        Duration::from_secs_f64(deviation / self.samples.len() as f64)
+    }
+
+    /// How far in the past the client shows the interpolated entities, compared to the latest
+    /// server tick it received; zero until the client interpolates an entity
+    pub fn interpolation_delay(&self) -> Duration {
+        self.interpolation_delay
+    }
+}
+
+/// Only ping while connected, and forget the samples of a previous connection
+fn track_connection(
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut latency: ResMut<Latency>,
+) {
+    if connections.read().count() > 0 {
+        latency.connected = true;
+    }
+    if disconnections.read().count() > 0 {
+        latency.connected = false;
+        latency.samples.clear();
+        latency.interpolation_delay = Duration::ZERO;
+        latency.interpolation_tick = None;
+    }
+}
+
+/// Read the interpolation tick that lightyear computed for this frame; it is the same for every
+/// interpolated entity. Without one, the previous delay is kept
+fn measure_interpolation_delay(
+    connection: Res<ClientConnectionManager>,
+    config: Res<ClientConfig>,
+    interpolated: Query<&InterpolateStatus<Position>>,
+    mut latency: ResMut<Latency>,
+) {
+    if !connection.is_synced() {
+        return;
+    }
+    let Some(status) = interpolated.iter().next() else {
+        return;
+    };
+    let behind = (connection.latest_received_server_tick() - status.current_tick) as f32
+        - status.current_overstep;
+    latency.interpolation_delay = config.shared.tick.tick_duration.mul_f32(behind.max(0.0));
+    latency.interpolation_tick = Some(status.current_tick);
+}
+
+fn send_pings(
+    time: Res<Time>,
+    tick_manager: Res<TickManager>,
+    config: Res<ClientConfig>,
+    latency: Res<Latency>,
+    mut connection: ResMut<ClientConnectionManager>,
+    mut since_last: Local<Duration>,
+) {
+    if !latency.connected {
+        return;
+    }
+    *since_last += time.delta();
+    if *since_last < PING_INTERVAL {
+        return;
+    }
+    *since_last = Duration::ZERO;
+    if let Err(e) =
+        connection.send_message::<HeartbeatChannel, Ping>(Ping(time.elapsed().as_secs_f64()))
+    {
+        error!(?e, "failed to send ping");
+    }
+    let Some(interpolation_tick) = latency.interpolation_tick else {
+        return;
+    };
+    // the inputs of this frame are for a later tick when there is an input delay
+    let view_tick = ViewTick {
+        input_tick: tick_manager.tick() + config.prediction.input_delay_ticks as i16,
+        interpolation_tick,
+    };
+    if let Err(e) = connection.send_message::<HeartbeatChannel, ViewTick>(view_tick) {
+        error!(?e, "failed to send the view tick");
+    }
+}
+
+fn receive_pongs(
+    time: Res<Time>,
+    mut pongs: EventReader<MessageEvent<Pong>>,
+    mut latency: ResMut<Latency>,
+) {
+    let now = time.elapsed().as_secs_f64();
+    for pong in pongs.read() {
+        if let Ok(rtt) = Duration::try_from_secs_f64(now - pong.message().0) {
+            latency.add_sample(rtt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_is_the_deviation_from_the_rtt() {
+        let mut latency = Latency::default();
+        assert_eq!(latency.rtt(), Duration::ZERO);
+        for ms in [40, 60, 40, 60] {
+            latency.add_sample(Duration::from_millis(ms));
+        }
+        assert_eq!(latency.rtt(), Duration::from_millis(50));
+        assert_eq!(latency.jitter().as_millis(), 10);
+        // only the last samples count
+        for _ in 0..SAMPLES {
+            latency.add_sample(Duration::from_millis(100));
+        }
+        assert_eq!(latency.rtt(), Duration::from_millis(100));
+        assert_eq!(latency.jitter(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,295 @@
+//! Leaderboard screens, with the best players of a server over all its matches.
+//!
+//! In game, toggle the screen with L; it asks the server for the leaderboard every time it opens.
+//! The menu has a leaderboard screen too: the menu is not connected to any server, so it shows the records
+//! of the server hosted on this machine, read from the data dir of the settings.
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use lightyear::prelude::client::*;
+use lightyear::prelude::ClientId;
+use std::path::PathBuf;
+
+use crate::menu::{FOREGROUND, FOREGROUND_DIM};
+use crate::GameState;
+
+use super::protocol::*;
+use super::records::{PlayerRecords, LEADERBOARD_SIZE};
+use super::ui::spawn_cell;
+
+const PANEL_BACKGROUND: Color = Color::rgba(0.0, 0.0, 0.0, 0.9);
+const NAME_WIDTH: f32 = 180.0;
+const COLUMN_WIDTH: f32 = 100.0;
+const COLUMNS: [&str; 4] = ["RATING", "MATCHES", "TOUCHES", "TIME"];
+const FONT_SIZE: f32 = 20.0;
+
+pub struct LeaderboardPlugin;
+
+impl Plugin for LeaderboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LeaderboardTable>();
+        app.add_systems(Update, receive_leaderboard);
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, spawn_leaderboard);
+            app.add_systems(
+                Update,
+                (toggle_leaderboard, update_leaderboard)
+                    .chain()
+                    .after(receive_leaderboard),
+            );
+        }
+    }
+}
+
+/// The last leaderboard received from the server
+#[derive(Resource, Default)]
+pub struct LeaderboardTable(pub Vec<LeaderboardEntry>);
+
+/// Ask the server for the leaderboard; the answer ends up in [`LeaderboardTable`]
+pub fn request_leaderboard(connection: &mut ClientConnectionManager) {
+    if let Err(e) = connection.send_message::<Channel1, LeaderboardRequest>(LeaderboardRequest) {
+        error!(?e, "failed to request the leaderboard");
+    }
+}
+
+fn receive_leaderboard(
+    mut events: EventReader<MessageEvent<Leaderboard>>,
+    mut table: ResMut<LeaderboardTable>,
+) {
+    for event in events.read() {
+        table.0 = event.message().0.clone();
+    }
+}
+
+#[derive(Component)]
+struct LeaderboardScreen;
+
+/// Parent of one row per player
+#[derive(Component)]
+struct LeaderboardRows;
+
+fn spawn_leaderboard(mut commands: Commands) {
+    commands
+        .spawn((
+            LeaderboardScreen,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(4.0),
+                    ..default()
+                },
+                background_color: PANEL_BACKGROUND.into(),
+                visibility: Visibility::Hidden,
+                // above the scoreboard
+                z_index: ZIndex::Global(1),
+                ..default()
+            },
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                "LEADERBOARD",
+                TextStyle {
+                    font_size: 48.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            ));
+            spawn_header(screen);
+            screen.spawn((LeaderboardRows, rows_bundle()));
+            screen.spawn(TextBundle::from_section(
+                "[L] close",
+                TextStyle {
+                    font_size: 18.0,
+                    color: Color::GRAY,
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn spawn_header(parent: &mut ChildBuilder) {
+    parent.spawn(NodeBundle::default()).with_children(|header| {
+        spawn_cell(header, "PLAYER", NAME_WIDTH, FONT_SIZE, Color::GRAY);
+        for column in COLUMNS {
+            spawn_cell(header, column, COLUMN_WIDTH, FONT_SIZE, Color::GRAY);
+        }
+    });
+}
+
+fn rows_bundle() -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(2.0),
+            ..default()
+        },
+        ..default()
+    }
+}
+
+/// One row per entry; the row of `own_id` stands out
+fn spawn_rows(rows: &mut ChildBuilder, entries: &[LeaderboardEntry], own_id: Option<ClientId>) {
+    for entry in entries {
+        let color = if Some(entry.client_id) == own_id {
+            Color::YELLOW
+        } else {
+            Color::WHITE
+        };
+        let time_played = entry.time_played as u32;
+        rows.spawn(NodeBundle::default()).with_children(|row| {
+            spawn_cell(row, &entry.name, NAME_WIDTH, FONT_SIZE, color);
+            let rating = format!("{:.0}", entry.rating);
+            spawn_cell(row, &rating, COLUMN_WIDTH, FONT_SIZE, color);
+            let matches = entry.matches.to_string();
+            spawn_cell(row, &matches, COLUMN_WIDTH, FONT_SIZE, color);
+            let ball_touches = entry.ball_touches.to_string();
+            spawn_cell(row, &ball_touches, COLUMN_WIDTH, FONT_SIZE, color);
+            let time = format!("{}h{:02}", time_played / 3600, time_played / 60 % 60);
+            spawn_cell(row, &time, COLUMN_WIDTH, FONT_SIZE, color);
+        });
+    }
+}
+
+fn toggle_leaderboard(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut connection: ResMut<ClientConnectionManager>,
+    mut screens: Query<&mut Visibility, With<LeaderboardScreen>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyL) {
+        return;
+    }
+    for mut visibility in screens.iter_mut() {
+        *visibility = if *visibility == Visibility::Hidden {
+            request_leaderboard(&mut connection);
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+fn update_leaderboard(
+    mut commands: Commands,
+    table: Res<LeaderboardTable>,
+    connection: Res<ClientConnection>,
+    rows: Query<Entity, With<LeaderboardRows>>,
+) {
+    if !table.is_changed() {
+        return;
+    }
+    let own_id = connection.id();
+    for rows in rows.iter() {
+        commands.entity(rows).despawn_descendants();
+        commands
+            .entity(rows)
+            .with_children(|rows| spawn_rows(rows, &table.0, Some(own_id)));
+    }
+}
+
+pub struct LeaderboardMenuPlugin {
+    /// Data dir of the server hosted on this machine; `None` if it doesn't keep records
+    pub dir: Option<PathBuf>,
+}
+
+impl Plugin for LeaderboardMenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RecordsDir(self.dir.clone()));
+        app.add_systems(OnEnter(GameState::Leaderboard), spawn_menu_leaderboard);
+        app.add_systems(
+            Update,
+            leave_menu_leaderboard.run_if(in_state(GameState::Leaderboard)),
+        );
+        app.add_systems(OnExit(GameState::Leaderboard), despawn_menu_leaderboard);
+    }
+}
+
+#[derive(Resource)]
+struct RecordsDir(Option<PathBuf>);
+
+#[derive(Component)]
+struct MenuLeaderboard;
+
+fn spawn_menu_leaderboard(mut commands: Commands, dir: Res<RecordsDir>) {
+    let entries = match &dir.0 {
+        Some(dir) => PlayerRecords::load(dir).map(|records| records.leaderboard(LEADERBOARD_SIZE)),
+        None => Ok(Vec::new()),
+    };
+    let message_style = TextStyle {
+        font_size: 24.0,
+        color: FOREGROUND_DIM,
+        ..default()
+    };
+    commands
+        .spawn((
+            MenuLeaderboard,
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    row_gap: Val::Px(8.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                "LEADERBOARD",
+                TextStyle {
+                    font_size: 48.0,
+                    color: FOREGROUND,
+                    ..default()
+                },
+            ));
+            match entries {
+                Ok(entries) if entries.is_empty() => {
+                    screen.spawn(TextBundle::from_section(
+                        "no match recorded on this machine yet",
+                        message_style,
+                    ));
+                }
+                Ok(entries) => {
+                    spawn_header(screen);
+                    screen
+                        .spawn(rows_bundle())
+                        .with_children(|rows| spawn_rows(rows, &entries, None));
+                }
+                Err(e) => {
+                    screen.spawn(TextBundle::from_section(
+                        format!("cannot read the records: {e}"),
+                        message_style,
+                    ));
+                }
+            }
+            screen.spawn(TextBundle::from_section(
+                "Escape to go back to the menu",
+                TextStyle {
+                    font_size: 16.0,
+                    color: FOREGROUND_DIM,
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn leave_menu_leaderboard(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut next_game_state: ResMut<NextState<GameState>>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_game_state.set(GameState::Menu);
+    }
+}
+
+fn despawn_menu_leaderboard(mut commands: Commands, screens: Query<Entity, With<MenuLeaderboard>>) {
+    for entity in screens.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
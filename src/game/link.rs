@@ -0,0 +1,226 @@
+//! UDP link between the client and the server, with link conditioners that can change while the
+//! game runs.
+//!
+//! lightyear only applies the conditioner of an io when the io is built. Instead, the io of the
+//! client is an in-memory channel, and a thread of ours relays the packets between that channel and
+//! the UDP socket, delaying or dropping them according to the current conditioners.
+//! The incoming conditioner applies to the packets received from the server, the outgoing one to
+//! the packets sent to it, so that both directions of the link can be simulated from the client.
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use lightyear::prelude::TransportConfig;
+
+use super::settings::Conditioner;
+
+/// How long the relay thread sleeps when there is nothing to do
+const RELAY_INTERVAL: Duration = Duration::from_millis(1);
+/// Larger than any packet that lightyear sends
+const MAX_PACKET_SIZE: usize = 1500;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LinkConditioners {
+    /// Conditioner of the packets received by the client
+    pub incoming: Option<Conditioner>,
+    /// Conditioner of the packets sent by the client
+    pub outgoing: Option<Conditioner>,
+}
+
+/// Handle to the relay thread, to change its conditioners
+#[derive(Resource, Clone)]
+pub struct ConditionedLink {
+    conditioners: Arc<Mutex<LinkConditioners>>,
+}
+
+impl ConditionedLink {
+    /// Bind the socket of the client and start relaying its packets to the server.
+    /// Returns the transport that the io of the client must use
+    pub fn start(
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+        conditioners: LinkConditioners,
+    ) -> std::io::Result<(Self, TransportConfig)> {
+        let socket = UdpSocket::bind(client_addr)?;
+        socket.connect(server_addr)?;
+        socket.set_nonblocking(true)?;
+        let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+        let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
+        let link = Self {
+            conditioners: Arc::new(Mutex::new(conditioners)),
+        };
+        let relay = Relay {
+            socket,
+            to_server: to_server_recv,
+            from_server: from_server_send,
+            conditioners: link.conditioners.clone(),
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        };
+        thread::Builder::new()
+            .name("conditioned link".to_string())
+            .spawn(move || relay.run())?;
+        let transport = TransportConfig::LocalChannel {
+            recv: from_server_recv,
+            send: to_server_send,
+        };
+        Ok((link, transport))
+    }
+
+    pub fn conditioners(&self) -> LinkConditioners {
+        *self.conditioners.lock().unwrap()
+    }
+
+    /// The new conditioners apply to the next packets; the packets already delayed keep their delay
+    pub fn set_conditioners(&self, conditioners: LinkConditioners) {
+        *self.conditioners.lock().unwrap() = conditioners;
+    }
+}
+
+/// Time at which a packet sent now is delivered, or None if it gets lost.
+/// Like lightyear's conditioner, the jitter is added or removed uniformly to the latency
+fn delivery_time(conditioner: Option<&Conditioner>, now: Instant) -> Option<Instant> {
+    let Some(conditioner) = conditioner else {
+        return Some(now);
+    };
+    if rand::random::<f32>() < conditioner.packet_loss {
+        return None;
+    }
+    let jitter = conditioner.jitter_ms as f32 * (rand::random::<f32>() * 2.0 - 1.0);
+    let delay_ms = (conditioner.latency_ms as f32 + jitter).max(0.0);
+    Some(now + Duration::from_secs_f32(delay_ms / 1000.0))
+}
+
+/// Remove the packets that are due from the queue, in the order they must be delivered
+fn take_due(queue: &mut Vec<(Instant, Vec<u8>)>, now: Instant) -> Vec<Vec<u8>> {
+    queue.sort_by_key(|(delivery, _)| *delivery);
+    let due = queue.partition_point(|(delivery, _)| *delivery <= now);
+    queue.drain(..due).map(|(_, packet)| packet).collect()
+}
+
+struct Relay {
+    socket: UdpSocket,
+    /// Packets that lightyear sends to the server
+    to_server: Receiver<Vec<u8>>,
+    /// Packets for lightyear, received from the server
+    from_server: Sender<Vec<u8>>,
+    conditioners: Arc<Mutex<LinkConditioners>>,
+    /// Delayed packets, with the time at which they are delivered
+    outgoing: Vec<(Instant, Vec<u8>)>,
+    incoming: Vec<(Instant, Vec<u8>)>,
+}
+
+impl Relay {
+    /// Relay the packets until the io of the client is dropped
+    fn run(mut self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        loop {
+            let now = Instant::now();
+            let conditioners = *self.conditioners.lock().unwrap();
+            loop {
+                match self.to_server.try_recv() {
+                    Ok(packet) => {
+                        if let Some(delivery) = delivery_time(conditioners.outgoing.as_ref(), now) {
+                            self.outgoing.push((delivery, packet));
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            loop {
+                match self.socket.recv(&mut buffer) {
+                    Ok(len) => {
+                        if let Some(delivery) = delivery_time(conditioners.incoming.as_ref(), now) {
+                            self.incoming.push((delivery, buffer[..len].to_vec()));
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // e.g. the server isn't listening yet; netcode retries on its own
+                    Err(e) => {
+                        trace!(?e, "failed to receive a packet from the server");
+                        break;
+                    }
+                }
+            }
+            for packet in take_due(&mut self.outgoing, now) {
+                if let Err(e) = self.socket.send(&packet) {
+                    trace!(?e, "failed to send a packet to the server");
+                }
+            }
+            for packet in take_due(&mut self.incoming, now) {
+                if self.from_server.send(packet).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(RELAY_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn send_and_count(send: &Sender<Vec<u8>>, server: &UdpSocket, packets: usize) -> usize {
+        for i in 0..packets {
+            send.send(vec![i as u8]).unwrap();
+        }
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let mut received = 0;
+        while server.recv(&mut buffer).is_ok() {
+            received += 1;
+        }
+        received
+    }
+
+    #[test]
+    fn conditioners_change_while_relaying() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let lost = Conditioner {
+            latency_ms: 0,
+            jitter_ms: 0,
+            packet_loss: 1.0,
+        };
+        let (link, transport) = ConditionedLink::start(
+            (Ipv4Addr::LOCALHOST, 0).into(),
+            server.local_addr().unwrap(),
+            LinkConditioners {
+                incoming: None,
+                outgoing: Some(lost),
+            },
+        )
+        .unwrap();
+        let TransportConfig::LocalChannel { send, .. } = transport else {
+            panic!("the link must give a local channel transport");
+        };
+        assert_eq!(send_and_count(&send, &server, 10), 0);
+
+        link.set_conditioners(LinkConditioners::default());
+        assert_eq!(send_and_count(&send, &server, 10), 10);
+    }
+
+    #[test]
+    fn delayed_packets_are_delivered_in_order() {
+        let now = Instant::now();
+        let mut queue = vec![
+            (now + Duration::from_millis(20), vec![2]),
+            (now, vec![0]),
+            (now + Duration::from_millis(10), vec![1]),
+        ];
+        assert_eq!(take_due(&mut queue, now), vec![vec![0]]);
+        assert_eq!(
+            take_due(&mut queue, now + Duration::from_millis(30)),
+            vec![vec![1], vec![2]]
+        );
+        assert!(queue.is_empty());
+    }
+}
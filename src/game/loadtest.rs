@@ -0,0 +1,391 @@
+//! Load test: many headless clients connected to one server.
+//!
+//! Each client is a separate bevy app with the normal client plugins, but without any rendering.
+//! The clients are updated in turn on one thread; use `--processes` to spread them over several
+//! processes when one process can't keep up. Every process prints its own report:
+//! how many clients connected, the RTT distribution, the tick rate of the server as seen by the clients
+//! and the bandwidth per client.
+//!
+//! Run with `cargo run --bin loadtest -- --clients 32 --transport udp`
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use bevy::asset::ron;
+use bevy::diagnostic::{DiagnosticsPlugin, DiagnosticsStore};
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use clap::{Parser, ValueEnum};
+use lightyear::prelude::client::{ClientConnectionManager, ConnectEvent};
+use lightyear::prelude::Mode;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+
+use super::client::ExampleClientPlugin;
+use super::latency::Latency;
+use super::protocol::protocol;
+use super::settings::{get_client_net_config, ClientTransports, ServerTransports, Settings};
+use super::shared::{SharedPlugin, FIXED_TIMESTEP_HZ};
+use super::{build_client_config, client};
+
+const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// How often the statistics of the clients are sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the scripted clients keep pressing the same key
+const SCRIPT_STEP: Duration = Duration::from_millis(1000);
+/// How often the random clients pick new keys
+const RANDOM_STEP: Duration = Duration::from_millis(500);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadTestTransport {
+    Udp,
+    WebSocket,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadTestActions {
+    /// Every client moves in a square, starting at a different corner
+    Scripted,
+    /// Every client presses random keys
+    Random,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about = "Connect many headless clients to a server and measure how it behaves")]
+pub struct LoadTestArgs {
+    /// Total number of clients
+    #[arg(short, long, default_value_t = 32)]
+    pub clients: u64,
+
+    /// Number of processes that the clients are spread over
+    #[arg(short, long, default_value_t = 1)]
+    pub processes: u64,
+
+    #[arg(short, long, value_enum, default_value_t = LoadTestTransport::Udp)]
+    pub transport: LoadTestTransport,
+
+    #[arg(short, long, value_enum, default_value_t = LoadTestActions::Random)]
+    pub actions: LoadTestActions,
+
+    /// How long the test runs, in seconds
+    #[arg(short, long, default_value_t = 30)]
+    pub duration: u64,
+
+    /// Id of the first client; the next clients get the following ids. Random if not set
+    #[arg(long)]
+    pub first_id: Option<u64>,
+
+    /// Address of the server, instead of the one from the settings
+    #[arg(long)]
+    pub server_addr: Option<std::net::Ipv4Addr>,
+}
+
+pub fn run_loadtest(args: LoadTestArgs) {
+    // leave room below the ids for the real clients
+    let first_id = args
+        .first_id
+        .unwrap_or_else(|| 1_000_000 + rand::random::<u32>() as u64);
+    if args.processes > 1 {
+        spawn_workers(&args, first_id);
+        return;
+    }
+
+    let settings_str = include_str!("../../assets/settings.ron");
+    let mut settings = ron::de::from_str::<Settings>(settings_str).unwrap();
+    configure_transport(&mut settings, args.transport);
+    if let Some(server_addr) = args.server_addr {
+        settings.client.server_addr = server_addr;
+    }
+    // the load test measures the real network, not a simulated one
+    settings.client.conditioner = None;
+
+    let mut clients: Vec<LoadTestClient> = (0..args.clients)
+        .map(|i| LoadTestClient::new(&settings, first_id + i))
+        .collect();
+    println!(
+        "load test: {} clients (ids {first_id}..{}) over {:?} for {}s",
+        clients.len(),
+        first_id + args.clients,
+        args.transport,
+        args.duration
+    );
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(args.duration);
+    let mut last_sample = start;
+    while Instant::now() < end {
+        let frame_start = Instant::now();
+        let elapsed = frame_start - start;
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.press_keys(args.actions, i, elapsed);
+            client.app.update();
+        }
+        if frame_start - last_sample >= SAMPLE_INTERVAL {
+            last_sample = frame_start;
+            for client in clients.iter_mut() {
+                client.sample(frame_start);
+            }
+        }
+        if let Some(remaining) = FRAME_DURATION.checked_sub(frame_start.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+    report(&clients, start.elapsed());
+}
+
+/// Run the clients in several processes, by starting this binary again with a part of the clients
+fn spawn_workers(args: &LoadTestArgs, first_id: u64) {
+    let exe = std::env::current_exe().expect("failed to find the loadtest executable");
+    let per_process = args.clients.div_ceil(args.processes);
+    let mut workers = Vec::new();
+    for p in 0..args.processes {
+        let first = p * per_process;
+        let count = per_process.min(args.clients.saturating_sub(first));
+        if count == 0 {
+            break;
+        }
+        let mut command = Command::new(&exe);
+        command
+            .arg("--clients")
+            .arg(count.to_string())
+            .arg("--processes")
+            .arg("1")
+            .arg("--transport")
+            .arg(args.transport.to_possible_value().unwrap().get_name())
+            .arg("--actions")
+            .arg(args.actions.to_possible_value().unwrap().get_name())
+            .arg("--duration")
+            .arg(args.duration.to_string())
+            .arg("--first-id")
+            .arg((first_id + first).to_string());
+        if let Some(server_addr) = args.server_addr {
+            command.arg("--server-addr").arg(server_addr.to_string());
+        }
+        workers.push(command.spawn().expect("failed to start a loadtest worker"));
+    }
+    for mut worker in workers {
+        if let Err(e) = worker.wait() {
+            eprintln!("loadtest worker failed: {e}");
+        }
+    }
+}
+
+/// Connect to the port of the server that uses the requested transport
+fn configure_transport(settings: &mut Settings, transport: LoadTestTransport) {
+    let port = settings
+        .server
+        .transport
+        .iter()
+        .find_map(|t| match (t, transport) {
+            (ServerTransports::Udp { local_port }, LoadTestTransport::Udp) => Some(*local_port),
+            (ServerTransports::WebSocket { local_port }, LoadTestTransport::WebSocket) => {
+                Some(*local_port)
+            }
+            _ => None,
+        })
+        .expect("the server settings don't have this transport");
+    settings.client.server_port = port;
+    settings.client.transport = match transport {
+        LoadTestTransport::Udp => ClientTransports::Udp,
+        LoadTestTransport::WebSocket => ClientTransports::WebSocket,
+    };
+}
+
+/// Set on the client app when it receives its `ConnectEvent`
+#[derive(Resource, Default)]
+struct Connected(Option<Instant>);
+
+fn record_connection(mut events: EventReader<ConnectEvent>, mut connected: ResMut<Connected>) {
+    if events.read().count() > 0 && connected.0.is_none() {
+        connected.0 = Some(Instant::now());
+    }
+}
+
+struct LoadTestClient {
+    app: App,
+    created: Instant,
+    rtt_samples: Vec<Duration>,
+    /// Latest server tick received, with the time it was sampled at
+    tick_samples: Vec<(Instant, u16)>,
+}
+
+impl LoadTestClient {
+    fn new(settings: &Settings, client_id: u64) -> Self {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            InputPlugin,
+            TransformPlugin,
+            HierarchyPlugin,
+            DiagnosticsPlugin,
+        ));
+        let net_config = get_client_net_config(settings, client_id);
+        let client_config = build_client_config(settings, Mode::Separate, net_config);
+        app.add_plugins((
+            client::ClientPlugin::new(client::PluginConfig::new(client_config, protocol())),
+            ExampleClientPlugin {
+                remote_input: settings.client.remote_input,
+                spectator: false,
+                name: settings.client.name.clone(),
+                color: settings.client.color,
+            },
+            SharedPlugin {
+                physics: settings.shared.physics,
+                replication: settings.shared.replication,
+            },
+        ));
+        app.init_resource::<Connected>();
+        app.add_systems(Update, record_connection);
+        app.finish();
+        app.cleanup();
+        Self {
+            app,
+            created: Instant::now(),
+            rtt_samples: Vec::new(),
+            tick_samples: Vec::new(),
+        }
+    }
+
+    fn connected_at(&self) -> Option<Instant> {
+        self.app.world.resource::<Connected>().0
+    }
+
+    /// Press the keys of the WASD box; the leafwing plugin turns them into `PlayerActions`
+    fn press_keys(&mut self, actions: LoadTestActions, index: usize, elapsed: Duration) {
+        const KEYS: [KeyCode; 4] = [KeyCode::KeyW, KeyCode::KeyD, KeyCode::KeyS, KeyCode::KeyA];
+        let mut keys = self.app.world.resource_mut::<ButtonInput<KeyCode>>();
+        match actions {
+            LoadTestActions::Scripted => {
+                let step = (elapsed.as_millis() / SCRIPT_STEP.as_millis()) as usize;
+                let pressed = KEYS[(step + index) % KEYS.len()];
+                for key in KEYS {
+                    if key == pressed {
+                        keys.press(key);
+                    } else {
+                        keys.release(key);
+                    }
+                }
+            }
+            LoadTestActions::Random => {
+                // only change the keys every step, like a (very bad) human player would
+                let frames_per_step = (RANDOM_STEP.as_nanos() / FRAME_DURATION.as_nanos()) as usize;
+                if rand::random::<usize>() % frames_per_step != 0 {
+                    return;
+                }
+                for key in KEYS {
+                    if rand::random::<bool>() {
+                        keys.press(key);
+                    } else {
+                        keys.release(key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn sample(&mut self, now: Instant) {
+        if self.connected_at().is_none() {
+            return;
+        }
+        // measured with the pings of the `LatencyPlugin`, zero until the first pong
+        let rtt = self.app.world.resource::<Latency>().rtt();
+        if rtt > Duration::ZERO {
+            self.rtt_samples.push(rtt);
+        }
+        let connection = self.app.world.resource::<ClientConnectionManager>();
+        let tick = connection.latest_received_server_tick();
+        self.tick_samples.push((now, tick.0));
+    }
+
+    /// Average bandwidth (in, out) in kB/s, as measured by the io diagnostics
+    fn bandwidth(&self) -> (f64, f64) {
+        let store = self.app.world.resource::<DiagnosticsStore>();
+        let average = |path| {
+            store
+                .get(path)
+                .and_then(|diagnostic| diagnostic.average())
+                .unwrap_or(0.0)
+        };
+        (
+            average(&IoDiagnosticsPlugin::BYTES_IN),
+            average(&IoDiagnosticsPlugin::BYTES_OUT),
+        )
+    }
+
+    /// Server ticks received per second between two samples
+    fn tick_rates(&self) -> impl Iterator<Item = f64> + '_ {
+        self.tick_samples.windows(2).map(|pair| {
+            let (t0, tick0) = pair[0];
+            let (t1, tick1) = pair[1];
+            tick1.wrapping_sub(tick0) as f64 / (t1 - t0).as_secs_f64()
+        })
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn report(clients: &[LoadTestClient], elapsed: Duration) {
+    let connected: Vec<&LoadTestClient> = clients
+        .iter()
+        .filter(|client| client.connected_at().is_some())
+        .collect();
+    let mut connect_times: Vec<Duration> = connected
+        .iter()
+        .map(|client| client.connected_at().unwrap() - client.created)
+        .collect();
+    connect_times.sort();
+    println!(
+        "connected: {}/{} (connect time p50 {:?}, max {:?})",
+        connected.len(),
+        clients.len(),
+        percentile(&connect_times, 0.5),
+        connect_times.last().copied().unwrap_or_default(),
+    );
+
+    let mut rtts: Vec<Duration> = connected
+        .iter()
+        .flat_map(|client| client.rtt_samples.iter().copied())
+        .collect();
+    rtts.sort();
+    println!(
+        "RTT: min {:?} | p50 {:?} | p90 {:?} | p99 {:?} | max {:?} ({} samples)",
+        rtts.first().copied().unwrap_or_default(),
+        percentile(&rtts, 0.5),
+        percentile(&rtts, 0.9),
+        percentile(&rtts, 0.99),
+        rtts.last().copied().unwrap_or_default(),
+        rtts.len(),
+    );
+
+    let tick_rates: Vec<f64> = connected
+        .iter()
+        .flat_map(|client| client.tick_rates())
+        .collect();
+    if !tick_rates.is_empty() {
+        let mean = tick_rates.iter().sum::<f64>() / tick_rates.len() as f64;
+        let variance =
+            tick_rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / tick_rates.len() as f64;
+        println!(
+            "server ticks: {mean:.1}/s (expected {FIXED_TIMESTEP_HZ}), std dev {:.2}",
+            variance.sqrt()
+        );
+    }
+
+    if !connected.is_empty() {
+        let (total_in, total_out) = connected
+            .iter()
+            .map(|client| client.bandwidth())
+            .fold((0.0, 0.0), |(a, b), (i, o)| (a + i, b + o));
+        let n = connected.len() as f64;
+        println!(
+            "bandwidth per client: in {:.2} kB/s | out {:.2} kB/s",
+            total_in / n,
+            total_out / n
+        );
+    }
+    println!("ran for {:.1}s", elapsed.as_secs_f64());
+}
@@ -0,0 +1,225 @@
+//! Lobby of the server, where the players get ready before each match.
+//!
+//! The lobby lives on its own replicated entity (`Lobby`). It lists the players with a slot, and opens
+//! when the server starts and at the end of every match; meanwhile the players can move in the arena,
+//! but nothing is counted (see `stats`). The clients send a `LobbyRequest` to get ready, and the host
+//! to change the options of the next match; the server only accepts the durations of [`DURATIONS`].
+//! The match starts when every player is ready, or when the countdown runs out; the countdown only runs
+//! while there are players in the lobby.
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+
+use super::bot::Bot;
+use super::protocol::*;
+use super::shared::color_from_id;
+use super::slots::PlayerSlots;
+use super::stats::{MatchFinished, MatchSet, StartMatch};
+
+/// Match durations that the host can pick, in seconds; `None` is an endless match
+pub const DURATIONS: [Option<u64>; 5] = [Some(120), Some(180), Some(300), Some(600), None];
+
+pub struct LobbyPlugin {
+    /// How long the players have to get ready
+    pub duration: Duration,
+    /// Options of the first match
+    pub options: MatchOptions,
+}
+
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LobbyConfig(self.options));
+        app.insert_resource(LobbyTimer(Timer::new(self.duration, TimerMode::Once)));
+        app.add_systems(Startup, spawn_lobby);
+        app.add_systems(
+            Update,
+            (
+                reopen_lobby,
+                update_lobby_players,
+                handle_lobby_requests,
+                start_when_ready,
+            )
+                .chain()
+                .after(MatchSet::End)
+                .before(MatchSet::Start),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct LobbyConfig(MatchOptions);
+
+#[derive(Resource)]
+struct LobbyEntity(Entity);
+
+/// Countdown before the match starts anyway
+#[derive(Resource)]
+struct LobbyTimer(Timer);
+
+fn spawn_lobby(mut commands: Commands, config: Res<LobbyConfig>, timer: Res<LobbyTimer>) {
+    let entity = commands
+        .spawn((
+            Lobby {
+                open: true,
+                players: Vec::new(),
+                host: None,
+                options: config.0,
+                seconds_left: timer.0.duration().as_secs() as u32,
+            },
+            Replicate {
+                replication_target: NetworkTarget::All,
+                replication_mode: ReplicationMode::Room,
+                ..default()
+            },
+        ))
+        .id();
+    commands.insert_resource(LobbyEntity(entity));
+}
+
+fn is_valid_duration(duration: Option<Duration>) -> bool {
+    DURATIONS
+        .iter()
+        .any(|secs| secs.map(Duration::from_secs) == duration)
+}
+
+/// The local client of a host-server hosts the lobby; on a dedicated server, the player that joined first
+fn host(players: &[ClientId]) -> Option<ClientId> {
+    players
+        .iter()
+        .find(|client_id| matches!(client_id, ClientId::Local(_)))
+        .or(players.first())
+        .copied()
+}
+
+fn reopen_lobby(
+    mut finished: EventReader<MatchFinished>,
+    entity: Res<LobbyEntity>,
+    mut timer: ResMut<LobbyTimer>,
+    mut lobbies: Query<&mut Lobby>,
+) {
+    if finished.read().count() == 0 {
+        return;
+    }
+    let Ok(mut lobby) = lobbies.get_mut(entity.0) else {
+        return;
+    };
+    timer.0.reset();
+    lobby.open = true;
+    lobby.seconds_left = timer.0.duration().as_secs() as u32;
+    for player in lobby.players.iter_mut() {
+        player.ready = false;
+    }
+    info!("lobby opened");
+}
+
+/// Follow the players that get or free a slot; the others keep their ready status
+fn update_lobby_players(
+    slots: Res<PlayerSlots>,
+    entity: Res<LobbyEntity>,
+    mut lobbies: Query<&mut Lobby>,
+) {
+    let Ok(mut lobby) = lobbies.get_mut(entity.0) else {
+        return;
+    };
+    let players: Vec<LobbyPlayer> = slots
+        .players()
+        .iter()
+        .map(|client_id| LobbyPlayer {
+            player: *client_id,
+            color: slots
+                .color(*client_id)
+                .unwrap_or_else(|| color_from_id(*client_id)),
+            ready: lobby
+                .players
+                .iter()
+                .any(|player| player.player == *client_id && player.ready),
+        })
+        .collect();
+    let host = host(slots.players());
+    // only touch the component if something changed, so that it isn't replicated every frame
+    if lobby.players != players {
+        lobby.players = players;
+    }
+    if lobby.host != host {
+        lobby.host = host;
+    }
+}
+
+fn handle_lobby_requests(
+    mut requests: EventReader<MessageEvent<LobbyRequest>>,
+    entity: Res<LobbyEntity>,
+    mut lobbies: Query<&mut Lobby>,
+) {
+    let Ok(mut lobby) = lobbies.get_mut(entity.0) else {
+        return;
+    };
+    for request in requests.read() {
+        let client_id = *request.context();
+        if !lobby.open {
+            continue;
+        }
+        match request.message() {
+            LobbyRequest::Ready(ready) => {
+                if let Some(player) = lobby
+                    .players
+                    .iter_mut()
+                    .find(|player| player.player == client_id)
+                {
+                    player.ready = *ready;
+                }
+            }
+            LobbyRequest::Options(options) => {
+                if lobby.host != Some(client_id) {
+                    warn!(?client_id, "only the host can change the match options");
+                    continue;
+                }
+                if !is_valid_duration(options.duration) {
+                    warn!(?client_id, ?options, "invalid match duration");
+                    continue;
+                }
+                lobby.options = *options;
+            }
+        }
+    }
+}
+
+fn start_when_ready(
+    time: Res<Time>,
+    entity: Res<LobbyEntity>,
+    mut timer: ResMut<LobbyTimer>,
+    mut start: EventWriter<StartMatch>,
+    mut lobbies: Query<&mut Lobby>,
+    mut bots: Query<&mut Bot>,
+) {
+    let Ok(mut lobby) = lobbies.get_mut(entity.0) else {
+        return;
+    };
+    if !lobby.open {
+        return;
+    }
+    if lobby.players.is_empty() {
+        timer.0.reset();
+    } else {
+        timer.0.tick(time.delta());
+    }
+    let seconds_left = timer.0.remaining().as_secs_f32().ceil() as u32;
+    if lobby.seconds_left != seconds_left {
+        lobby.seconds_left = seconds_left;
+    }
+    let all_ready = !lobby.players.is_empty() && lobby.players.iter().all(|player| player.ready);
+    if !all_ready && !timer.0.finished() {
+        return;
+    }
+
+    let options = lobby.options;
+    lobby.open = false;
+    // the bots are only spawned when the server starts, and stay for every match
+    for mut bot in bots.iter_mut() {
+        bot.difficulty = options.bot_difficulty;
+    }
+    start.send(StartMatch {
+        duration: options.duration,
+    });
+    info!(?options, all_ready, "lobby closed");
+}
@@ -0,0 +1,350 @@
+//! Lobby screen of the client, shown while the lobby of the server is open (see `lobby`).
+//!
+//! This is the matchmaking step of the game: it lists the players of the next match with their color
+//! and whether they are ready, the options of the match and the countdown before it starts anyway.
+//! Get ready with the READY button or R; only the host can change the options.
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::utils::{Duration, HashMap};
+use lightyear::prelude::client::*;
+use lightyear::prelude::ClientId;
+
+use super::lobby::DURATIONS;
+use super::protocol::*;
+use super::render::player_label;
+use super::settings::BotDifficulty;
+use super::ui::spawn_cell;
+use crate::menu::{BACKGROUND, BACKGROUND_DIM, FOREGROUND, FOREGROUND_DIM};
+
+const NAME_WIDTH: f32 = 180.0;
+const STATUS_WIDTH: f32 = 120.0;
+const BUTTON_WIDTH: f32 = 300.0;
+const FONT_SIZE: f32 = 20.0;
+
+pub struct LobbyScreenPlugin;
+
+impl Plugin for LobbyScreenPlugin {
+    fn build(&self, app: &mut App) {
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, spawn_lobby_screen);
+            app.add_systems(
+                Update,
+                (lobby_buttons, lobby_button_style, update_lobby_screen),
+            );
+        }
+    }
+}
+
+/// Send a request to the lobby of the server; it answers by updating the replicated `Lobby`
+pub fn send_lobby_request(connection: &mut ClientConnectionManager, request: LobbyRequest) {
+    if let Err(e) = connection.send_message::<Channel1, LobbyRequest>(request) {
+        error!(?e, "failed to send the lobby request");
+    }
+}
+
+/// The duration after this one, in the order of `DURATIONS`
+fn next_duration(duration: Option<Duration>) -> Option<Duration> {
+    let secs = duration.map(|duration| duration.as_secs());
+    let index = DURATIONS
+        .iter()
+        .position(|option| *option == secs)
+        .map_or(0, |index| (index + 1) % DURATIONS.len());
+    DURATIONS[index].map(Duration::from_secs)
+}
+
+fn next_difficulty(difficulty: BotDifficulty) -> BotDifficulty {
+    match difficulty {
+        BotDifficulty::Easy => BotDifficulty::Medium,
+        BotDifficulty::Medium => BotDifficulty::Hard,
+        BotDifficulty::Hard => BotDifficulty::Easy,
+    }
+}
+
+fn format_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => {
+            let seconds = duration.as_secs();
+            format!("{}:{:02}", seconds / 60, seconds % 60)
+        }
+        None => "ENDLESS".to_string(),
+    }
+}
+
+#[derive(Component)]
+struct LobbyScreen;
+
+#[derive(Component)]
+struct LobbyCountdown;
+
+/// Parent of one row per player
+#[derive(Component)]
+struct LobbyRows;
+
+#[derive(Clone, Copy)]
+enum LobbyAction {
+    Ready,
+    Duration,
+    Difficulty,
+}
+
+#[derive(Component)]
+struct LobbyButton(LobbyAction);
+
+/// Text of the button with the same action
+#[derive(Component)]
+struct LobbyButtonText(LobbyAction);
+
+fn spawn_lobby_screen(mut commands: Commands) {
+    commands
+        .spawn((
+            LobbyScreen,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(16.0),
+                    left: Val::Px(16.0),
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(8.0),
+                    padding: UiRect::all(Val::Px(12.0)),
+                    ..default()
+                },
+                background_color: BACKGROUND.into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                "LOBBY",
+                TextStyle {
+                    font_size: 32.0,
+                    color: FOREGROUND,
+                    ..default()
+                },
+            ));
+            screen.spawn((
+                LobbyCountdown,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 18.0,
+                        color: FOREGROUND_DIM,
+                        ..default()
+                    },
+                ),
+            ));
+            screen.spawn((
+                LobbyRows,
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        row_gap: Val::Px(4.0),
+                        ..default()
+                    },
+                    ..default()
+                },
+            ));
+            for action in [
+                LobbyAction::Ready,
+                LobbyAction::Duration,
+                LobbyAction::Difficulty,
+            ] {
+                screen
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(BUTTON_WIDTH),
+                                height: Val::Px(32.0),
+                                justify_content: JustifyContent::Center,
+                                align_items: AlignItems::Center,
+                                ..default()
+                            },
+                            background_color: BACKGROUND.into(),
+                            ..default()
+                        },
+                        LobbyButton(action),
+                    ))
+                    .with_children(|button| {
+                        button.spawn((
+                            LobbyButtonText(action),
+                            TextBundle::from_section(
+                                "",
+                                TextStyle {
+                                    font_size: 20.0,
+                                    color: FOREGROUND,
+                                    ..default()
+                                },
+                            ),
+                        ));
+                    });
+            }
+        });
+}
+
+/// Ask the server to change the ready status or the options, from the buttons or the keyboard
+fn lobby_buttons(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut connection: ResMut<ClientConnectionManager>,
+    client: Res<ClientConnection>,
+    lobbies: Query<&Lobby>,
+    buttons: Query<(&Interaction, &LobbyButton), Changed<Interaction>>,
+) {
+    let Some(lobby) = lobbies.iter().find(|lobby| lobby.open) else {
+        return;
+    };
+    let own_id = client.id();
+    let mut actions: Vec<LobbyAction> = buttons
+        .iter()
+        .filter(|(interaction, _)| **interaction == Interaction::Pressed)
+        .map(|(_, button)| button.0)
+        .collect();
+    if keys.just_pressed(KeyCode::KeyR) {
+        actions.push(LobbyAction::Ready);
+    }
+    for action in actions {
+        let request = match action {
+            LobbyAction::Ready => {
+                let Some(player) = lobby.players.iter().find(|player| player.player == own_id)
+                else {
+                    continue;
+                };
+                LobbyRequest::Ready(!player.ready)
+            }
+            // the server ignores the other players, but there is no need to ask
+            _ if lobby.host != Some(own_id) => continue,
+            LobbyAction::Duration => LobbyRequest::Options(MatchOptions {
+                duration: next_duration(lobby.options.duration),
+                ..lobby.options
+            }),
+            LobbyAction::Difficulty => LobbyRequest::Options(MatchOptions {
+                bot_difficulty: next_difficulty(lobby.options.bot_difficulty),
+                ..lobby.options
+            }),
+        };
+        send_lobby_request(&mut connection, request);
+    }
+}
+
+fn lobby_button_style(
+    mut buttons: Query<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<LobbyButton>),
+    >,
+) {
+    for (interaction, mut background_color) in buttons.iter_mut() {
+        *background_color = match interaction {
+            Interaction::Pressed => BACKGROUND_DIM.into(),
+            Interaction::Hovered => FOREGROUND_DIM.into(),
+            Interaction::None => BACKGROUND.into(),
+        };
+    }
+}
+
+/// Show the screen while the lobby is open, with the players in the order they joined
+fn update_lobby_screen(
+    mut commands: Commands,
+    client: Res<ClientConnection>,
+    lobbies: Query<Ref<Lobby>>,
+    players: Query<
+        (&PlayerId, Option<&PlayerName>, Has<BotMarker>),
+        (Without<Predicted>, Without<Interpolated>),
+    >,
+    new_names: Query<(), Added<PlayerName>>,
+    mut screens: Query<&mut Visibility, With<LobbyScreen>>,
+    mut countdown: Query<&mut Text, With<LobbyCountdown>>,
+    mut button_texts: Query<(&mut Text, &LobbyButtonText), Without<LobbyCountdown>>,
+    rows: Query<Entity, With<LobbyRows>>,
+) {
+    let Some(lobby) = lobbies.iter().next() else {
+        return;
+    };
+    if !lobby.is_changed() && new_names.is_empty() {
+        return;
+    }
+    for mut visibility in screens.iter_mut() {
+        *visibility = if lobby.open {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+    if !lobby.open {
+        return;
+    }
+
+    let own_id = client.id();
+    let is_host = lobby.host == Some(own_id);
+    for mut text in countdown.iter_mut() {
+        text.sections[0].value = if lobby.players.is_empty() {
+            "WAITING FOR PLAYERS".to_string()
+        } else {
+            format!("STARTING IN {}s", lobby.seconds_left)
+        };
+    }
+    let own_ready = lobby
+        .players
+        .iter()
+        .any(|player| player.player == own_id && player.ready);
+    for (mut text, button) in button_texts.iter_mut() {
+        let (value, enabled) = match button.0 {
+            LobbyAction::Ready if own_ready => ("[R] NOT READY".to_string(), true),
+            LobbyAction::Ready => ("[R] READY".to_string(), true),
+            LobbyAction::Duration => (
+                format!("DURATION {}", format_duration(lobby.options.duration)),
+                is_host,
+            ),
+            LobbyAction::Difficulty => (
+                format!("BOTS {:?}", lobby.options.bot_difficulty).to_uppercase(),
+                is_host,
+            ),
+        };
+        text.sections[0].value = value;
+        // the options are greyed out for the players that can't change them
+        text.sections[0].style.color = if enabled { FOREGROUND } else { FOREGROUND_DIM };
+    }
+
+    let labels: HashMap<ClientId, String> = players
+        .iter()
+        .map(|(player_id, name, is_bot)| (player_id.0, player_label(player_id, name, is_bot)))
+        .collect();
+    for rows in rows.iter() {
+        commands.entity(rows).despawn_descendants();
+        commands.entity(rows).with_children(|rows| {
+            for player in &lobby.players {
+                let mut label = labels
+                    .get(&player.player)
+                    .cloned()
+                    .unwrap_or_else(|| player.player.to_string());
+                if lobby.host == Some(player.player) {
+                    label.push_str(" (HOST)");
+                }
+                let (status, status_color) = if player.ready {
+                    ("READY", FOREGROUND)
+                } else {
+                    ("NOT READY", FOREGROUND_DIM)
+                };
+                rows.spawn(NodeBundle {
+                    style: Style {
+                        column_gap: Val::Px(8.0),
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|row| {
+                    row.spawn(NodeBundle {
+                        style: Style {
+                            width: Val::Px(20.0),
+                            height: Val::Px(20.0),
+                            ..default()
+                        },
+                        background_color: player.color.into(),
+                        ..default()
+                    });
+                    spawn_cell(row, &label, NAME_WIDTH, FONT_SIZE, FOREGROUND);
+                    spawn_cell(row, status, STATUS_WIDTH, FONT_SIZE, status_color);
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Matchmaking screen of the menu app, shown between the menu and the game.
+//!
+//! It sums up the choices of the menu: how the game is joined (hosted on this machine, joined or
+//! spectated on the server of the settings), and the name and color that the player asks for; the server
+//! has the last word on both. Once connected, the players of the next match gather in the lobby
+//! (see `lobby_screen`). Escape goes back to the menu.
+use bevy::prelude::*;
+use std::net::SocketAddr;
+
+use crate::menu::{FOREGROUND, FOREGROUND_DIM};
+use crate::{ClientTypeState, GameState, PlayerProfile};
+
+pub struct MatchmakingScreenPlugin {
+    /// Address of the server of the settings
+    pub server_addr: SocketAddr,
+}
+
+impl Plugin for MatchmakingScreenPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ServerAddr(self.server_addr));
+        app.add_systems(OnEnter(GameState::Matchmaking), spawn_matchmaking_screen);
+        app.add_systems(
+            Update,
+            (update_matchmaking_screen, leave_matchmaking).run_if(in_state(GameState::Matchmaking)),
+        );
+        app.add_systems(OnExit(GameState::Matchmaking), despawn_matchmaking_screen);
+    }
+}
+
+#[derive(Resource)]
+struct ServerAddr(SocketAddr);
+
+#[derive(Component)]
+struct MatchmakingScreen;
+
+/// How the game is joined, which can be chosen in the same frame as the state
+#[derive(Component)]
+struct MatchmakingMode;
+
+fn spawn_matchmaking_screen(mut commands: Commands, profile: Res<PlayerProfile>) {
+    let text_style = TextStyle {
+        font_size: 24.0,
+        color: FOREGROUND,
+        ..default()
+    };
+    let dim_style = TextStyle {
+        color: FOREGROUND_DIM,
+        ..text_style.clone()
+    };
+    commands
+        .spawn((
+            MatchmakingScreen,
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    row_gap: Val::Px(8.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                "LOOKING FOR A MATCH",
+                TextStyle {
+                    font_size: 48.0,
+                    color: FOREGROUND,
+                    ..default()
+                },
+            ));
+            screen.spawn((
+                MatchmakingMode,
+                TextBundle::from_section("", text_style.clone()),
+            ));
+            // the name and the color that the server will validate
+            screen
+                .spawn(NodeBundle {
+                    style: Style {
+                        column_gap: Val::Px(8.0),
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|row| {
+                    if let Some(color) = profile.color {
+                        row.spawn(NodeBundle {
+                            style: Style {
+                                width: Val::Px(20.0),
+                                height: Val::Px(20.0),
+                                ..default()
+                            },
+                            background_color: color.into(),
+                            ..default()
+                        });
+                    }
+                    let name = if profile.name.is_empty() {
+                        TextBundle::from_section("NAME OF THE SETTINGS", dim_style.clone())
+                    } else {
+                        TextBundle::from_section(profile.name.clone(), text_style.clone())
+                    };
+                    row.spawn(name);
+                });
+            screen.spawn(TextBundle::from_section(
+                "the lobby opens once connected",
+                dim_style.clone(),
+            ));
+            screen.spawn(TextBundle::from_section(
+                "Escape to go back to the menu",
+                TextStyle {
+                    font_size: 16.0,
+                    color: FOREGROUND_DIM,
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn update_matchmaking_screen(
+    client_type: Res<State<ClientTypeState>>,
+    server_addr: Res<ServerAddr>,
+    mut texts: Query<&mut Text, With<MatchmakingMode>>,
+) {
+    let value = match client_type.get() {
+        #[cfg(not(target_family = "wasm"))]
+        ClientTypeState::HostServer { .. } => "HOSTING ON THIS MACHINE".to_string(),
+        ClientTypeState::Client { .. } => format!("JOINING {}", server_addr.0),
+        ClientTypeState::Spectator { .. } => format!("SPECTATING {}", server_addr.0),
+        ClientTypeState::NotInGame => String::new(),
+    };
+    for mut text in texts.iter_mut() {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    }
+}
+
+fn leave_matchmaking(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut next_game_state: ResMut<NextState<GameState>>,
+    mut next_client_type_state: ResMut<NextState<ClientTypeState>>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_game_state.set(GameState::Menu);
+        next_client_type_state.set(ClientTypeState::NotInGame);
+    }
+}
+
+fn despawn_matchmaking_screen(
+    mut commands: Commands,
+    screens: Query<Entity, With<MatchmakingScreen>>,
+) {
+    for entity in screens.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -6,11 +6,13 @@
 //! - `cargo run -- server`
 //! - `cargo run -- client -c 1`
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use bevy::asset::ron;
 use bevy::log::{Level, LogPlugin};
 use bevy::prelude::*;
+use bevy::utils::Duration;
 use bevy::DefaultPlugins;
 // use bevy_inspector_egui::quick::{FilterQueryInspectorPlugin, WorldInspectorPlugin};
 // use clap::{Parser, ValueEnum};
@@ -24,19 +26,56 @@ use lightyear::prelude::{Mode, TransportConfig};
 use lightyear::shared::log::add_log_layer;
 use lightyear::transport::LOCAL_SOCKET;
 
+use crate::audio::AudioVolumes;
 use crate::ClientTypeState;
 
 use self::client::ExampleClientPlugin;
+use self::dev_panel::DevPanelPlugin;
+use self::input_delay::InputDelayPlugin;
+use self::leaderboard::LeaderboardMenuPlugin;
+use self::link::{ConditionedLink, LinkConditioners};
+use self::matchmaking::MatchmakingScreenPlugin;
 use self::protocol::{protocol, MyProtocol, PlayerActions, PlayerId};
+use self::replay::ReplayRecorderPlugin;
+use self::replay_viewer::{ReplayViewerPlugin, DEFAULT_REPLAY_DIR};
 use self::server::ExampleServerPlugin;
 use self::settings::*;
-use self::shared::{shared_config, SharedPlugin};
+use self::shared::{shared_config, SharedPlugin, INTERPOLATION_SEND_INTERVAL_RATIO};
 
+mod bot;
+mod camera;
 mod client;
+pub mod colors;
+mod debug;
+pub mod determinism;
+mod dev_panel;
+mod input_delay;
+mod lag_compensation;
+mod latency;
+mod leaderboard;
+mod link;
+pub mod loadtest;
+mod lobby;
+mod lobby_screen;
+mod matchmaking;
+mod names;
+mod net_stats;
 mod protocol;
+mod rating;
+mod records;
+mod render;
+mod replay;
+mod replay_viewer;
+mod scoreboard;
 mod server;
 mod settings;
 mod shared;
+mod slots;
+mod spectator;
+mod stats;
+#[cfg(test)]
+mod tests;
+mod ui;
 
 // #[derive(Parser, PartialEq, Debug)]
 // enum Cli {
@@ -70,14 +109,31 @@ mod shared;
 //     run(settings, cli);
 // }
 
-fn run(settings: Settings, client_type_state: ClientTypeState) {
+fn run(
+    mut settings: Settings,
+    client_type_state: ClientTypeState,
+    name: Option<String>,
+    color: Option<Color>,
+    volumes: AudioVolumes,
+) {
+    if let Err(e) = settings.shared.replication.validate() {
+        panic!("Invalid replication policy: {e}");
+    }
+    // use the menu-provided name if it exists, otherwise use the settings name
+    if let Some(name) = name.filter(|name| !name.is_empty()) {
+        settings.client.name = name;
+    }
+    // same for the color picked in the menu
+    if color.is_some() {
+        settings.client.color = color;
+    }
     match client_type_state {
         #[cfg(not(target_family = "wasm"))]
         ClientTypeState::HostServer { client_id } => {
             let client_net_config = NetConfig::Local {
                 id: client_id.unwrap_or(settings.client.client_id),
             };
-            let mut app = combined_app(settings, vec![], client_net_config);
+            let mut app = combined_app(settings, vec![], client_net_config, volumes);
             app.run();
         }
         ClientTypeState::Client { client_id } => {
@@ -87,8 +143,14 @@ fn run(settings: Settings, client_type_state: ClientTypeState) {
             );
             // use the cli-provided client id if it exists, otherwise use the settings client id
             let client_id = client_id.unwrap_or(settings.client.client_id);
-            let net_config = get_client_net_config(&settings, client_id);
-            let mut app = client_app(settings, net_config);
+            let (net_config, link) = client_net_config(&settings, client_id);
+            let mut app = client_app(settings, net_config, link, volumes, false);
+            app.run();
+        }
+        ClientTypeState::Spectator { client_id } => {
+            let client_id = client_id.unwrap_or(settings.client.client_id);
+            let (net_config, link) = client_net_config(&settings, client_id);
+            let mut app = client_app(settings, net_config, link, volumes, true);
             app.run();
         }
         ClientTypeState::NotInGame => {
@@ -97,27 +159,68 @@ fn run(settings: Settings, client_type_state: ClientTypeState) {
     }
 }
 
-/// Build the client app
-fn client_app(settings: Settings, net_config: client::NetConfig) -> App {
-    let mut app = App::new();
-    app.add_plugins(DefaultPlugins.build().set(LogPlugin {
-        level: Level::INFO,
-        filter: "wgpu=error,bevy_render=info,bevy_ecs=warn".to_string(),
-        update_subscriber: Some(add_log_layer),
-    }));
-    // if settings.client.inspector {
-    //     app.add_plugins(FilterQueryInspectorPlugin::<With<PlayerId>>::default());
-    // }
-    let client_config = client::ClientConfig {
-        shared: shared_config(Mode::Separate),
+/// The net config of a client. With the UDP transport, the packets go through a `ConditionedLink`,
+/// so that the dev panel can change the link conditioners while the game runs
+fn client_net_config(settings: &Settings, client_id: u64) -> (NetConfig, Option<ConditionedLink>) {
+    #[cfg(not(target_family = "wasm"))]
+    if settings.client.transport == ClientTransports::Udp {
+        let server_addr = SocketAddr::new(
+            settings.client.server_addr.into(),
+            settings.client.server_port,
+        );
+        let client_addr = SocketAddr::new(
+            std::net::Ipv4Addr::UNSPECIFIED.into(),
+            settings.client.client_port,
+        );
+        let conditioners = LinkConditioners {
+            incoming: settings.client.conditioner,
+            outgoing: settings.client.outgoing_conditioner,
+        };
+        match ConditionedLink::start(client_addr, server_addr, conditioners) {
+            Ok((link, transport)) => {
+                // the packets of the in-memory channel come from the local socket for netcode
+                let net_config = build_client_netcode_config(
+                    client_id,
+                    LOCAL_SOCKET,
+                    None,
+                    &settings.shared,
+                    transport,
+                );
+                return (net_config, Some(link));
+            }
+            Err(e) => error!(
+                ?e,
+                "failed to start the conditioned link, using the plain transport"
+            ),
+        }
+    }
+    (get_client_net_config(settings, client_id), None)
+}
+
+/// Build the lightyear config of a client
+fn build_client_config(
+    settings: &Settings,
+    mode: Mode,
+    net_config: client::NetConfig,
+) -> client::ClientConfig {
+    // the local client of a host-server doesn't adapt its delay
+    let input_delay_ticks = match settings.client.adaptive_input_delay {
+        Some(adaptive) if mode == Mode::Separate => {
+            adaptive.clamp(settings.client.input_delay_ticks)
+        }
+        _ => settings.client.input_delay_ticks,
+    };
+    client::ClientConfig {
+        shared: shared_config(mode),
         net: net_config,
         prediction: PredictionConfig {
-            input_delay_ticks: settings.client.input_delay_ticks,
+            input_delay_ticks,
             correction_ticks_factor: settings.client.correction_ticks_factor,
             ..default()
         },
         interpolation: InterpolationConfig {
-            delay: InterpolationDelay::default().with_send_interval_ratio(2.0),
+            delay: InterpolationDelay::default()
+                .with_send_interval_ratio(INTERPOLATION_SEND_INTERVAL_RATIO),
             ..default()
         },
         replication: client::ReplicationConfig {
@@ -126,12 +229,59 @@ fn client_app(settings: Settings, net_config: client::NetConfig) -> App {
             enable_receive: true,
         },
         ..default()
-    };
+    }
+}
+
+/// Build the lightyear config of a server
+fn build_server_config(mode: Mode, net_configs: Vec<server::NetConfig>) -> server::ServerConfig {
+    server::ServerConfig {
+        shared: shared_config(mode),
+        net: net_configs,
+        replication: lightyear::server::replication::ReplicationConfig {
+            enable_send: true,
+            enable_receive: true,
+        },
+        ..default()
+    }
+}
+
+/// Build the client app
+fn client_app(
+    settings: Settings,
+    net_config: client::NetConfig,
+    link: Option<ConditionedLink>,
+    volumes: AudioVolumes,
+    spectator: bool,
+) -> App {
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins.build().set(LogPlugin {
+        level: Level::INFO,
+        filter: "wgpu=error,bevy_render=info,bevy_ecs=warn".to_string(),
+        update_subscriber: Some(add_log_layer),
+    }));
+    // the volumes chosen in the menu, which the audio plugin keeps
+    app.insert_resource(volumes);
+    // if settings.client.inspector {
+    //     app.add_plugins(FilterQueryInspectorPlugin::<With<PlayerId>>::default());
+    // }
+    let client_config = build_client_config(&settings, Mode::Separate, net_config);
     let plugin_config = client::PluginConfig::new(client_config, protocol());
     app.add_plugins((
         client::ClientPlugin::new(plugin_config),
-        ExampleClientPlugin,
-        SharedPlugin,
+        ExampleClientPlugin {
+            remote_input: settings.client.remote_input,
+            spectator,
+            name: settings.client.name.clone(),
+            color: settings.client.color,
+        },
+        DevPanelPlugin { link },
+        InputDelayPlugin {
+            adaptive: settings.client.adaptive_input_delay,
+        },
+        SharedPlugin {
+            physics: settings.shared.physics,
+            replication: settings.shared.replication,
+        },
     ));
     app
 }
@@ -142,6 +292,7 @@ fn combined_app(
     settings: Settings,
     extra_transport_configs: Vec<TransportConfig>,
     client_net_config: client::NetConfig,
+    volumes: AudioVolumes,
 ) -> App {
     let mut app = App::new();
     app.add_plugins(DefaultPlugins.build().set(LogPlugin {
@@ -149,6 +300,8 @@ fn combined_app(
         filter: "wgpu=error,bevy_render=info,bevy_ecs=warn".to_string(),
         update_subscriber: Some(add_log_layer),
     }));
+    // the volumes chosen in the menu, which the audio plugin keeps
+    app.insert_resource(volumes);
     // if settings.client.inspector {
     //     app.add_plugins(FilterQueryInspectorPlugin::<With<PlayerId>>::default());
     // }
@@ -159,49 +312,48 @@ fn combined_app(
         build_server_netcode_config(settings.server.conditioner.as_ref(), &settings.shared, c)
     });
     net_configs.extend(extra_net_configs);
-    let server_config = server::ServerConfig {
-        shared: shared_config(Mode::HostServer),
-        net: net_configs,
-        replication: lightyear::server::replication::ReplicationConfig {
-            enable_send: true,
-            enable_receive: true,
-        },
-        ..default()
-    };
+    let server_config = build_server_config(Mode::HostServer, net_configs);
     app.add_plugins((
         server::ServerPlugin::new(server::PluginConfig::new(server_config, protocol())),
         ExampleServerPlugin {
-            predict_all: settings.server.predict_all,
+            bots: settings.server.bots,
+            max_players: settings.server.max_players,
+            join_queue: settings.server.join_queue,
+            palette: settings.server.palette,
+            match_duration: settings.server.match_duration_secs.map(Duration::from_secs),
+            lobby_duration: settings.server.lobby_duration_secs.map(Duration::from_secs),
+            data_dir: settings.server.data_dir.as_ref().map(PathBuf::from),
         },
     ));
+    if let Some(replay_dir) = &settings.server.replay_dir {
+        app.add_plugins(ReplayRecorderPlugin {
+            dir: replay_dir.into(),
+            settings_hash: settings.shared.simulation_hash(),
+        });
+    }
 
     // client plugin
-    let client_config = client::ClientConfig {
-        shared: shared_config(Mode::HostServer),
-        net: client_net_config,
-        prediction: PredictionConfig {
-            input_delay_ticks: settings.client.input_delay_ticks,
-            correction_ticks_factor: settings.client.correction_ticks_factor,
-            ..default()
-        },
-        interpolation: InterpolationConfig {
-            delay: InterpolationDelay::default().with_send_interval_ratio(2.0),
-            ..default()
-        },
-        replication: client::ReplicationConfig {
-            // enable send because we pre-spawn entities on the client
-            enable_send: true,
-            enable_receive: true,
-        },
-        ..default()
-    };
+    let client_config = build_client_config(&settings, Mode::HostServer, client_net_config);
     let plugin_config = client::PluginConfig::new(client_config, protocol());
     app.add_plugins((
         client::ClientPlugin::new(plugin_config),
-        ExampleClientPlugin,
+        ExampleClientPlugin {
+            remote_input: settings.client.remote_input,
+            spectator: false,
+            name: settings.client.name.clone(),
+            color: settings.client.color,
+        },
+        // the local client has no network delay, so there's nothing to adapt, and no link to
+        // condition (its dev panel is greyed out): the remote clients change their own conditioners
+        // from their dev panel
+        InputDelayPlugin { adaptive: None },
+        DevPanelPlugin { link: None },
     ));
     // shared plugin
-    app.add_plugins(SharedPlugin);
+    app.add_plugins(SharedPlugin {
+        physics: settings.shared.physics,
+        replication: settings.shared.replication,
+    });
     app
 }
 
@@ -209,6 +361,29 @@ pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
+        // replays are played back without any network connection, inside the menu app,
+        // from the directory where the server of the settings records them
+        let settings_str = include_str!("../../assets/settings.ron");
+        let settings = ron::de::from_str::<Settings>(settings_str).unwrap();
+        app.add_plugins(ReplayViewerPlugin {
+            dir: PathBuf::from(
+                settings
+                    .server
+                    .replay_dir
+                    .as_deref()
+                    .unwrap_or(DEFAULT_REPLAY_DIR),
+            ),
+        });
+        // same for the leaderboard, which shows the records of the server hosted on this machine
+        app.add_plugins(LeaderboardMenuPlugin {
+            dir: settings.server.data_dir.as_ref().map(PathBuf::from),
+        });
+        app.add_plugins(MatchmakingScreenPlugin {
+            server_addr: SocketAddr::new(
+                settings.client.server_addr.into(),
+                settings.client.server_port,
+            ),
+        });
         // app.init_state::<GameState>()
         //     .init_state::<ClientTypeState>()
         //     .add_plugins((
@@ -0,0 +1,176 @@
+//! Display names of the players.
+//!
+//! The client sends the name typed in the menu in its `JoinRequest`; the server validates it here before
+//! giving the client a slot, and makes it unique by adding a number.
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of a name, in characters
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Words that can't appear in a name, after undoing the usual letter substitutions
+const BLOCKED_WORDS: [&str; 8] = [
+    "fuck", "shit", "cunt", "bitch", "asshole", "bastard", "wanker", "nazi",
+];
+
+/// Words that contain a blocked word, but are fine
+const ALLOWED_WORDS: [&str; 4] = ["scunthorpe", "shitake", "mishit", "ashitaka"];
+
+/// Characters that separate the words of a name
+const SEPARATORS: [char; 4] = [' ', '-', '_', '.'];
+
+/// Why a name was refused
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    InvalidCharacter(char),
+    NotAllowed,
+}
+
+impl std::fmt::Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "the name is empty"),
+            NameError::TooLong => write!(f, "the name is longer than {MAX_NAME_LEN} characters"),
+            NameError::InvalidCharacter(c) => write!(f, "the name can't contain {c:?}"),
+            NameError::NotAllowed => write!(f, "the name is not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Check a name and normalize its whitespace
+pub fn validate_name(name: &str) -> Result<String, NameError> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(NameError::TooLong);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || SEPARATORS.contains(c)))
+    {
+        return Err(NameError::InvalidCharacter(c));
+    }
+    if is_profane(&name) {
+        return Err(NameError::NotAllowed);
+    }
+    Ok(name)
+}
+
+/// Undo the usual substitutions (`sh1t`, `f.u.c.k`...) and look for the blocked words.
+///
+/// The name is split on every separator, and the blocked words are looked for anywhere in each word; the
+/// runs of single letters are joined back, to catch the spelled out words. The allowed words are removed
+/// before looking, so that the names of places or things that happen to contain a blocked word still pass.
+fn is_profane(name: &str) -> bool {
+    let normalize = |text: &str| -> String {
+        text.chars()
+            .filter_map(|c| match c.to_ascii_lowercase() {
+                '0' => Some('o'),
+                '1' | '!' => Some('i'),
+                '3' => Some('e'),
+                '4' | '@' => Some('a'),
+                '5' | '$' => Some('s'),
+                '7' => Some('t'),
+                c if c.is_alphanumeric() => Some(c),
+                _ => None,
+            })
+            .collect()
+    };
+    let contains_blocked = |word: &str| {
+        let word = ALLOWED_WORDS
+            .iter()
+            .fold(word.to_string(), |word, allowed| word.replace(allowed, " "));
+        BLOCKED_WORDS.iter().any(|blocked| word.contains(blocked))
+    };
+    let words: Vec<String> = name
+        .split(SEPARATORS)
+        .map(normalize)
+        .filter(|word| !word.is_empty())
+        .collect();
+    let spelled_out = words
+        .split(|word| word.chars().count() > 1)
+        .map(|letters| letters.concat());
+    words
+        .iter()
+        .cloned()
+        .chain(spelled_out)
+        .any(|word| contains_blocked(&word))
+}
+
+/// Make the name unique among the `taken` ones (ignoring case), by adding the smallest free number
+pub fn unique_name<'a>(name: &str, taken: impl Iterator<Item = &'a str> + Clone) -> String {
+    let is_taken = |candidate: &str| {
+        taken
+            .clone()
+            .any(|other| other.to_lowercase() == candidate.to_lowercase())
+    };
+    if !is_taken(name) {
+        return name.to_string();
+    }
+    (2..)
+        .map(|n| {
+            let suffix = format!(" {n}");
+            // keep the name within the length limit
+            let base: String = name
+                .chars()
+                .take(MAX_NAME_LEN.saturating_sub(suffix.len()))
+                .collect();
+            format!("{}{suffix}", base.trim_end())
+        })
+        .find(|candidate| !is_taken(candidate))
+        .expect("there is always a free number")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitespace_is_normalized() {
+        assert_eq!(
+            validate_name("  Box   Master "),
+            Ok("Box Master".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_names_are_refused() {
+        assert_eq!(validate_name("   "), Err(NameError::Empty));
+        assert_eq!(
+            validate_name("a name that is way too long"),
+            Err(NameError::TooLong)
+        );
+        assert_eq!(
+            validate_name("<script>"),
+            Err(NameError::InvalidCharacter('<'))
+        );
+    }
+
+    #[test]
+    fn profanity_is_filtered() {
+        assert_eq!(validate_name("shit"), Err(NameError::NotAllowed));
+        assert_eq!(validate_name("Sh1T head"), Err(NameError::NotAllowed));
+        assert_eq!(validate_name("f.u.c.k"), Err(NameError::NotAllowed));
+        assert_eq!(validate_name("my_shit"), Err(NameError::NotAllowed));
+        assert_eq!(validate_name("the-fuck"), Err(NameError::NotAllowed));
+        assert_eq!(validate_name("xfuck"), Err(NameError::NotAllowed));
+        // the allowed words that contain a blocked word are fine, and so are the words around them
+        assert!(validate_name("Scunthorpe").is_ok());
+        assert!(validate_name("Ashitaka_Fan").is_ok());
+        assert!(validate_name("Kit Tenshi Tom").is_ok());
+    }
+
+    #[test]
+    fn names_are_made_unique() {
+        let taken = ["Player", "player 2", "Other"];
+        assert_eq!(unique_name("Newcomer", taken.iter().copied()), "Newcomer");
+        assert_eq!(unique_name("PLAYER", taken.iter().copied()), "PLAYER 3");
+        let long = "Sixteen chars ab";
+        assert_eq!(unique_name(long, std::iter::once(long)), "Sixteen chars 2");
+    }
+}
@@ -0,0 +1,174 @@
+//! On-screen panel with the network statistics of the client.
+//!
+//! Toggle the panel with F3, and switch between the compact and the detailed layout with F4.
+//!
+//! The tick offset, input delay and interpolation delay come from lightyear (its sync manager,
+//! config and the `InterpolateStatus` of the interpolated entities). The other values are
+//! approximations, since lightyear keeps them private: the RTT and jitter are measured with our own
+//! pings (see `latency`), and the packet loss with the heartbeats of the server.
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::client::*;
+use lightyear::prelude::TickManager;
+use std::collections::VecDeque;
+
+use super::debug::RollbackStats;
+use super::latency::Latency;
+use super::protocol::*;
+
+/// Over how much time the packet loss is measured
+const LOSS_WINDOW: Duration = Duration::from_secs(5);
+
+pub struct NetworkStatsPlugin;
+
+impl Plugin for NetworkStatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkStatsPanel>();
+        app.init_resource::<PacketLoss>();
+        app.add_systems(Startup, spawn_panel);
+        app.add_systems(
+            Update,
+            (toggle_panel, receive_heartbeats, update_panel).chain(),
+        );
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanelLayout {
+    /// Only the RTT and the packet loss
+    #[default]
+    Compact,
+    /// Every statistic
+    Detailed,
+}
+
+#[derive(Resource)]
+pub struct NetworkStatsPanel {
+    pub visible: bool,
+    pub layout: PanelLayout,
+}
+
+impl Default for NetworkStatsPanel {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            layout: PanelLayout::default(),
+        }
+    }
+}
+
+/// Packet loss measured from the sequence numbers of the server heartbeats
+#[derive(Resource, Default)]
+pub struct PacketLoss {
+    /// Received sequence numbers, with the time they were received at
+    received: VecDeque<(Duration, u32)>,
+}
+
+impl PacketLoss {
+    /// Ratio of heartbeats that were lost over the last window, between 0.0 and 1.0
+    pub fn ratio(&self) -> f32 {
+        let (Some(min), Some(max)) = (
+            self.received.iter().map(|(_, seq)| *seq).min(),
+            self.received.iter().map(|(_, seq)| *seq).max(),
+        ) else {
+            return 0.0;
+        };
+        let expected = (max - min + 1) as f32;
+        1.0 - (self.received.len() as f32 / expected).min(1.0)
+    }
+}
+
+#[derive(Component)]
+struct NetworkStatsText;
+
+fn spawn_panel(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(40.0),
+            right: Val::Px(8.0),
+            ..default()
+        }),
+        NetworkStatsText,
+    ));
+}
+
+fn toggle_panel(keys: Res<ButtonInput<KeyCode>>, mut panel: ResMut<NetworkStatsPanel>) {
+    if keys.just_pressed(KeyCode::F3) {
+        panel.visible = !panel.visible;
+    }
+    if keys.just_pressed(KeyCode::F4) {
+        panel.layout = match panel.layout {
+            PanelLayout::Compact => PanelLayout::Detailed,
+            PanelLayout::Detailed => PanelLayout::Compact,
+        };
+    }
+}
+
+fn receive_heartbeats(
+    time: Res<Time>,
+    mut heartbeats: EventReader<MessageEvent<Heartbeat>>,
+    mut loss: ResMut<PacketLoss>,
+) {
+    let now = time.elapsed();
+    for event in heartbeats.read() {
+        loss.received.push_back((now, event.message().0));
+    }
+    while loss
+        .received
+        .front()
+        .is_some_and(|(t, _)| now.saturating_sub(*t) > LOSS_WINDOW)
+    {
+        loss.received.pop_front();
+    }
+}
+
+fn update_panel(
+    panel: Res<NetworkStatsPanel>,
+    connection: Res<ClientConnectionManager>,
+    latency: Res<Latency>,
+    config: Res<ClientConfig>,
+    tick_manager: Res<TickManager>,
+    loss: Res<PacketLoss>,
+    rollback_stats: Option<Res<RollbackStats>>,
+    mut text_query: Query<(&mut Text, &mut Visibility), With<NetworkStatsText>>,
+) {
+    let Ok((mut text, mut visibility)) = text_query.get_single_mut() else {
+        return;
+    };
+    if !panel.visible {
+        *visibility = Visibility::Hidden;
+        return;
+    }
+    *visibility = Visibility::Inherited;
+
+    let rtt = latency.rtt().as_millis();
+    let loss = loss.ratio() * 100.0;
+    text.sections[0].value = match panel.layout {
+        PanelLayout::Compact => format!("RTT {rtt}ms | loss {loss:.1}%"),
+        PanelLayout::Detailed => {
+            let jitter = latency.jitter().as_millis();
+            let tick_difference = tick_manager.tick() - connection.latest_received_server_tick();
+            let input_delay = config.prediction.input_delay_ticks;
+            let rollback_depth = rollback_stats.map_or(0, |stats| stats.depth());
+            let interpolation_delay = latency.interpolation_delay().as_millis();
+            format!(
+                "RTT: {rtt}ms\n\
+                 Jitter: {jitter}ms\n\
+                 Packet loss: {loss:.1}%\n\
+                 Tick offset: {tick_difference} ticks\n\
+                 Input delay: {input_delay} ticks\n\
+                 Rollback depth: {rollback_depth} ticks\n\
+                 Interpolation delay: {interpolation_delay}ms"
+            )
+        }
+    };
+}
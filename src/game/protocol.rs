@@ -1,9 +1,14 @@
 use bevy::prelude::*;
+use bevy::utils::Duration;
 use bevy_xpbd_2d::prelude::*;
 use derive_more::{Add, Mul};
 use leafwing_input_manager::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use super::names::NameError;
+use super::settings::{
+    BodyMaterial, BotDifficulty, PhysicsSettings, ReplicationMode, ReplicationPolicy,
+};
 use super::shared::color_from_id;
 use lightyear::client::components::LerpFn;
 use lightyear::prelude::*;
@@ -11,12 +16,104 @@ use lightyear::utils::bevy_xpbd_2d::*;
 
 pub const BALL_SIZE: f32 = 15.0;
 pub const PLAYER_SIZE: f32 = 40.0;
+/// The bots get client ids above this value, so that they never collide with the ids of real clients
+pub const BOT_ID_OFFSET: u64 = 1 << 32;
 
 // For prediction, we want everything entity that is predicted to be part of the same replication group
 // This will make sure that they will be replicated in the same message and that all the entities in the group
 // will always be consistent (= on the same tick)
 pub const REPLICATION_GROUP: ReplicationGroup = ReplicationGroup::new_id(1);
 
+/// Build the `Replicate` component of an entity, depending on how its owner (if any)
+/// and the other clients should see it
+pub fn replicate_with_modes(
+    owner: Option<ClientId>,
+    own: ReplicationMode,
+    others: ReplicationMode,
+) -> Replicate {
+    let target = |mode: ReplicationMode| match owner {
+        None if others == mode => NetworkTarget::All,
+        None => NetworkTarget::None,
+        Some(owner) => match (own == mode, others == mode) {
+            (true, true) => NetworkTarget::All,
+            (true, false) => NetworkTarget::Single(owner),
+            (false, true) => NetworkTarget::AllExceptSingle(owner),
+            (false, false) => NetworkTarget::None,
+        },
+    };
+    let prediction_target = target(ReplicationMode::Predicted);
+    let interpolation_target = target(ReplicationMode::Interpolated);
+    // NOTE (important): all entities that are being predicted need to be part of the same replication-group
+    //  so that all their updates are sent as a single message and are consistent (on the same tick)
+    let replication_group = if matches!(prediction_target, NetworkTarget::None) {
+        ReplicationGroup::default()
+    } else {
+        REPLICATION_GROUP
+    };
+    Replicate {
+        replication_target: NetworkTarget::All,
+        // the clients only receive the entity once they joined the arena room (see `slots`)
+        replication_mode: lightyear::prelude::ReplicationMode::Room,
+        replication_group,
+        prediction_target,
+        interpolation_target,
+        ..default()
+    }
+}
+
+/// Make the spectators interpolate the entity, even if the policy predicts it for the other clients:
+/// they have no inputs, so there is nothing to predict
+pub fn with_spectators(mut replicate: Replicate, spectators: &[ClientId]) -> Replicate {
+    if spectators.is_empty() {
+        return replicate;
+    }
+    replicate.prediction_target = match replicate.prediction_target {
+        NetworkTarget::All => NetworkTarget::AllExcept(spectators.to_vec()),
+        NetworkTarget::AllExceptSingle(id) => NetworkTarget::AllExcept(union(&[id], spectators)),
+        NetworkTarget::AllExcept(ids) => NetworkTarget::AllExcept(union(&ids, spectators)),
+        NetworkTarget::Only(ids) => NetworkTarget::Only(difference(&ids, spectators)),
+        target => target,
+    };
+    replicate.interpolation_target = match replicate.interpolation_target {
+        NetworkTarget::None => NetworkTarget::Only(spectators.to_vec()),
+        NetworkTarget::Single(id) => NetworkTarget::Only(union(&[id], spectators)),
+        NetworkTarget::Only(ids) => NetworkTarget::Only(union(&ids, spectators)),
+        NetworkTarget::AllExcept(ids) => NetworkTarget::AllExcept(difference(&ids, spectators)),
+        target => target,
+    };
+    replicate
+}
+
+fn union(a: &[ClientId], b: &[ClientId]) -> Vec<ClientId> {
+    let mut ids = a.to_vec();
+    ids.extend(b.iter().filter(|id| !a.contains(id)));
+    ids
+}
+
+fn difference(a: &[ClientId], b: &[ClientId]) -> Vec<ClientId> {
+    a.iter().filter(|id| !b.contains(id)).copied().collect()
+}
+
+/// `Replicate` component of a player owned by `owner`
+pub fn player_replicate(owner: ClientId, policy: &ReplicationPolicy) -> Replicate {
+    replicate_with_modes(Some(owner), policy.own_player, policy.other_players)
+}
+
+/// `Replicate` component of a bot; every client sees it like the players of the other clients
+pub fn bot_replicate(policy: &ReplicationPolicy) -> Replicate {
+    replicate_with_modes(None, policy.other_players, policy.other_players)
+}
+
+/// `Replicate` component of the ball
+pub fn ball_replicate(policy: &ReplicationPolicy) -> Replicate {
+    replicate_with_modes(None, policy.ball, policy.ball)
+}
+
+/// `Replicate` component of a server-authoritative prop
+pub fn prop_replicate(policy: &ReplicationPolicy) -> Replicate {
+    replicate_with_modes(None, policy.props, policy.props)
+}
+
 // Player
 #[derive(Bundle)]
 pub struct PlayerBundle {
@@ -33,23 +130,22 @@ pub struct PlayerBundle {
 }
 
 impl PlayerBundle {
-    pub fn new(id: ClientId, position: Vec2, input_map: InputMap<PlayerActions>) -> Self {
-        let color = color_from_id(id);
+    pub fn new(
+        id: ClientId,
+        position: Vec2,
+        color: Color,
+        input_map: InputMap<PlayerActions>,
+        physics: &PhysicsSettings,
+        policy: &ReplicationPolicy,
+    ) -> Self {
         Self {
             id: PlayerId(id),
             position: Position(position),
             color: ColorComponent(color),
-            replicate: Replicate {
-                // NOTE (important): all entities that are being predicted need to be part of the same replication-group
-                //  so that all their updates are sent as a single message and are consistent (on the same tick)
-                replication_group: REPLICATION_GROUP,
-                // TODO: improve this! this should depend on the predict_all settings
-                // We still need to specify the interpolation/prediction target for this local entity
-                // in the case where we're running in HostServer mode
-                prediction_target: NetworkTarget::All,
-                ..default()
-            },
-            physics: PhysicsBundle::player(),
+            // We still need to specify the interpolation/prediction target for this local entity
+            // in the case where we're running in HostServer mode
+            replicate: player_replicate(id, policy),
+            physics: PhysicsBundle::player(&physics.player),
             inputs: InputManagerBundle::<PlayerActions> {
                 action_state: ActionState::default(),
                 input_map,
@@ -59,6 +155,53 @@ impl PlayerBundle {
     }
 }
 
+/// Client id of the bot with this index
+pub fn bot_client_id(index: u64) -> ClientId {
+    ClientId::Local(BOT_ID_OFFSET + index)
+}
+
+/// Index of the bot, if the client id belongs to a bot
+pub fn bot_index(client_id: ClientId) -> Option<u64> {
+    match client_id {
+        ClientId::Local(id) if id >= BOT_ID_OFFSET => Some(id - BOT_ID_OFFSET),
+        _ => None,
+    }
+}
+
+// Bot
+/// A player that is controlled by the server instead of a client: it has no input map
+/// (its `ActionState` is written by the AI) and is not pre-predicted
+#[derive(Bundle)]
+pub struct BotBundle {
+    id: PlayerId,
+    position: Position,
+    color: ColorComponent,
+    replicate: Replicate,
+    physics: PhysicsBundle,
+    action_state: ActionState<PlayerActions>,
+    marker: BotMarker,
+}
+
+impl BotBundle {
+    pub fn new(
+        index: u64,
+        position: Vec2,
+        physics: &PhysicsSettings,
+        policy: &ReplicationPolicy,
+    ) -> Self {
+        let id = bot_client_id(index);
+        Self {
+            id: PlayerId(id),
+            position: Position(position),
+            color: ColorComponent(color_from_id(id)),
+            replicate: bot_replicate(policy),
+            physics: PhysicsBundle::player(&physics.player),
+            action_state: ActionState::default(),
+            marker: BotMarker,
+        }
+    }
+}
+
 // Ball
 #[derive(Bundle)]
 pub struct BallBundle {
@@ -70,22 +213,17 @@ pub struct BallBundle {
 }
 
 impl BallBundle {
-    pub fn new(position: Vec2, color: Color, predicted: bool) -> Self {
-        let mut replicate = Replicate {
-            replication_target: NetworkTarget::All,
-            ..default()
-        };
-        if predicted {
-            replicate.prediction_target = NetworkTarget::All;
-            replicate.replication_group = REPLICATION_GROUP;
-        } else {
-            replicate.interpolation_target = NetworkTarget::All;
-        }
+    pub fn new(
+        position: Vec2,
+        color: Color,
+        policy: &ReplicationPolicy,
+        physics: &PhysicsSettings,
+    ) -> Self {
         Self {
             position: Position(position),
             color: ColorComponent(color),
-            replicate,
-            physics: PhysicsBundle::ball(),
+            replicate: ball_replicate(policy),
+            physics: PhysicsBundle::ball(&physics.ball),
             marker: BallMarker,
         }
     }
@@ -96,23 +234,46 @@ pub struct PhysicsBundle {
     pub collider: Collider,
     pub collider_density: ColliderDensity,
     pub rigid_body: RigidBody,
+    pub restitution: Restitution,
+    pub friction: Friction,
+    pub linear_damping: LinearDamping,
+    pub angular_damping: AngularDamping,
 }
 
 impl PhysicsBundle {
-    pub fn ball() -> Self {
+    pub fn new(
+        collider: Collider,
+        density: f32,
+        rigid_body: RigidBody,
+        material: &BodyMaterial,
+    ) -> Self {
         Self {
-            collider: Collider::circle(BALL_SIZE),
-            collider_density: ColliderDensity(0.05),
-            rigid_body: RigidBody::Dynamic,
+            collider,
+            collider_density: ColliderDensity(density),
+            rigid_body,
+            restitution: Restitution::new(material.restitution),
+            friction: Friction::new(material.friction),
+            linear_damping: LinearDamping(material.linear_damping),
+            angular_damping: AngularDamping(material.angular_damping),
         }
     }
 
-    pub fn player() -> Self {
-        Self {
-            collider: Collider::rectangle(PLAYER_SIZE, PLAYER_SIZE),
-            collider_density: ColliderDensity(0.2),
-            rigid_body: RigidBody::Dynamic,
-        }
+    pub fn ball(material: &BodyMaterial) -> Self {
+        Self::new(
+            Collider::circle(BALL_SIZE),
+            0.05,
+            RigidBody::Dynamic,
+            material,
+        )
+    }
+
+    pub fn player(material: &BodyMaterial) -> Self {
+        Self::new(
+            Collider::rectangle(PLAYER_SIZE, PLAYER_SIZE),
+            0.2,
+            RigidBody::Dynamic,
+            material,
+        )
     }
 }
 
@@ -126,6 +287,78 @@ pub struct ColorComponent(pub Color);
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BallMarker;
 
+/// Display name of the player, validated by the server
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerName(pub String);
+
+/// Marks the players that are bots
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BotMarker;
+
+/// The two sides of a match; the side whose players touch the ball the most (per player) wins.
+/// The boxes of a side start in its half of the arena, and their nameplates show the side; there
+/// is no goal, so the ratings measure the ball touches of the sides, not goals
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    pub const ALL: [Side; 2] = [Side::Left, Side::Right];
+
+    /// Sign of the x coordinates of the half of the side
+    pub fn sign(self) -> f32 {
+        match self {
+            Side::Left => -1.0,
+            Side::Right => 1.0,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Side::Left => "LEFT",
+            Side::Right => "RIGHT",
+        }
+    }
+}
+
+/// What a player (both of its boxes) did during the current match, measured by the server.
+/// The stats live on their own entity, so that they outlive the boxes of a player that disconnects
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerStats {
+    pub player: ClientId,
+    pub side: Side,
+    /// Rating of the player when it joined the match
+    pub rating: f32,
+    pub ball_touches: u32,
+    /// Distance travelled by the boxes
+    pub distance: f32,
+    /// Highest speed reached by one of the boxes
+    pub top_speed: f32,
+    /// Seconds during which the player had boxes in the arena
+    pub time_connected: f32,
+    /// Collisions with the boxes of the other players
+    pub player_collisions: u32,
+    pub wall_collisions: u32,
+}
+
+impl PlayerStats {
+    pub fn new(player: ClientId, side: Side, rating: f32) -> Self {
+        Self {
+            player,
+            side,
+            rating,
+            ball_touches: 0,
+            distance: 0.0,
+            top_speed: 0.0,
+            time_connected: 0.0,
+            player_collisions: 0,
+            wall_collisions: 0,
+        }
+    }
+}
+
 #[component_protocol(protocol = "MyProtocol")]
 pub enum Components {
     #[protocol(sync(mode = "once"))]
@@ -134,6 +367,13 @@ pub enum Components {
     ColorComponent(ColorComponent),
     #[protocol(sync(mode = "once"))]
     BallMarker(BallMarker),
+    #[protocol(sync(mode = "once"))]
+    BotMarker(BotMarker),
+    #[protocol(sync(mode = "once"))]
+    PlayerName(PlayerName),
+    // only needed on the Confirmed entity: the stats are neither predicted nor interpolated
+    PlayerStats(PlayerStats),
+    Lobby(Lobby),
     // You need to specify how to do interpolation for the component
     // Normally LinearInterpolation is fine, but it's not possible for xpbd's components
     // as they do not implement Mul<f32> and Add<Self>
@@ -162,19 +402,167 @@ pub enum Components {
     AngularVelocity(AngularVelocity),
 }
 
+/// Options of the next match, that the host can change in the lobby
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MatchOptions {
+    /// If `None`, the match never ends
+    pub duration: Option<Duration>,
+    pub bot_difficulty: BotDifficulty,
+}
+
+/// A player waiting in the lobby
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LobbyPlayer {
+    pub player: ClientId,
+    pub color: Color,
+    pub ready: bool,
+}
+
+/// The lobby where the players get ready for the next match, on its own entity.
+/// Only the server changes it; the clients send a `LobbyRequest` instead
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Lobby {
+    /// `false` while a match is played
+    pub open: bool,
+    /// The players with a slot, in the order they joined
+    pub players: Vec<LobbyPlayer>,
+    /// The player that can change the options
+    pub host: Option<ClientId>,
+    pub options: MatchOptions,
+    /// The match starts after this many seconds, even if some players aren't ready
+    pub seconds_left: u32,
+}
+
 // Channels
 
 #[derive(Channel)]
 pub struct Channel1;
 
+/// Unreliable channel, used to measure the packet loss
+#[derive(Channel)]
+pub struct HeartbeatChannel;
+
 // Messages
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message1(pub usize);
 
+/// Sent by the server at a fixed rate with an increasing sequence number, so that
+/// clients can count how many of them got lost
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Heartbeat(pub u32);
+
+/// Sent by the client at a fixed rate with the time of its clock, in seconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ping(pub f64);
+
+/// Sent back by the server as soon as it receives a `Ping`, with the same time,
+/// so that the client can measure the RTT
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Pong(pub f64);
+
+/// Sent by the client with each `Ping`: the tick of the inputs that it is sending, and the tick of
+/// the interpolated entities that it shows meanwhile, so that the server can rewind the ball to what
+/// the client saw when it pressed the inputs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ViewTick {
+    pub input_tick: Tick,
+    pub interpolation_tick: Tick,
+}
+
+/// Why the server refused a client
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    ServerFull,
+    InvalidName(NameError),
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::ServerFull => write!(f, "server full"),
+            RejectReason::InvalidName(e) => write!(f, "invalid name: {e}"),
+        }
+    }
+}
+
+/// Sent by the client right after it connects: the server only gives it a player slot
+/// (or a place in the join queue) after receiving this
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinRequest {
+    /// Spectators watch the game without playing, so they don't take a player slot
+    pub spectator: bool,
+    /// Display name typed in the menu; the server may change it to make it unique
+    pub name: String,
+    /// Color picked in the menu; the server gives a different one if it is too close to the color
+    /// of another player
+    pub color: Option<Color>,
+}
+
+/// Sent by the server when the client gets a player slot; the client only spawns its boxes after that
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinAccepted {
+    /// Color of the boxes of the player, chosen by the server; `None` for spectators
+    pub color: Option<Color>,
+}
+
+/// Sent by the server when the client waits in the join queue, every time its position changes.
+/// The position starts at 1
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinQueued(pub u32);
+
+/// Sent by the server just before it disconnects the client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinRejected(pub RejectReason);
+
+/// Sent by the server to every client at the end of a match, with the final stats of every player.
+/// The replicated `PlayerStats` are reset when the next match starts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MatchEnded(pub Vec<PlayerStats>);
+
+/// Sent by a client to get the best players of the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardRequest;
+
+/// One player of the leaderboard, with its stats over all the matches on the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub client_id: ClientId,
+    pub name: String,
+    pub rating: f32,
+    pub matches: u32,
+    pub ball_touches: u64,
+    /// Seconds
+    pub time_played: f32,
+}
+
+/// Answer to a `LeaderboardRequest`, best players first
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Leaderboard(pub Vec<LeaderboardEntry>);
+
+/// Sent by a client in the lobby
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LobbyRequest {
+    Ready(bool),
+    /// Only accepted from the host of the lobby
+    Options(MatchOptions),
+}
+
 #[message_protocol(protocol = "MyProtocol")]
 pub enum Messages {
     Message1(Message1),
+    Heartbeat(Heartbeat),
+    Ping(Ping),
+    Pong(Pong),
+    ViewTick(ViewTick),
+    JoinRequest(JoinRequest),
+    JoinAccepted(JoinAccepted),
+    JoinQueued(JoinQueued),
+    JoinRejected(JoinRejected),
+    MatchEnded(MatchEnded),
+    LeaderboardRequest(LeaderboardRequest),
+    Leaderboard(Leaderboard),
+    LobbyRequest(LobbyRequest),
 }
 
 // Inputs
@@ -213,5 +601,9 @@ pub fn protocol() -> MyProtocol {
         mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
         ..default()
     });
+    protocol.add_channel::<HeartbeatChannel>(ChannelSettings {
+        mode: ChannelMode::UnorderedUnreliable,
+        ..default()
+    });
     protocol
 }
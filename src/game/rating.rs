@@ -0,0 +1,207 @@
+//! Elo rating of the players, and balancing of the sides.
+//!
+//! Only the math lives here, without any networking or storage: the server rates each player of a
+//! finished match against the average rating of the other side, and splits the players into sides of
+//! similar strength when a match starts. Comparing averages is fair even when a side has one more player,
+//! because the outcome of a match is decided by the ball touches per player (see `stats`).
+
+/// Rating of a player that never finished a match
+pub const INITIAL_RATING: f32 = 1000.0;
+/// Maximum change of a rating after one match
+const K_FACTOR: f32 = 32.0;
+/// A player rated this much higher than its opponent is expected to win 10 times more often
+const SCALE: f32 = 400.0;
+/// Above this number of players, the sides are balanced greedily instead of trying every split
+const MAX_EXHAUSTIVE_PLAYERS: usize = 12;
+
+/// Result of a match for one side
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win,
+    Draw,
+    Loss,
+}
+
+impl Outcome {
+    /// Compare the points of a side with the points of the other side
+    pub fn from_points(own: u32, other: u32) -> Self {
+        match own.cmp(&other) {
+            std::cmp::Ordering::Greater => Outcome::Win,
+            std::cmp::Ordering::Equal => Outcome::Draw,
+            std::cmp::Ordering::Less => Outcome::Loss,
+        }
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Outcome::Win => Outcome::Loss,
+            Outcome::Draw => Outcome::Draw,
+            Outcome::Loss => Outcome::Win,
+        }
+    }
+
+    fn score(self) -> f32 {
+        match self {
+            Outcome::Win => 1.0,
+            Outcome::Draw => 0.5,
+            Outcome::Loss => 0.0,
+        }
+    }
+}
+
+/// Probability that the player wins against the opponent (a draw counting as half a win)
+pub fn expected_score(rating: f32, opponent: f32) -> f32 {
+    1.0 / (1.0 + 10f32.powf((opponent - rating) / SCALE))
+}
+
+/// Rating of the player after a match against the opponent
+pub fn updated_rating(rating: f32, opponent: f32, outcome: Outcome) -> f32 {
+    rating + K_FACTOR * (outcome.score() - expected_score(rating, opponent))
+}
+
+/// Average rating of a side
+pub fn side_rating(ratings: &[f32]) -> f32 {
+    if ratings.is_empty() {
+        return INITIAL_RATING;
+    }
+    ratings.iter().sum::<f32>() / ratings.len() as f32
+}
+
+/// New ratings of the players of both sides, each player being rated against the average of the other side
+pub fn update_sides(first: &[f32], second: &[f32], first_outcome: Outcome) -> (Vec<f32>, Vec<f32>) {
+    let (first_rating, second_rating) = (side_rating(first), side_rating(second));
+    let first = first
+        .iter()
+        .map(|rating| updated_rating(*rating, second_rating, first_outcome))
+        .collect();
+    let second = second
+        .iter()
+        .map(|rating| updated_rating(*rating, first_rating, first_outcome.opposite()))
+        .collect();
+    (first, second)
+}
+
+/// Split the players into two sides with the same number of players (one more on the second side if
+/// the number is odd) and total ratings as close as possible
+pub fn balance_sides<T: Copy>(players: &[(T, f32)]) -> (Vec<T>, Vec<T>) {
+    let first_size = players.len() / 2;
+    let in_first = if players.len() <= MAX_EXHAUSTIVE_PLAYERS {
+        best_split(players, first_size)
+    } else {
+        greedy_split(players, first_size)
+    };
+    let side = |first: bool| {
+        players
+            .iter()
+            .zip(&in_first)
+            .filter(|(_, in_first)| **in_first == first)
+            .map(|((player, _), _)| *player)
+            .collect()
+    };
+    (side(true), side(false))
+}
+
+/// Try every split, as a bitmask of the players of the first side
+fn best_split<T>(players: &[(T, f32)], first_size: usize) -> Vec<bool> {
+    let total: f32 = players.iter().map(|(_, rating)| rating).sum();
+    let best_mask = (0u32..1 << players.len())
+        .filter(|mask| mask.count_ones() as usize == first_size)
+        .min_by(|a, b| {
+            let gap = |mask: u32| {
+                let first: f32 = players
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, (_, rating))| rating)
+                    .sum();
+                (total - 2.0 * first).abs()
+            };
+            gap(*a).total_cmp(&gap(*b))
+        })
+        .unwrap_or_default();
+    (0..players.len())
+        .map(|i| best_mask & (1 << i) != 0)
+        .collect()
+}
+
+/// From the best player to the worst, each one goes to the weakest side that still has room
+fn greedy_split<T>(players: &[(T, f32)], first_size: usize) -> Vec<bool> {
+    let mut order: Vec<usize> = (0..players.len()).collect();
+    order.sort_by(|a, b| players[*b].1.total_cmp(&players[*a].1));
+    let second_size = players.len() - first_size;
+    let mut in_first = vec![false; players.len()];
+    let (mut first, mut second) = ((0, 0.0), (0, 0.0));
+    for i in order {
+        let rating = players[i].1;
+        let to_first = second.0 == second_size || (first.0 < first_size && first.1 <= second.1);
+        if to_first {
+            in_first[i] = true;
+            first = (first.0 + 1, first.1 + rating);
+        } else {
+            second = (second.0 + 1, second.1 + rating);
+        }
+    }
+    in_first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(ratings: &[(usize, f32)], side: &[usize]) -> f32 {
+        side.iter().map(|i| ratings[*i].1).sum()
+    }
+
+    #[test]
+    fn expected_scores_add_up_to_one() {
+        assert_eq!(expected_score(1000.0, 1000.0), 0.5);
+        let (strong, weak) = (
+            expected_score(1400.0, 1000.0),
+            expected_score(1000.0, 1400.0),
+        );
+        assert!((strong + weak - 1.0).abs() < 1e-6);
+        assert!((strong / weak - 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn upsets_change_the_ratings_more() {
+        assert_eq!(updated_rating(1000.0, 1000.0, Outcome::Win), 1016.0);
+        assert_eq!(updated_rating(1000.0, 1000.0, Outcome::Draw), 1000.0);
+        let underdog_gain = updated_rating(1000.0, 1400.0, Outcome::Win) - 1000.0;
+        let favorite_gain = updated_rating(1400.0, 1000.0, Outcome::Win) - 1400.0;
+        assert!(underdog_gain > favorite_gain);
+        assert!(updated_rating(1400.0, 1000.0, Outcome::Loss) < 1400.0);
+    }
+
+    #[test]
+    fn equal_sides_exchange_points() {
+        let (first, second) = update_sides(&[1100.0, 900.0], &[1200.0, 800.0], Outcome::Win);
+        let gained: f32 = first.iter().sum::<f32>() - 2000.0;
+        let lost: f32 = 2000.0 - second.iter().sum::<f32>();
+        assert!(gained > 0.0);
+        assert!((gained - lost).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sides_are_balanced() {
+        let players = [(0, 1500.0), (1, 1400.0), (2, 1000.0), (3, 900.0)];
+        let (first, second) = balance_sides(&players);
+        assert_eq!(first.len(), 2);
+        assert_eq!(second.len(), 2);
+        assert_eq!(total(&players, &first), total(&players, &second));
+        // with an odd number of players, the second side gets the extra one
+        let (first, second) = balance_sides(&players[..3]);
+        assert_eq!((first.len(), second.len()), (1, 2));
+        assert_eq!(first, vec![0]);
+    }
+
+    #[test]
+    fn many_players_are_balanced_greedily() {
+        let players: Vec<(usize, f32)> = (0..20).map(|i| (i, 800.0 + 25.0 * i as f32)).collect();
+        let (first, second) = balance_sides(&players);
+        assert_eq!(first.len(), 10);
+        assert_eq!(second.len(), 10);
+        let gap = (total(&players, &first) - total(&players, &second)).abs();
+        assert!(gap <= 250.0, "gap of {gap}");
+    }
+}
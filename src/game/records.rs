@@ -0,0 +1,371 @@
+//! Cumulative statistics of the players, kept by the server across matches and restarts.
+//!
+//! At the end of every match, the stats of each player are added to its record, which is identified
+//! by the client id, and its rating is updated from the outcome for its side (see `rating`).
+//! The client ids are chosen by the clients themselves, so the records trust them; the id 0, which every
+//! client that kept the default of the settings uses, is shared and never recorded.
+//! The records are saved as RON in `players.ron` under the data dir of the server,
+//! and the clients can ask for the leaderboard with a `LeaderboardRequest`.
+use bevy::asset::ron;
+use bevy::prelude::*;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::protocol::*;
+use super::rating::{update_sides, INITIAL_RATING};
+use super::slots::PlayerSlots;
+use super::stats::{left_outcome, MatchFinished, MatchSet};
+
+const RECORDS_FILE: &str = "players.ron";
+/// Number of players sent in the leaderboard
+pub const LEADERBOARD_SIZE: usize = 10;
+/// Client id of the settings, which several players may use at the same time
+const DEFAULT_CLIENT_ID: ClientId = ClientId::Netcode(0);
+
+/// Everything a player did on this server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerRecord {
+    pub client_id: ClientId,
+    /// Name of the player in its last match
+    pub name: String,
+    pub matches: u32,
+    pub ball_touches: u64,
+    pub distance: f64,
+    /// Seconds
+    pub time_played: f64,
+    pub player_collisions: u64,
+    pub wall_collisions: u64,
+    pub rating: f32,
+}
+
+impl PlayerRecord {
+    fn new(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            name: client_id.to_string(),
+            matches: 0,
+            ball_touches: 0,
+            distance: 0.0,
+            time_played: 0.0,
+            player_collisions: 0,
+            wall_collisions: 0,
+            rating: INITIAL_RATING,
+        }
+    }
+
+    fn add(&mut self, stats: &PlayerStats) {
+        self.matches += 1;
+        self.ball_touches += stats.ball_touches as u64;
+        self.distance += stats.distance as f64;
+        self.time_played += stats.time_connected as f64;
+        self.player_collisions += stats.player_collisions as u64;
+        self.wall_collisions += stats.wall_collisions as u64;
+    }
+}
+
+/// The records of every player, and the file they are saved to
+#[derive(Resource, Debug, Default)]
+pub struct PlayerRecords {
+    /// If `None`, the records only live in memory
+    path: Option<PathBuf>,
+    records: Vec<PlayerRecord>,
+}
+
+impl PlayerRecords {
+    /// Load the records saved in `dir`; there are none yet if the file doesn't exist
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(RECORDS_FILE);
+        let records = match std::fs::read_to_string(&path) {
+            Ok(content) => ron::de::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            records,
+        })
+    }
+
+    /// Write the records, through a temporary file so that a crash never leaves a truncated file
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let content = ron::ser::to_string_pretty(&self.records, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let temp_path = path.with_extension("ron.tmp");
+        std::fs::write(&temp_path, content)?;
+        std::fs::rename(&temp_path, path)
+    }
+
+    pub fn get(&self, client_id: ClientId) -> Option<&PlayerRecord> {
+        self.records
+            .iter()
+            .find(|record| record.client_id == client_id)
+    }
+
+    /// The record of the client, created if needed
+    pub fn get_or_insert(&mut self, client_id: ClientId) -> &mut PlayerRecord {
+        let index = match self
+            .records
+            .iter()
+            .position(|record| record.client_id == client_id)
+        {
+            Some(index) => index,
+            None => {
+                self.records.push(PlayerRecord::new(client_id));
+                self.records.len() - 1
+            }
+        };
+        &mut self.records[index]
+    }
+
+    /// Add the stats of a finished match, and rate its players if both sides had some.
+    /// The bots and the players with the default client id have no record, but they count in the rating
+    /// of their side
+    pub fn record_match(
+        &mut self,
+        results: &[PlayerStats],
+        name: impl Fn(ClientId) -> Option<String>,
+    ) {
+        let (left, right): (Vec<&PlayerStats>, Vec<&PlayerStats>) =
+            results.iter().partition(|stats| stats.side == Side::Left);
+        let mut new_ratings = Vec::new();
+        if !left.is_empty() && !right.is_empty() {
+            let outcome = left_outcome(results);
+            let ratings = |side: &[&PlayerStats]| -> Vec<f32> {
+                side.iter().map(|stats| stats.rating).collect()
+            };
+            let (left_ratings, right_ratings) =
+                update_sides(&ratings(&left), &ratings(&right), outcome);
+            new_ratings.extend(left.iter().map(|stats| stats.player).zip(left_ratings));
+            new_ratings.extend(right.iter().map(|stats| stats.player).zip(right_ratings));
+        }
+
+        for stats in results {
+            if bot_index(stats.player).is_some() || stats.player == DEFAULT_CLIENT_ID {
+                continue;
+            }
+            let record = self.get_or_insert(stats.player);
+            record.add(stats);
+            // the players that left before the end keep their previous name
+            if let Some(name) = name(stats.player) {
+                record.name = name;
+            }
+            if let Some((_, rating)) = new_ratings.iter().find(|(id, _)| *id == stats.player) {
+                record.rating = *rating;
+            }
+        }
+    }
+
+    /// The best players, by rating
+    pub fn leaderboard(&self, limit: usize) -> Vec<LeaderboardEntry> {
+        let mut records: Vec<&PlayerRecord> = self.records.iter().collect();
+        records.sort_by(|a, b| {
+            b.rating
+                .total_cmp(&a.rating)
+                .then(b.ball_touches.cmp(&a.ball_touches))
+        });
+        records
+            .into_iter()
+            .take(limit)
+            .map(|record| LeaderboardEntry {
+                client_id: record.client_id,
+                name: record.name.clone(),
+                rating: record.rating,
+                matches: record.matches,
+                ball_touches: record.ball_touches,
+                time_played: record.time_played as f32,
+            })
+            .collect()
+    }
+}
+
+pub struct RecordsPlugin {
+    /// Where the records are saved; if `None`, they are lost when the server stops
+    pub dir: Option<PathBuf>,
+}
+
+impl Plugin for RecordsPlugin {
+    fn build(&self, app: &mut App) {
+        let records = match &self.dir {
+            Some(dir) => PlayerRecords::load(dir).unwrap_or_else(|e| {
+                // don't overwrite a file that we couldn't read
+                error!(
+                    ?e,
+                    ?dir,
+                    "failed to load the player records, they won't be saved"
+                );
+                PlayerRecords::default()
+            }),
+            None => PlayerRecords::default(),
+        };
+        app.insert_resource(records);
+        app.add_systems(
+            Update,
+            (
+                // the next match is balanced with the new ratings
+                record_matches.after(MatchSet::End).before(MatchSet::Start),
+                answer_leaderboard_requests,
+            ),
+        );
+    }
+}
+
+fn record_matches(
+    mut matches: EventReader<MatchFinished>,
+    slots: Res<PlayerSlots>,
+    mut records: ResMut<PlayerRecords>,
+) {
+    for finished in matches.read() {
+        records.record_match(&finished.results, |client_id| {
+            slots.name(client_id).map(str::to_string)
+        });
+        if let Err(e) = records.save() {
+            error!(?e, "failed to save the player records");
+        }
+    }
+}
+
+fn answer_leaderboard_requests(
+    mut requests: EventReader<MessageEvent<LeaderboardRequest>>,
+    records: Res<PlayerRecords>,
+    mut connection: ResMut<ServerConnectionManager>,
+) {
+    for request in requests.read() {
+        let client_id = *request.context();
+        let leaderboard = Leaderboard(records.leaderboard(LEADERBOARD_SIZE));
+        if let Err(e) = connection.send_message_to_target::<Channel1, Leaderboard>(
+            leaderboard,
+            NetworkTarget::Single(client_id),
+        ) {
+            error!(?e, ?client_id, "failed to send the leaderboard");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(client_id: ClientId, side: Side, ball_touches: u32) -> PlayerStats {
+        PlayerStats {
+            ball_touches,
+            time_connected: 60.0,
+            ..PlayerStats::new(client_id, side, INITIAL_RATING)
+        }
+    }
+
+    /// An empty directory for the test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bevy_game-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn matches_are_accumulated() {
+        let mut records = PlayerRecords::default();
+        let player = ClientId::Netcode(1);
+        records.record_match(&[stats(player, Side::Left, 3)], |_| {
+            Some("Player".to_string())
+        });
+        records.record_match(
+            &[
+                stats(player, Side::Left, 2),
+                stats(bot_client_id(0), Side::Left, 5),
+            ],
+            |_| None,
+        );
+
+        let record = records.get(player).unwrap();
+        assert_eq!(record.matches, 2);
+        assert_eq!(record.ball_touches, 5);
+        assert_eq!(record.time_played, 120.0);
+        // the name of the last match in which the player was there
+        assert_eq!(record.name, "Player");
+        assert!(records.get(bot_client_id(0)).is_none());
+        // nobody to play against
+        assert_eq!(record.rating, INITIAL_RATING);
+    }
+
+    #[test]
+    fn default_client_id_is_not_recorded() {
+        let mut records = PlayerRecords::default();
+        let player = ClientId::Netcode(1);
+        records.record_match(
+            &[
+                stats(player, Side::Left, 3),
+                stats(DEFAULT_CLIENT_ID, Side::Right, 1),
+            ],
+            |_| None,
+        );
+        assert!(records.get(DEFAULT_CLIENT_ID).is_none());
+        // the player still won against it
+        assert!(records.get(player).unwrap().rating > INITIAL_RATING);
+    }
+
+    #[test]
+    fn winners_gain_rating() {
+        let mut records = PlayerRecords::default();
+        let (winner, loser) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        records.record_match(
+            &[
+                stats(winner, Side::Left, 8),
+                stats(bot_client_id(0), Side::Left, 0),
+                stats(loser, Side::Right, 3),
+            ],
+            |_| None,
+        );
+        assert!(records.get(winner).unwrap().rating > INITIAL_RATING);
+        assert!(records.get(loser).unwrap().rating < INITIAL_RATING);
+    }
+
+    #[test]
+    fn sides_are_compared_per_player() {
+        let mut records = PlayerRecords::default();
+        let (left, right) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        // the right side touched the ball less in total, but more per player
+        records.record_match(
+            &[
+                stats(left, Side::Left, 3),
+                stats(right, Side::Right, 2),
+                stats(bot_client_id(0), Side::Right, 2),
+            ],
+            |_| None,
+        );
+        assert!(records.get(left).unwrap().rating < INITIAL_RATING);
+        assert!(records.get(right).unwrap().rating > INITIAL_RATING);
+    }
+
+    #[test]
+    fn records_survive_a_restart() {
+        let dir = test_dir("restart");
+        let mut records = PlayerRecords::load(&dir).unwrap();
+        assert!(records.leaderboard(LEADERBOARD_SIZE).is_empty());
+        records.record_match(&[stats(ClientId::Netcode(1), Side::Left, 3)], |_| None);
+        records.save().unwrap();
+
+        let reloaded = PlayerRecords::load(&dir).unwrap();
+        assert_eq!(reloaded.records, records.records);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn leaderboard_is_sorted_by_rating() {
+        let mut records = PlayerRecords::default();
+        for id in 1..=3 {
+            records.get_or_insert(ClientId::Netcode(id)).rating = INITIAL_RATING + id as f32;
+        }
+        let leaderboard = records.leaderboard(2);
+        let ids: Vec<ClientId> = leaderboard.iter().map(|entry| entry.client_id).collect();
+        assert_eq!(ids, vec![ClientId::Netcode(3), ClientId::Netcode(2)]);
+    }
+}
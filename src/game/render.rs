@@ -0,0 +1,372 @@
+//! Renders the game elements with 2d meshes.
+//!
+//! Every element that is displayed on screen (i.e. not the `Confirmed` copies) gets a separate visual entity.
+//! We don't put the meshes on the simulated entities directly, because xpbd would then read back
+//! the `Transform` that we write after interpolation and visual correction into the `Position`.
+use bevy::prelude::*;
+use bevy::sprite::{MaterialMesh2dBundle, Mesh2dHandle};
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::*;
+
+use crate::loading::TextureAssets;
+
+use super::protocol::*;
+use super::shared::{draw_elements, Wall};
+
+const WALL_Z: f32 = 0.0;
+const BALL_Z: f32 = 1.0;
+const PLAYER_Z: f32 = 2.0;
+const NAMEPLATE_Z: f32 = 3.0;
+/// Distance between the center of a player and its nameplate
+const NAMEPLATE_OFFSET: f32 = PLAYER_SIZE / 2.0 + 14.0;
+const WALL_THICKNESS: f32 = 4.0;
+const OUTLINE_THICKNESS: f32 = 3.0;
+
+pub struct VisualsPlugin;
+
+impl Plugin for VisualsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebugOverlay>();
+        app.add_systems(
+            Update,
+            (
+                toggle_debug_overlay,
+                (
+                    spawn_player_visuals,
+                    spawn_ball_visuals,
+                    spawn_wall_visuals,
+                    spawn_nameplates,
+                ),
+                (
+                    update_visual_colors,
+                    rename_nameplates,
+                    show_nameplate_sides,
+                ),
+                (despawn_orphan_visuals, despawn_orphan_nameplates),
+            )
+                .chain(),
+        );
+        // sync after interpolation and visual correction are done
+        app.add_systems(
+            PostUpdate,
+            (sync_visuals, sync_nameplates)
+                .after(InterpolationSet::Interpolate)
+                .after(PredictionSet::VisualCorrection)
+                .before(TransformSystem::TransformPropagate),
+        );
+        // the gizmos are only drawn as a debug overlay
+        app.add_systems(
+            PostUpdate,
+            draw_elements
+                .after(InterpolationSet::Interpolate)
+                .after(PredictionSet::VisualCorrection)
+                .run_if(resource_equals(DebugOverlay(true))),
+        );
+    }
+}
+
+/// If true, the physics shapes are drawn with gizmos on top of the meshes
+#[derive(Resource, Default, PartialEq)]
+pub struct DebugOverlay(pub bool);
+
+/// Marks the entity that displays the simulated `target` entity
+#[derive(Component)]
+pub struct Visual {
+    pub target: Entity,
+    material: Handle<ColorMaterial>,
+    outline_material: Option<Handle<ColorMaterial>>,
+}
+
+/// Text above a player; unlike the visual, it doesn't rotate with the player
+#[derive(Component)]
+pub struct Nameplate {
+    pub target: Entity,
+}
+
+/// Label of a player in its nameplate: its name once the server has sent it
+pub fn player_label(player_id: &PlayerId, name: Option<&PlayerName>, is_bot: bool) -> String {
+    if let Some(name) = name {
+        return name.0.clone();
+    }
+    match bot_index(player_id.0) {
+        Some(index) if is_bot => format!("BOT {}", index + 1),
+        _ => format!("{}", player_id.0),
+    }
+}
+
+fn toggle_debug_overlay(keys: Res<ButtonInput<KeyCode>>, mut overlay: ResMut<DebugOverlay>) {
+    if keys.just_pressed(KeyCode::F1) {
+        overlay.0 = !overlay.0;
+    }
+}
+
+/// The outline is a slightly darker version of the fill color
+fn outline_color(color: Color) -> Color {
+    let [h, s, l, a] = color.as_hsla_f32();
+    Color::hsla(h, s, l * 0.5, a)
+}
+
+/// Spawn a visual entity with a filled shape and an outline behind it
+fn spawn_visual(
+    commands: &mut Commands,
+    materials: &mut Assets<ColorMaterial>,
+    target: Entity,
+    mesh: Handle<Mesh>,
+    outline_mesh: Handle<Mesh>,
+    material: ColorMaterial,
+    z: f32,
+) {
+    let outline_material = materials.add(outline_color(material.color));
+    let material = materials.add(material);
+    commands
+        .spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(mesh),
+                material: material.clone(),
+                transform: Transform::from_xyz(0.0, 0.0, z),
+                ..default()
+            },
+            Visual {
+                target,
+                material,
+                outline_material: Some(outline_material.clone()),
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(outline_mesh),
+                material: outline_material,
+                // draw the outline just behind the shape
+                transform: Transform::from_xyz(0.0, 0.0, -0.1),
+                ..default()
+            });
+        });
+}
+
+fn spawn_player_visuals(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    players: Query<(Entity, &ColorComponent), (Added<PlayerId>, Without<Confirmed>)>,
+) {
+    for (entity, color) in players.iter() {
+        spawn_visual(
+            &mut commands,
+            &mut materials,
+            entity,
+            meshes.add(Rectangle::from_size(Vec2::splat(PLAYER_SIZE))),
+            meshes.add(Rectangle::from_size(Vec2::splat(
+                PLAYER_SIZE + 2.0 * OUTLINE_THICKNESS,
+            ))),
+            ColorMaterial::from(color.0),
+            PLAYER_Z,
+        );
+    }
+}
+
+fn spawn_ball_visuals(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    textures: Option<Res<TextureAssets>>,
+    balls: Query<(Entity, &ColorComponent), (Added<BallMarker>, Without<Confirmed>)>,
+) {
+    for (entity, color) in balls.iter() {
+        let material = ColorMaterial {
+            color: color.0,
+            texture: textures.as_ref().map(|textures| textures.bevy.clone()),
+        };
+        spawn_visual(
+            &mut commands,
+            &mut materials,
+            entity,
+            meshes.add(Circle::new(BALL_SIZE)),
+            meshes.add(Circle::new(BALL_SIZE + OUTLINE_THICKNESS)),
+            material,
+            BALL_Z,
+        );
+    }
+}
+
+/// Walls never move, so their transform is set once here
+fn spawn_wall_visuals(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    walls: Query<(Entity, &Wall, &ColorComponent), Added<Wall>>,
+) {
+    for (entity, wall, color) in walls.iter() {
+        let direction = wall.end - wall.start;
+        let center = (wall.start + wall.end) / 2.0;
+        let material = materials.add(ColorMaterial::from(color.0));
+        commands.spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(Rectangle::new(
+                    direction.length() + WALL_THICKNESS,
+                    WALL_THICKNESS,
+                ))),
+                material: material.clone(),
+                transform: Transform::from_xyz(center.x, center.y, WALL_Z)
+                    .with_rotation(Quat::from_rotation_z(direction.y.atan2(direction.x))),
+                ..default()
+            },
+            Visual {
+                target: entity,
+                material,
+                outline_material: None,
+            },
+        ));
+    }
+}
+
+/// The colors of the elements can change after they are spawned (for example the saturation
+/// of predicted and interpolated entities)
+fn update_visual_colors(
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    visuals: Query<&Visual>,
+    colors: Query<Ref<ColorComponent>>,
+) {
+    for visual in visuals.iter() {
+        let Ok(color) = colors.get(visual.target) else {
+            continue;
+        };
+        if !color.is_changed() {
+            continue;
+        }
+        if let Some(material) = materials.get_mut(&visual.material) {
+            material.color = color.0;
+        }
+        if let Some(material) = visual
+            .outline_material
+            .as_ref()
+            .and_then(|handle| materials.get_mut(handle))
+        {
+            material.color = outline_color(color.0);
+        }
+    }
+}
+
+fn despawn_orphan_visuals(
+    mut commands: Commands,
+    visuals: Query<(Entity, &Visual)>,
+    targets: Query<(), Or<(With<PlayerId>, With<BallMarker>, With<Wall>)>>,
+) {
+    for (entity, visual) in visuals.iter() {
+        if targets.get(visual.target).is_err() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn spawn_nameplates(
+    mut commands: Commands,
+    players: Query<
+        (Entity, &PlayerId, Option<&PlayerName>, Has<BotMarker>),
+        (Added<PlayerId>, Without<Confirmed>),
+    >,
+) {
+    for (entity, player_id, name, is_bot) in players.iter() {
+        // the bots are displayed in grey, so that they can't be mistaken for real players
+        let color = if is_bot { Color::GRAY } else { Color::WHITE };
+        let style = TextStyle {
+            font_size: 16.0,
+            color,
+            ..default()
+        };
+        commands.spawn((
+            Text2dBundle {
+                // the second section shows the side of the player, once its stats are replicated
+                text: Text::from_sections([
+                    TextSection::new(player_label(player_id, name, is_bot), style.clone()),
+                    TextSection::new("", style),
+                ]),
+                transform: Transform::from_xyz(0.0, 0.0, NAMEPLATE_Z),
+                ..default()
+            },
+            Nameplate { target: entity },
+        ));
+    }
+}
+
+/// The name is replicated separately from the player, so it can arrive after the nameplate is spawned
+fn rename_nameplates(
+    mut nameplates: Query<(&Nameplate, &mut Text)>,
+    names: Query<&PlayerName, Changed<PlayerName>>,
+) {
+    for (nameplate, mut text) in nameplates.iter_mut() {
+        if let Ok(name) = names.get(nameplate.target) {
+            text.sections[0].value = name.0.clone();
+        }
+    }
+}
+
+/// Color of the side tag of the nameplates
+fn side_color(side: Side) -> Color {
+    match side {
+        Side::Left => Color::rgb(1.0, 0.6, 0.2),
+        Side::Right => Color::rgb(0.3, 0.7, 1.0),
+    }
+}
+
+/// The sides change at every match, with the replicated `PlayerStats`
+fn show_nameplate_sides(
+    mut nameplates: Query<(&Nameplate, &mut Text)>,
+    players: Query<&PlayerId>,
+    stats: Query<&PlayerStats>,
+) {
+    for (nameplate, mut text) in nameplates.iter_mut() {
+        let Ok(player_id) = players.get(nameplate.target) else {
+            continue;
+        };
+        let Some(side) = stats
+            .iter()
+            .find(|stats| stats.player == player_id.0)
+            .map(|stats| stats.side)
+        else {
+            continue;
+        };
+        let value = format!(" {}", side.name());
+        // only touch the text when the side changes, so that it isn't laid out every frame
+        if text.sections[1].value != value {
+            text.sections[1].value = value;
+            text.sections[1].style.color = side_color(side);
+        }
+    }
+}
+
+fn despawn_orphan_nameplates(
+    mut commands: Commands,
+    nameplates: Query<(Entity, &Nameplate)>,
+    targets: Query<(), With<PlayerId>>,
+) {
+    for (entity, nameplate) in nameplates.iter() {
+        if targets.get(nameplate.target).is_err() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn sync_nameplates(mut nameplates: Query<(&Nameplate, &mut Transform)>, targets: Query<&Position>) {
+    for (nameplate, mut transform) in nameplates.iter_mut() {
+        let Ok(position) = targets.get(nameplate.target) else {
+            continue;
+        };
+        transform.translation.x = position.x;
+        transform.translation.y = position.y + NAMEPLATE_OFFSET;
+    }
+}
+
+/// Move the visuals to the (interpolated or visually corrected) position of their target
+fn sync_visuals(
+    mut visuals: Query<(&Visual, &mut Transform)>,
+    targets: Query<(&Position, &Rotation), Without<Wall>>,
+) {
+    for (visual, mut transform) in visuals.iter_mut() {
+        let Ok((position, rotation)) = targets.get(visual.target) else {
+            continue;
+        };
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+        transform.rotation = Quat::from_rotation_z(rotation.as_radians());
+    }
+}
@@ -0,0 +1,670 @@
+//! Recording of matches into replay files.
+//!
+//! The server records, on every tick, the physical state of every replicated entity and the inputs
+//! of every player. The replay is streamed to the file as the match goes, in a compact binary format:
+//!
+//! ```text
+//! magic "PWBR" | version: u16
+//! header: map: str | settings hash: u64 | tick duration (µs): u32
+//! records, until the end of the file:
+//!   0 | player: client id: u64 | name: str | color (sRGBA): 4 × f32
+//!   1 | frame: tick: u16 | entities: u16 × EntityFrame | inputs: u16 × InputFrame
+//! ```
+//! All the numbers are little-endian and the strings are prefixed by their length as a u16.
+//! An entity frame also tells if the server teleported the entity on the tick (see [`Teleport`]), in
+//! which case its state doesn't come from the simulation.
+//! A player record comes before the first frame that references it. It keeps the name and the color
+//! that the server assigned to the player, so that the replay shows them as in the match.
+//! If the server stopped in the middle of a record, the replay ends with the last complete one.
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_xpbd_2d::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::protocol::*;
+use super::render::player_label;
+use super::server::{ServerConfig, Teleport, TeleportSet};
+use super::shared::{color_from_id, MAP_NAME};
+
+const MAGIC: &[u8; 4] = b"PWBR";
+pub const REPLAY_VERSION: u16 = 4;
+
+const PLAYER_RECORD: u8 = 0;
+const FRAME_RECORD: u8 = 1;
+/// The recorder flushes the file every this many frames
+const FLUSH_INTERVAL_FRAMES: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayHeader {
+    pub map: String,
+    /// Hash of the settings that influence the simulation, see [`settings_hash`]
+    pub settings_hash: u64,
+    pub tick_duration: Duration,
+    /// Every client that had a player during the match
+    pub players: Vec<ReplayPlayer>,
+}
+
+/// A client that had a player during the match
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayPlayer {
+    pub client_id: ClientId,
+    /// The name that the server assigned to the player, or the label of a bot
+    pub name: String,
+    /// The color that the server assigned to the player
+    pub color: Color,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityKind {
+    Player,
+    Ball,
+}
+
+/// State of one entity on one tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntityFrame {
+    /// Identifies the entity during the whole replay
+    pub id: u64,
+    pub kind: EntityKind,
+    /// Index of the owning client in [`ReplayHeader::players`], for players
+    pub owner: Option<u16>,
+    pub position: Vec2,
+    /// In radians
+    pub rotation: f32,
+    pub linear_velocity: Vec2,
+    pub angular_velocity: f32,
+    /// The server moved the entity outside of the simulation at the end of this tick
+    pub teleported: bool,
+}
+
+/// Input of one player on one tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputFrame {
+    /// Id of the player entity, as in [`EntityFrame::id`]
+    pub id: u64,
+    /// Bitmask of the pressed actions, see [`pressed_mask`]
+    pub pressed: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplayFrame {
+    pub tick: Tick,
+    pub entities: Vec<EntityFrame>,
+    pub inputs: Vec<InputFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub frames: Vec<ReplayFrame>,
+}
+
+const ACTIONS: [PlayerActions; 4] = [
+    PlayerActions::Up,
+    PlayerActions::Down,
+    PlayerActions::Left,
+    PlayerActions::Right,
+];
+
+/// Encode the pressed actions as a bitmask
+pub fn pressed_mask(action_state: &ActionState<PlayerActions>) -> u8 {
+    ACTIONS
+        .iter()
+        .enumerate()
+        .filter(|(_, action)| action_state.pressed(action))
+        .fold(0, |mask, (i, _)| mask | (1 << i))
+}
+
+/// Decode a bitmask of pressed actions
+pub fn actions_from_mask(mask: u8) -> impl Iterator<Item = PlayerActions> {
+    ACTIONS
+        .into_iter()
+        .enumerate()
+        .filter(move |(i, _)| mask & (1 << i) != 0)
+        .map(|(_, action)| action)
+}
+
+/// FNV-1a hash, which is stable across platforms and compiler versions (unlike the std hasher)
+pub fn settings_hash(serialized_settings: &str) -> u64 {
+    serialized_settings
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+struct Writer<W: Write>(W);
+
+impl<W: Write> Writer<W> {
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.0.write_all(&[v])
+    }
+    fn u16(&mut self, v: u16) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+    fn u64(&mut self, v: u64) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+    fn f32(&mut self, v: f32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+    fn vec2(&mut self, v: Vec2) -> io::Result<()> {
+        self.f32(v.x)?;
+        self.f32(v.y)
+    }
+    fn color(&mut self, v: Color) -> io::Result<()> {
+        v.as_rgba_f32().into_iter().try_for_each(|c| self.f32(c))
+    }
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u16(u16::try_from(len).map_err(|_| invalid_data("too many elements"))?)
+    }
+    fn str(&mut self, v: &str) -> io::Result<()> {
+        self.len(v.len())?;
+        self.0.write_all(v.as_bytes())
+    }
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+    fn vec2(&mut self) -> io::Result<Vec2> {
+        Ok(Vec2::new(self.f32()?, self.f32()?))
+    }
+    fn color(&mut self) -> io::Result<Color> {
+        Ok(Color::rgba(
+            self.f32()?,
+            self.f32()?,
+            self.f32()?,
+            self.f32()?,
+        ))
+    }
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        let mut buf = vec![0; len];
+        self.0.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| invalid_data("invalid string"))
+    }
+}
+
+/// Writes a replay record by record, so that the recording never has to be kept in memory
+pub struct ReplayWriter<W: Write>(Writer<W>);
+
+impl<W: Write> ReplayWriter<W> {
+    /// Write the header, with a player record for each of its players
+    pub fn new(writer: W, header: &ReplayHeader) -> io::Result<Self> {
+        let mut w = Writer(writer);
+        w.0.write_all(MAGIC)?;
+        w.u16(REPLAY_VERSION)?;
+        w.str(&header.map)?;
+        w.u64(header.settings_hash)?;
+        w.u32(header.tick_duration.as_micros() as u32)?;
+        let mut writer = Self(w);
+        for player in &header.players {
+            writer.player(player)?;
+        }
+        Ok(writer)
+    }
+
+    /// Add a player, whose index in the players of the replay is the number of players added before
+    pub fn player(&mut self, player: &ReplayPlayer) -> io::Result<()> {
+        let w = &mut self.0;
+        w.u8(PLAYER_RECORD)?;
+        w.u64(player.client_id.to_bits())?;
+        w.str(&player.name)?;
+        w.color(player.color)
+    }
+
+    pub fn frame(&mut self, frame: &ReplayFrame) -> io::Result<()> {
+        let w = &mut self.0;
+        w.u8(FRAME_RECORD)?;
+        w.u16(frame.tick.0)?;
+        w.len(frame.entities.len())?;
+        for entity in &frame.entities {
+            w.u64(entity.id)?;
+            w.u8(match entity.kind {
+                EntityKind::Player => 0,
+                EntityKind::Ball => 1,
+            })?;
+            // u16::MAX means that the entity has no owner
+            w.u16(entity.owner.unwrap_or(u16::MAX))?;
+            w.vec2(entity.position)?;
+            w.f32(entity.rotation)?;
+            w.vec2(entity.linear_velocity)?;
+            w.f32(entity.angular_velocity)?;
+            w.u8(entity.teleported as u8)?;
+        }
+        w.len(frame.inputs.len())?;
+        for input in &frame.inputs {
+            w.u64(input.id)?;
+            w.u8(input.pressed)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.0 .0.flush()
+    }
+}
+
+impl<R: Read> Reader<R> {
+    fn player(&mut self) -> io::Result<ReplayPlayer> {
+        Ok(ReplayPlayer {
+            client_id: ClientId::from_bits(self.u64()?),
+            name: self.str()?,
+            color: self.color()?,
+        })
+    }
+
+    fn frame(&mut self) -> io::Result<ReplayFrame> {
+        let tick = Tick(self.u16()?);
+        let entities = (0..self.u16()?)
+            .map(|_| {
+                Ok(EntityFrame {
+                    id: self.u64()?,
+                    kind: match self.u8()? {
+                        0 => EntityKind::Player,
+                        1 => EntityKind::Ball,
+                        _ => return Err(invalid_data("unknown entity kind")),
+                    },
+                    owner: Some(self.u16()?).filter(|owner| *owner != u16::MAX),
+                    position: self.vec2()?,
+                    rotation: self.f32()?,
+                    linear_velocity: self.vec2()?,
+                    angular_velocity: self.f32()?,
+                    teleported: self.u8()? != 0,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let inputs = (0..self.u16()?)
+            .map(|_| {
+                Ok(InputFrame {
+                    id: self.u64()?,
+                    pressed: self.u8()?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(ReplayFrame {
+            tick,
+            entities,
+            inputs,
+        })
+    }
+}
+
+fn is_eof(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::UnexpectedEof
+}
+
+impl Replay {
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        let mut w = ReplayWriter::new(writer, &self.header)?;
+        for frame in &self.frames {
+            w.frame(frame)?;
+        }
+        w.flush()
+    }
+
+    pub fn read(reader: impl Read) -> io::Result<Self> {
+        let mut r = Reader(reader);
+        if &r.bytes::<4>()? != MAGIC {
+            return Err(invalid_data("not a replay file"));
+        }
+        let version = r.u16()?;
+        if version != REPLAY_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported replay version {version}, expected {REPLAY_VERSION}"
+            )));
+        }
+
+        let map = r.str()?;
+        let settings_hash = r.u64()?;
+        let tick_duration = Duration::from_micros(r.u32()? as u64);
+
+        let mut players = Vec::new();
+        let mut frames = Vec::new();
+        loop {
+            let tag = match r.u8() {
+                Ok(tag) => tag,
+                Err(e) if is_eof(&e) => break,
+                Err(e) => return Err(e),
+            };
+            match tag {
+                PLAYER_RECORD => match r.player() {
+                    Ok(player) => players.push(player),
+                    Err(e) if is_eof(&e) => break,
+                    Err(e) => return Err(e),
+                },
+                FRAME_RECORD => match r.frame() {
+                    Ok(frame) => frames.push(frame),
+                    Err(e) if is_eof(&e) => break,
+                    Err(e) => return Err(e),
+                },
+                _ => return Err(invalid_data("unknown record")),
+            }
+        }
+        Ok(Self {
+            header: ReplayHeader {
+                map,
+                settings_hash,
+                tick_duration,
+                players,
+            },
+            frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+/// Records the match on the server, streaming it to a new file in `dir`
+pub struct ReplayRecorderPlugin {
+    pub dir: PathBuf,
+    pub settings_hash: u64,
+}
+
+impl Plugin for ReplayRecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplayRecorder {
+            dir: self.dir.clone(),
+            settings_hash: self.settings_hash,
+            players: Vec::new(),
+            writer: None,
+            started: false,
+            frames: 0,
+        });
+        // record the state once the physics step of the tick is done, with the teleported entities
+        app.add_systems(
+            FixedPostUpdate,
+            record_frame
+                .after(TeleportSet::Apply)
+                .before(TeleportSet::Clear),
+        );
+        app.add_systems(Last, flush_replay_on_exit);
+    }
+}
+
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    dir: PathBuf,
+    settings_hash: u64,
+    players: Vec<ReplayPlayer>,
+    /// `None` before the first frame, or if writing the file failed
+    writer: Option<ReplayWriter<BufWriter<File>>>,
+    started: bool,
+    /// Number of frames written
+    frames: u32,
+}
+
+impl ReplayRecorder {
+    /// Create the replay file; it is named after the time when the recording started
+    fn start(&self, tick_duration: Duration) -> io::Result<ReplayWriter<BufWriter<File>>> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self.dir.join(format!("match-{timestamp}.replay"));
+        std::fs::create_dir_all(&self.dir)?;
+        let writer = ReplayWriter::new(
+            BufWriter::new(File::create(&path)?),
+            &ReplayHeader {
+                map: MAP_NAME.to_string(),
+                settings_hash: self.settings_hash,
+                tick_duration,
+                players: Vec::new(),
+            },
+        )?;
+        info!(?path, "recording the match");
+        Ok(writer)
+    }
+
+    /// Index of the client in the player list, adding it to the replay if needed.
+    /// `player` is only called for a new client
+    fn player_index(
+        &mut self,
+        client_id: ClientId,
+        player: impl FnOnce() -> ReplayPlayer,
+    ) -> io::Result<u16> {
+        if let Some(index) = self.players.iter().position(|p| p.client_id == client_id) {
+            return Ok(index as u16);
+        }
+        let player = player();
+        if let Some(writer) = self.writer.as_mut() {
+            writer.player(&player)?;
+        }
+        self.players.push(player);
+        Ok((self.players.len() - 1) as u16)
+    }
+
+    fn write_frame(&mut self, frame: &ReplayFrame) -> io::Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        writer.frame(frame)?;
+        self.frames += 1;
+        if self.frames % FLUSH_INTERVAL_FRAMES == 0 {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Record the server entities (in HostServer mode, the client's `Confirmed` and `Predicted` copies are skipped)
+fn record_frame(
+    tick_manager: Res<TickManager>,
+    config: Res<ServerConfig>,
+    mut recorder: ResMut<ReplayRecorder>,
+    entities: Query<
+        (
+            Entity,
+            &Position,
+            &Rotation,
+            &LinearVelocity,
+            &AngularVelocity,
+            Option<&PlayerId>,
+            Option<&PlayerName>,
+            Option<&ColorComponent>,
+            Has<BotMarker>,
+            Option<&ActionState<PlayerActions>>,
+            Has<Teleport>,
+        ),
+        (
+            With<Replicate>,
+            Or<(With<PlayerId>, With<BallMarker>)>,
+            Without<Confirmed>,
+            Without<Predicted>,
+        ),
+    >,
+) {
+    if !recorder.started {
+        recorder.started = true;
+        match recorder.start(config.shared.tick.tick_duration) {
+            Ok(writer) => recorder.writer = Some(writer),
+            Err(e) => error!(?e, "failed to create the replay file"),
+        }
+    }
+    if recorder.writer.is_none() {
+        return;
+    }
+    let mut frame = ReplayFrame {
+        tick: tick_manager.tick(),
+        ..default()
+    };
+    let mut result = Ok(());
+    for (
+        entity,
+        position,
+        rotation,
+        linear_velocity,
+        angular_velocity,
+        player_id,
+        name,
+        color,
+        is_bot,
+        action_state,
+        teleported,
+    ) in entities.iter()
+    {
+        // the bits include the generation, so an entity that reuses the index of a despawned one
+        // gets another id
+        let id = entity.to_bits();
+        let owner = player_id.map(|player_id| {
+            recorder.player_index(player_id.0, || ReplayPlayer {
+                client_id: player_id.0,
+                name: player_label(player_id, name, is_bot),
+                color: color.map_or_else(|| color_from_id(player_id.0), |color| color.0),
+            })
+        });
+        let owner = match owner {
+            Some(Ok(index)) => Some(index),
+            Some(Err(e)) => {
+                result = Err(e);
+                break;
+            }
+            None => None,
+        };
+        frame.entities.push(EntityFrame {
+            id,
+            kind: if player_id.is_some() {
+                EntityKind::Player
+            } else {
+                EntityKind::Ball
+            },
+            owner,
+            position: position.0,
+            rotation: rotation.as_radians(),
+            linear_velocity: linear_velocity.0,
+            angular_velocity: angular_velocity.0,
+            teleported,
+        });
+        if let Some(action_state) = action_state {
+            frame.inputs.push(InputFrame {
+                id,
+                pressed: pressed_mask(action_state),
+            });
+        }
+    }
+    if let Err(e) = result.and_then(|_| recorder.write_frame(&frame)) {
+        error!(?e, "failed to write the replay, stopping the recording");
+        recorder.writer = None;
+    }
+}
+
+fn flush_replay_on_exit(
+    mut exit_events: EventReader<AppExit>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    if exit_events.read().count() == 0 {
+        return;
+    }
+    let frames = recorder.frames;
+    let Some(writer) = recorder.writer.as_mut() else {
+        return;
+    };
+    if let Err(e) = writer.flush() {
+        error!(?e, "failed to save the replay");
+    } else {
+        info!(frames, "saved the replay");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        let player = EntityFrame {
+            id: Entity::from_raw(3).to_bits(),
+            kind: EntityKind::Player,
+            owner: Some(0),
+            position: Vec2::new(1.0, 2.0),
+            rotation: 0.5,
+            linear_velocity: Vec2::X,
+            angular_velocity: 0.0,
+            teleported: false,
+        };
+        Replay {
+            header: ReplayHeader {
+                map: "arena".to_string(),
+                settings_hash: 42,
+                tick_duration: Duration::from_micros(15625),
+                players: vec![ReplayPlayer {
+                    client_id: ClientId::Netcode(7),
+                    name: "Player".to_string(),
+                    color: Color::rgba(0.2, 0.4, 0.6, 1.0),
+                }],
+            },
+            frames: (0..3)
+                .map(|tick| ReplayFrame {
+                    tick: Tick(tick),
+                    // the round-trip keeps the teleports
+                    entities: vec![EntityFrame {
+                        teleported: tick == 2,
+                        ..player
+                    }],
+                    inputs: vec![InputFrame {
+                        id: player.id,
+                        pressed: 0b0101,
+                    }],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn replay_round_trip() {
+        let replay = replay();
+        let mut bytes = Vec::new();
+        replay.write(&mut bytes).unwrap();
+        assert_eq!(Replay::read(bytes.as_slice()).unwrap(), replay);
+    }
+
+    #[test]
+    fn truncated_replay_keeps_the_complete_frames() {
+        let replay = replay();
+        let mut bytes = Vec::new();
+        replay.write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        let truncated = Replay::read(bytes.as_slice()).unwrap();
+        assert_eq!(truncated.header, replay.header);
+        assert_eq!(truncated.frames, replay.frames[..2]);
+    }
+}
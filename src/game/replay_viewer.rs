@@ -0,0 +1,651 @@
+//! Plays back the replays recorded by the server, without any network connection.
+//!
+//! Entering the replay state lists the replays of the directory, newest first; clicking one plays it.
+//! The replayed entities get the same components as the networked ones (`Position`, `Rotation`,
+//! `PlayerId`, `PlayerName`, `ColorComponent`...) so that they are drawn by the normal draw path.
+//!
+//! Controls: Space to play/pause, Up/Down to change the speed, Left/Right or the timeline to scrub,
+//! C to switch between the free and the follow camera, Tab to follow the next player, Escape to quit.
+//! The free camera moves with WASD and zooms with the mouse wheel.
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy::window::PrimaryWindow;
+use bevy_xpbd_2d::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::menu::{BACKGROUND, BACKGROUND_DIM, FOREGROUND, FOREGROUND_DIM};
+use crate::GameState;
+
+use super::protocol::*;
+use super::replay::{EntityFrame, EntityKind, Replay, ReplayPlayer};
+use super::shared::{draw_elements, Wall, WALL_SIZE};
+
+/// Directory where the viewer looks for replays, if the settings don't record any
+pub const DEFAULT_REPLAY_DIR: &str = "replays";
+/// Only the most recent replays are listed
+const MAX_LISTED_REPLAYS: usize = 12;
+const SPEEDS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+/// How far Left/Right jump in the replay
+const SCRUB_STEP_SECS: f32 = 5.0;
+const FREE_CAMERA_SPEED: f32 = 400.0;
+const ZOOM_STEP: f32 = 0.1;
+const TIMELINE_WIDTH: f32 = 600.0;
+
+pub struct ReplayViewerPlugin {
+    /// Directory of the replays, usually the one where the server records them
+    pub dir: PathBuf,
+}
+
+impl Plugin for ReplayViewerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplayDir(self.dir.clone()));
+        app.add_systems(OnEnter(GameState::Replay), spawn_replay_list);
+        app.add_systems(
+            Update,
+            (
+                replay_list_buttons,
+                replay_list_button_style,
+                leave_replay_list,
+            )
+                .run_if(in_state(GameState::Replay))
+                .run_if(not(resource_exists::<ReplayPlayback>)),
+        );
+        app.add_systems(
+            Update,
+            (
+                playback_controls,
+                scrub_with_timeline,
+                advance_playback,
+                apply_frame,
+                camera_controls,
+                move_camera,
+                update_hud,
+            )
+                .chain()
+                .run_if(in_state(GameState::Replay))
+                .run_if(resource_exists::<ReplayPlayback>),
+        );
+        app.add_systems(
+            PostUpdate,
+            draw_elements.run_if(in_state(GameState::Replay)),
+        );
+        app.add_systems(
+            OnExit(GameState::Replay),
+            (cleanup_replay, despawn_replay_list),
+        );
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayCameraMode {
+    /// Moved by the viewer
+    #[default]
+    Free,
+    /// Follows the boxes of one player
+    Follow,
+}
+
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    replay: Replay,
+    /// Time since the start of the replay, in seconds
+    time: f32,
+    playing: bool,
+    speed_index: usize,
+    camera_mode: ReplayCameraMode,
+    /// Index of the followed player in the header of the replay
+    followed: usize,
+    /// Displayed entity of each recorded entity id
+    entities: HashMap<u64, Entity>,
+}
+
+impl ReplayPlayback {
+    fn speed(&self) -> f32 {
+        SPEEDS[self.speed_index]
+    }
+
+    fn tick_secs(&self) -> f32 {
+        self.replay.header.tick_duration.as_secs_f32()
+    }
+
+    fn duration(&self) -> f32 {
+        self.replay.frames.len().saturating_sub(1) as f32 * self.tick_secs()
+    }
+
+    fn seek(&mut self, time: f32) {
+        self.time = time.clamp(0.0, self.duration());
+    }
+}
+
+#[derive(Resource)]
+struct ReplayDir(PathBuf);
+
+#[derive(Component)]
+struct ReplayList;
+
+/// Plays the replay at this path when clicked
+#[derive(Component)]
+struct ReplayButton(PathBuf);
+
+/// Marks the entities that only exist while viewing a replay
+#[derive(Component)]
+struct ReplayEntity;
+
+#[derive(Component)]
+struct ReplayText;
+
+#[derive(Component)]
+struct Timeline;
+
+#[derive(Component)]
+struct TimelineProgress;
+
+/// The replays of the directory, most recently modified first
+pub fn list_replays(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut replays: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "replay"))
+        .map(|entry| {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            (modified, entry.path())
+        })
+        .collect();
+    replays.sort_by(|a, b| b.cmp(a));
+    replays.into_iter().map(|(_, path)| path).collect()
+}
+
+fn spawn_replay_list(mut commands: Commands, dir: Res<ReplayDir>) {
+    let replays = list_replays(&dir.0);
+    let text_style = TextStyle {
+        font_size: 24.0,
+        color: FOREGROUND,
+        ..default()
+    };
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    row_gap: Val::Px(8.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            ReplayList,
+        ))
+        .with_children(|node| {
+            node.spawn(TextBundle::from_section(
+                format!("REPLAYS IN {}", dir.0.display()),
+                text_style.clone(),
+            ));
+            if replays.is_empty() {
+                node.spawn(TextBundle::from_section(
+                    "no replay yet",
+                    TextStyle {
+                        color: FOREGROUND_DIM,
+                        ..text_style.clone()
+                    },
+                ));
+            }
+            for path in replays.into_iter().take(MAX_LISTED_REPLAYS) {
+                let name = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                node.spawn((
+                    ButtonBundle {
+                        style: Style {
+                            width: Val::Px(400.0),
+                            height: Val::Px(32.0),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        background_color: BACKGROUND.into(),
+                        ..default()
+                    },
+                    ReplayButton(path),
+                ))
+                .with_children(|button| {
+                    button.spawn(TextBundle::from_section(name, text_style.clone()));
+                });
+            }
+            node.spawn(TextBundle::from_section(
+                "Escape to go back to the menu",
+                TextStyle {
+                    font_size: 16.0,
+                    color: FOREGROUND_DIM,
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn replay_list_buttons(
+    mut commands: Commands,
+    buttons: Query<(&Interaction, &ReplayButton), Changed<Interaction>>,
+    list: Query<Entity, With<ReplayList>>,
+) {
+    let Some((_, button)) = buttons
+        .iter()
+        .find(|(interaction, _)| **interaction == Interaction::Pressed)
+    else {
+        return;
+    };
+    let path = button.0.clone();
+    let replay = match Replay::load(&path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            error!(?e, ?path, "failed to load the replay");
+            return;
+        }
+    };
+    for entity in list.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    start_playback(&mut commands, replay, &path);
+}
+
+fn replay_list_button_style(
+    mut buttons: Query<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<ReplayButton>),
+    >,
+) {
+    for (interaction, mut background_color) in buttons.iter_mut() {
+        *background_color = match *interaction {
+            Interaction::Pressed => BACKGROUND_DIM.into(),
+            Interaction::Hovered => FOREGROUND_DIM.into(),
+            Interaction::None => BACKGROUND.into(),
+        };
+    }
+}
+
+fn leave_replay_list(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut next_game_state: ResMut<NextState<GameState>>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_game_state.set(GameState::Menu);
+    }
+}
+
+fn despawn_replay_list(mut commands: Commands, list: Query<Entity, With<ReplayList>>) {
+    for entity in list.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn start_playback(commands: &mut Commands, replay: Replay, path: &Path) {
+    info!(
+        ?path,
+        map = replay.header.map,
+        frames = replay.frames.len(),
+        "playing replay"
+    );
+
+    // the walls are not recorded, they are the same in every match
+    let corners = [
+        Vec2::new(-WALL_SIZE, -WALL_SIZE),
+        Vec2::new(-WALL_SIZE, WALL_SIZE),
+        Vec2::new(WALL_SIZE, WALL_SIZE),
+        Vec2::new(WALL_SIZE, -WALL_SIZE),
+    ];
+    for (i, start) in corners.iter().enumerate() {
+        commands.spawn((
+            Wall {
+                start: *start,
+                end: corners[(i + 1) % corners.len()],
+            },
+            ColorComponent(Color::WHITE),
+            ReplayEntity,
+        ));
+    }
+
+    commands.insert_resource(ReplayPlayback {
+        replay,
+        time: 0.0,
+        playing: true,
+        speed_index: SPEEDS.iter().position(|s| *s == 1.0).unwrap(),
+        camera_mode: ReplayCameraMode::default(),
+        followed: 0,
+        entities: HashMap::default(),
+    });
+    spawn_hud(commands);
+}
+
+fn spawn_hud(commands: &mut Commands) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(16.0),
+                    width: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    row_gap: Val::Px(8.0),
+                    ..default()
+                },
+                ..default()
+            },
+            ReplayEntity,
+        ))
+        .with_children(|node| {
+            node.spawn((
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                ReplayText,
+            ));
+            node.spawn((
+                ButtonBundle {
+                    style: Style {
+                        width: Val::Px(TIMELINE_WIDTH),
+                        height: Val::Px(12.0),
+                        ..default()
+                    },
+                    background_color: Color::rgb(0.2, 0.2, 0.2).into(),
+                    ..default()
+                },
+                Timeline,
+            ))
+            .with_children(|timeline| {
+                timeline.spawn((
+                    NodeBundle {
+                        style: Style {
+                            width: Val::Percent(0.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        background_color: Color::rgb(0.9, 0.9, 0.9).into(),
+                        ..default()
+                    },
+                    TimelineProgress,
+                ));
+            });
+        });
+}
+
+fn playback_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut next_game_state: ResMut<NextState<GameState>>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_game_state.set(GameState::Menu);
+    }
+    if keys.just_pressed(KeyCode::Space) {
+        // restart from the beginning when pressing play at the end
+        if !playback.playing && playback.time >= playback.duration() {
+            playback.time = 0.0;
+        }
+        playback.playing = !playback.playing;
+    }
+    if keys.just_pressed(KeyCode::ArrowUp) {
+        playback.speed_index = (playback.speed_index + 1).min(SPEEDS.len() - 1);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        playback.speed_index = playback.speed_index.saturating_sub(1);
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        let time = playback.time + SCRUB_STEP_SECS;
+        playback.seek(time);
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        let time = playback.time - SCRUB_STEP_SECS;
+        playback.seek(time);
+    }
+}
+
+/// Seek to the clicked position while the timeline is pressed, so that it can be dragged
+fn scrub_with_timeline(
+    windows: Query<&Window, With<PrimaryWindow>>,
+    timelines: Query<(&Interaction, &Node, &GlobalTransform), With<Timeline>>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    let Some(cursor) = windows.get_single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+    for (interaction, node, transform) in timelines.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        let left = transform.translation().x - node.size().x / 2.0;
+        let ratio = ((cursor.x - left) / node.size().x).clamp(0.0, 1.0);
+        let time = ratio * playback.duration();
+        playback.seek(time);
+    }
+}
+
+fn advance_playback(time: Res<Time>, mut playback: ResMut<ReplayPlayback>) {
+    if !playback.playing {
+        return;
+    }
+    let new_time = playback.time + time.delta_seconds() * playback.speed();
+    playback.seek(new_time);
+    if playback.time >= playback.duration() {
+        playback.playing = false;
+    }
+}
+
+/// Interpolate between the two recorded frames around the current time
+fn apply_frame(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    mut transforms: Query<(&mut Position, &mut Rotation)>,
+) {
+    let playback = &mut *playback;
+    let frames = &playback.replay.frames;
+    if frames.is_empty() {
+        return;
+    }
+    let position = playback.time / playback.tick_secs();
+    let index = (position.floor() as usize).min(frames.len() - 1);
+    let next = (index + 1).min(frames.len() - 1);
+    let t = position - index as f32;
+
+    let current = &frames[index];
+    for entity_frame in &current.entities {
+        // a teleported entity jumps to its new position instead of sliding across the arena
+        let target = frames[next]
+            .entities
+            .iter()
+            .find(|e| e.id == entity_frame.id && !e.teleported)
+            .unwrap_or(entity_frame);
+        let interpolated_position = entity_frame.position.lerp(target.position, t);
+        let interpolated_rotation =
+            Rotation::from_radians(lerp_angle(entity_frame.rotation, target.rotation, t));
+
+        if let Some(entity) = playback.entities.get(&entity_frame.id) {
+            if let Ok((mut position, mut rotation)) = transforms.get_mut(*entity) {
+                position.0 = interpolated_position;
+                *rotation = interpolated_rotation;
+            }
+            continue;
+        }
+        let entity = spawn_replayed_entity(
+            &mut commands,
+            &playback.replay.header.players,
+            entity_frame,
+            interpolated_position,
+            interpolated_rotation,
+        );
+        playback.entities.insert(entity_frame.id, entity);
+    }
+
+    // despawn the entities that are not in the current frame (disconnected players, or when scrubbing back)
+    playback.entities.retain(|id, entity| {
+        let present = current.entities.iter().any(|e| e.id == *id);
+        if !present {
+            commands.entity(*entity).despawn();
+        }
+        present
+    });
+}
+
+/// Interpolate between two angles in radians, along the shortest arc
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let delta =
+        (to - from + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI;
+    from + delta * t
+}
+
+fn spawn_replayed_entity(
+    commands: &mut Commands,
+    players: &[ReplayPlayer],
+    frame: &EntityFrame,
+    position: Vec2,
+    rotation: Rotation,
+) -> Entity {
+    let mut entity = commands.spawn((Position(position), rotation, ReplayEntity));
+    match frame.kind {
+        EntityKind::Player => {
+            // the name and the color that the server assigned during the match
+            if let Some(player) = frame.owner.and_then(|owner| players.get(owner as usize)) {
+                entity.insert((
+                    PlayerId(player.client_id),
+                    PlayerName(player.name.clone()),
+                    ColorComponent(player.color),
+                ));
+            }
+        }
+        EntityKind::Ball => {
+            entity.insert((BallMarker, ColorComponent(Color::AZURE)));
+        }
+    }
+    entity.id()
+}
+
+fn camera_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut wheel: EventReader<MouseWheel>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+) {
+    if keys.just_pressed(KeyCode::KeyC) {
+        playback.camera_mode = match playback.camera_mode {
+            ReplayCameraMode::Free => ReplayCameraMode::Follow,
+            ReplayCameraMode::Follow => ReplayCameraMode::Free,
+        };
+    }
+    if keys.just_pressed(KeyCode::Tab) && !playback.replay.header.players.is_empty() {
+        playback.followed = (playback.followed + 1) % playback.replay.header.players.len();
+        playback.camera_mode = ReplayCameraMode::Follow;
+    }
+
+    let zoom: f32 = wheel.read().map(|event| event.y).sum();
+    let mut direction = Vec2::ZERO;
+    if playback.camera_mode == ReplayCameraMode::Free {
+        for (key, delta) in [
+            (KeyCode::KeyW, Vec2::Y),
+            (KeyCode::KeyS, Vec2::NEG_Y),
+            (KeyCode::KeyA, Vec2::NEG_X),
+            (KeyCode::KeyD, Vec2::X),
+        ] {
+            if keys.pressed(key) {
+                direction += delta;
+            }
+        }
+    }
+    for (mut transform, mut projection) in cameras.iter_mut() {
+        projection.scale = (projection.scale * (1.0 - zoom * ZOOM_STEP)).clamp(0.25, 4.0);
+        let movement = direction.normalize_or_zero()
+            * FREE_CAMERA_SPEED
+            * projection.scale
+            * time.delta_seconds();
+        transform.translation += movement.extend(0.0);
+    }
+}
+
+fn move_camera(
+    playback: Res<ReplayPlayback>,
+    players: Query<(&Position, &PlayerId), With<ReplayEntity>>,
+    mut cameras: Query<&mut Transform, With<Camera2d>>,
+) {
+    if playback.camera_mode != ReplayCameraMode::Follow {
+        return;
+    }
+    let Some(followed) = playback.replay.header.players.get(playback.followed) else {
+        return;
+    };
+    let positions: Vec<Vec2> = players
+        .iter()
+        .filter(|(_, player_id)| player_id.0 == followed.client_id)
+        .map(|(position, _)| position.0)
+        .collect();
+    if positions.is_empty() {
+        return;
+    }
+    let focus = positions.iter().sum::<Vec2>() / positions.len() as f32;
+    for mut transform in cameras.iter_mut() {
+        transform.translation.x = focus.x;
+        transform.translation.y = focus.y;
+    }
+}
+
+fn update_hud(
+    playback: Res<ReplayPlayback>,
+    mut texts: Query<&mut Text, With<ReplayText>>,
+    mut progress: Query<&mut Style, With<TimelineProgress>>,
+) {
+    let duration = playback.duration();
+    for mut text in texts.iter_mut() {
+        let state = if playback.playing {
+            "playing"
+        } else {
+            "paused"
+        };
+        let camera = match playback.camera_mode {
+            ReplayCameraMode::Free => "free camera".to_string(),
+            ReplayCameraMode::Follow => match playback.replay.header.players.get(playback.followed)
+            {
+                Some(player) => format!("following {}", player.name),
+                None => "following nobody".to_string(),
+            },
+        };
+        text.sections[0].value = format!(
+            "{state} {:.2}x | {:.1}s / {duration:.1}s | {camera}",
+            playback.speed(),
+            playback.time,
+        );
+    }
+    let ratio = if duration > 0.0 {
+        playback.time / duration
+    } else {
+        0.0
+    };
+    for mut style in progress.iter_mut() {
+        style.width = Val::Percent(ratio * 100.0);
+    }
+}
+
+fn cleanup_replay(
+    mut commands: Commands,
+    entities: Query<Entity, With<ReplayEntity>>,
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+) {
+    for entity in entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<ReplayPlayback>();
+    // the camera is shared with the menu
+    for (mut transform, mut projection) in cameras.iter_mut() {
+        *transform = Transform::default();
+        projection.scale = 1.0;
+    }
+}
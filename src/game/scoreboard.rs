@@ -0,0 +1,224 @@
+//! Scoreboard of the client: the stats of every player while Tab is held, and the results of the
+//! last match for a few seconds after it ends. The players are grouped by side, with their rating.
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::utils::{Duration, HashMap};
+use lightyear::prelude::client::*;
+use lightyear::prelude::ClientId;
+
+use super::protocol::*;
+use super::rating::Outcome;
+use super::render::player_label;
+use super::stats::{left_outcome, side_score};
+use super::ui::spawn_cell;
+
+/// How long the results stay on screen after the end of a match
+const RESULTS_DURATION: Duration = Duration::from_secs(10);
+const PANEL_BACKGROUND: Color = Color::rgba(0.0, 0.0, 0.0, 0.8);
+const NAME_WIDTH: f32 = 180.0;
+const COLUMN_WIDTH: f32 = 100.0;
+const FONT_SIZE: f32 = 18.0;
+const COLUMNS: [&str; 8] = [
+    "SIDE",
+    "RATING",
+    "TOUCHES",
+    "DISTANCE",
+    "TOP SPEED",
+    "TIME",
+    "BUMPS",
+    "WALLS",
+];
+
+pub struct ScoreboardPlugin;
+
+impl Plugin for ScoreboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, receive_match_results);
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, spawn_scoreboard);
+            app.add_systems(Update, update_scoreboard.after(receive_match_results));
+        }
+    }
+}
+
+/// Final stats of the last match
+#[derive(Resource)]
+pub struct MatchResults {
+    pub stats: Vec<PlayerStats>,
+    /// When the results were received, to hide them after a while
+    received_at: Duration,
+}
+
+fn receive_match_results(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut events: EventReader<MessageEvent<MatchEnded>>,
+) {
+    for event in events.read() {
+        info!("match ended");
+        commands.insert_resource(MatchResults {
+            stats: event.message().0.clone(),
+            received_at: time.elapsed(),
+        });
+    }
+}
+
+#[derive(Component)]
+struct Scoreboard;
+
+#[derive(Component)]
+struct ScoreboardTitle;
+
+/// Parent of one row per player
+#[derive(Component)]
+struct ScoreboardRows;
+
+fn spawn_scoreboard(mut commands: Commands) {
+    commands
+        .spawn((
+            Scoreboard,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    align_self: AlignSelf::Center,
+                    justify_self: JustifySelf::Center,
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(4.0),
+                    padding: UiRect::all(Val::Px(12.0)),
+                    ..default()
+                },
+                background_color: PANEL_BACKGROUND.into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|panel| {
+            panel.spawn((
+                ScoreboardTitle,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 28.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+            ));
+            panel.spawn(NodeBundle::default()).with_children(|header| {
+                spawn_cell(header, "PLAYER", NAME_WIDTH, FONT_SIZE, Color::GRAY);
+                for column in COLUMNS {
+                    spawn_cell(header, column, COLUMN_WIDTH, FONT_SIZE, Color::GRAY);
+                }
+            });
+            panel.spawn((
+                ScoreboardRows,
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        row_gap: Val::Px(2.0),
+                        ..default()
+                    },
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Show the results of the last match if it just ended, otherwise the live stats while Tab is held
+fn update_scoreboard(
+    mut commands: Commands,
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    results: Option<Res<MatchResults>>,
+    live_stats: Query<&PlayerStats>,
+    players: Query<
+        (
+            &PlayerId,
+            Option<&PlayerName>,
+            &ColorComponent,
+            Has<BotMarker>,
+        ),
+        (Without<Predicted>, Without<Interpolated>),
+    >,
+    mut panel: Query<&mut Visibility, With<Scoreboard>>,
+    mut title: Query<&mut Text, With<ScoreboardTitle>>,
+    rows: Query<Entity, With<ScoreboardRows>>,
+) {
+    let results = results.filter(|results| time.elapsed() - results.received_at < RESULTS_DURATION);
+    let (ended, mut stats) = match results {
+        Some(results) => (true, results.stats.clone()),
+        None if keys.pressed(KeyCode::Tab) => (false, live_stats.iter().cloned().collect()),
+        None => {
+            for mut visibility in panel.iter_mut() {
+                *visibility = Visibility::Hidden;
+            }
+            return;
+        }
+    };
+    for mut visibility in panel.iter_mut() {
+        *visibility = Visibility::Visible;
+    }
+    let (left, right) = (
+        side_score(&stats, Side::Left),
+        side_score(&stats, Side::Right),
+    );
+    let heading = match (ended, left_outcome(&stats)) {
+        (false, _) => "SCOREBOARD".to_string(),
+        (true, Outcome::Draw) => "MATCH RESULTS: DRAW".to_string(),
+        (true, Outcome::Win) => format!("MATCH RESULTS: {} WINS", Side::Left.name()),
+        (true, Outcome::Loss) => format!("MATCH RESULTS: {} WINS", Side::Right.name()),
+    };
+    for mut text in title.iter_mut() {
+        text.sections[0].value = format!("{heading}   {left:.1} - {right:.1} TOUCHES PER PLAYER");
+    }
+
+    // the players that left the game are shown with their id, in grey
+    let labels: HashMap<ClientId, (String, Color)> = players
+        .iter()
+        .map(|(player_id, name, color, is_bot)| {
+            (
+                player_id.0,
+                (player_label(player_id, name, is_bot), color.0),
+            )
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        let side = |stats: &PlayerStats| Side::ALL.iter().position(|side| *side == stats.side);
+        side(a)
+            .cmp(&side(b))
+            .then(b.ball_touches.cmp(&a.ball_touches))
+            .then(b.distance.total_cmp(&a.distance))
+    });
+    for rows in rows.iter() {
+        commands.entity(rows).despawn_descendants();
+        commands.entity(rows).with_children(|rows| {
+            for stats in &stats {
+                let (label, color) = labels
+                    .get(&stats.player)
+                    .cloned()
+                    .unwrap_or_else(|| (stats.player.to_string(), Color::GRAY));
+                rows.spawn(NodeBundle::default()).with_children(|row| {
+                    spawn_cell(row, &label, NAME_WIDTH, FONT_SIZE, color);
+                    let values = [
+                        stats.side.name().to_string(),
+                        format!("{:.0}", stats.rating),
+                        stats.ball_touches.to_string(),
+                        format!("{:.0}", stats.distance),
+                        format!("{:.0}", stats.top_speed),
+                        format_time(stats.time_connected),
+                        stats.player_collisions.to_string(),
+                        stats.wall_collisions.to_string(),
+                    ];
+                    for value in values {
+                        spawn_cell(row, &value, COLUMN_WIDTH, FONT_SIZE, Color::WHITE);
+                    }
+                });
+            }
+        });
+    }
+}
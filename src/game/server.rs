@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
 use bevy::app::PluginGroupBuilder;
 use bevy::prelude::*;
@@ -12,18 +13,37 @@ use lightyear::prelude::client::{Confirmed, Predicted};
 pub use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 
+use super::bot::BotPlugin;
+use super::colors::Palette;
+use super::lag_compensation::LagCompensationPlugin;
+use super::lobby::LobbyPlugin;
 use super::protocol::*;
+use super::records::RecordsPlugin;
+use super::settings::{BotSettings, PhysicsSettings, ReplicationPolicy};
 use super::shared::{color_from_id, shared_config, shared_movement_behaviour, FixedSet};
+use super::slots::{PlayerSlots, PlayerSlotsPlugin};
+use super::stats::StatsPlugin;
 use super::{shared, ServerTransports, SharedSettings};
 
+/// How often the server sends a heartbeat to the clients
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
 // Plugin for server-specific logic
 pub struct ExampleServerPlugin {
-    pub predict_all: bool,
-}
-
-#[derive(Resource)]
-pub struct Global {
-    predict_all: bool,
+    pub bots: BotSettings,
+    /// Maximum number of players, across all the transports
+    pub max_players: usize,
+    /// Queue the clients that connect to a full server, instead of rejecting them
+    pub join_queue: bool,
+    /// Colors given to the players whose requested color is taken
+    pub palette: Palette,
+    /// If `None`, the match never ends
+    pub match_duration: Option<Duration>,
+    /// How long the players have to get ready in the lobby before each match; if `None`, there is no
+    /// lobby and the next match starts as soon as one ends
+    pub lobby_duration: Option<Duration>,
+    /// Where the records of the players are saved; if `None`, they are lost when the server stops
+    pub data_dir: Option<PathBuf>,
 }
 
 impl Plugin for ExampleServerPlugin {
@@ -33,8 +53,30 @@ impl Plugin for ExampleServerPlugin {
             LeafwingInputPlugin::<MyProtocol, PlayerActions>::default(),
             // LeafwingInputPlugin::<MyProtocol, AdminActions>::default(),
         ));
-        app.insert_resource(Global {
-            predict_all: self.predict_all,
+        app.add_plugins(LagCompensationPlugin);
+        app.add_plugins(BotPlugin {
+            settings: self.bots,
+        });
+        app.add_plugins(PlayerSlotsPlugin {
+            max_players: self.max_players,
+            join_queue: self.join_queue,
+            palette: self.palette,
+        });
+        app.add_plugins(StatsPlugin {
+            match_duration: self.match_duration,
+            lobby: self.lobby_duration.is_some(),
+        });
+        if let Some(duration) = self.lobby_duration {
+            app.add_plugins(LobbyPlugin {
+                duration,
+                options: MatchOptions {
+                    duration: self.match_duration,
+                    bot_difficulty: self.bots.difficulty,
+                },
+            });
+        }
+        app.add_plugins(RecordsPlugin {
+            dir: self.data_dir.clone(),
         });
         app.add_systems(Startup, init);
         // Re-adding Replicate components to client-replicated entities must be done in this set for proper handling.
@@ -44,14 +86,30 @@ impl Plugin for ExampleServerPlugin {
         );
         // the physics/FixedUpdates systems that consume inputs should be run in this set
         app.add_systems(FixedUpdate, movement.in_set(FixedSet::Main));
-        app.add_systems(Update, handle_disconnections);
+        // after the physics step, so that the teleported state is the one recorded for the tick
+        app.configure_sets(
+            FixedPostUpdate,
+            (TeleportSet::Apply, TeleportSet::Clear).chain(),
+        );
+        app.add_systems(
+            FixedPostUpdate,
+            (
+                apply_teleports.in_set(TeleportSet::Apply),
+                clear_teleports.in_set(TeleportSet::Clear),
+            ),
+        );
+        app.add_systems(
+            Update,
+            (handle_disconnections, send_heartbeats, answer_pings),
+        );
     }
 }
 
 pub fn init(
     mut commands: Commands,
     mut connections: ResMut<ServerConnections>,
-    global: Res<Global>,
+    physics: Res<PhysicsSettings>,
+    policy: Res<ReplicationPolicy>,
 ) {
     connections.start().expect("Failed to start server");
     commands.spawn(
@@ -73,8 +131,8 @@ pub fn init(
     commands.spawn(BallBundle::new(
         Vec2::new(0.0, 0.0),
         Color::AZURE,
-        // if true, we predict the ball on clients
-        global.predict_all,
+        &policy,
+        &physics,
     ));
 }
 
@@ -94,6 +152,44 @@ pub fn handle_disconnections(
     }
 }
 
+/// Send heartbeats with an increasing sequence number on an unreliable channel,
+/// so that clients can measure the packet loss
+pub fn send_heartbeats(
+    time: Res<Time>,
+    mut connection: ResMut<ServerConnectionManager>,
+    mut since_last: Local<Duration>,
+    mut sequence: Local<u32>,
+) {
+    *since_last += time.delta();
+    if *since_last < HEARTBEAT_INTERVAL {
+        return;
+    }
+    *since_last = Duration::ZERO;
+    *sequence = sequence.wrapping_add(1);
+    if let Err(e) = connection.send_message_to_target::<HeartbeatChannel, Heartbeat>(
+        Heartbeat(*sequence),
+        NetworkTarget::All,
+    ) {
+        error!(?e, "failed to send heartbeat");
+    }
+}
+
+/// Echo the pings of the clients, so that they can measure their RTT
+pub fn answer_pings(
+    mut pings: EventReader<MessageEvent<Ping>>,
+    mut connection: ResMut<ServerConnectionManager>,
+) {
+    for ping in pings.read() {
+        let client_id = *ping.context();
+        if let Err(e) = connection.send_message_to_target::<HeartbeatChannel, Pong>(
+            Pong(ping.message().0),
+            NetworkTarget::Single(client_id),
+        ) {
+            error!(?e, ?client_id, "failed to answer ping");
+        }
+    }
+}
+
 /// Read client inputs and move players
 /// NOTE: this system can now be run in both client/server!
 pub fn movement(
@@ -120,9 +216,48 @@ pub fn movement(
     }
 }
 
+/// Moves a box outside of the simulation, where it stops. It is applied at the end of the next tick,
+/// after the physics step, so that the replays know which state of the box didn't come from the
+/// simulation
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Teleport(pub Vec2);
+
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum TeleportSet {
+    /// Move the boxes to their `Teleport` position
+    Apply,
+    /// Remove the `Teleport` components: the systems between the two sets see the boxes that were
+    /// teleported on this tick
+    Clear,
+}
+
+fn apply_teleports(
+    mut teleported: Query<(
+        &Teleport,
+        &mut Position,
+        &mut LinearVelocity,
+        &mut AngularVelocity,
+    )>,
+) {
+    for (teleport, mut position, mut linear_velocity, mut angular_velocity) in teleported.iter_mut()
+    {
+        position.0 = teleport.0;
+        linear_velocity.0 = Vec2::ZERO;
+        angular_velocity.0 = 0.0;
+    }
+}
+
+fn clear_teleports(mut commands: Commands, teleported: Query<Entity, With<Teleport>>) {
+    for entity in teleported.iter() {
+        commands.entity(entity).remove::<Teleport>();
+    }
+}
+
 // Replicate the pre-spawned entities back to the client
 pub fn replicate_players(
-    global: Res<Global>,
+    policy: Res<ReplicationPolicy>,
+    physics: Res<PhysicsSettings>,
+    slots: Res<PlayerSlots>,
     mut commands: Commands,
     mut player_spawn_reader: EventReader<ComponentInsertEvent<PlayerId>>,
 ) {
@@ -131,16 +266,23 @@ pub fn replicate_players(
         let entity = event.entity();
         info!("received player spawn event: {:?}", event);
 
+        // a client without a slot is not allowed to play
+        if !slots.has_slot(client_id) {
+            warn!(?client_id, "ignoring the player of a client without a slot");
+            if let Some(e) = commands.get_entity(entity) {
+                e.despawn_recursive();
+            }
+            continue;
+        }
+
         // for all cursors we have received, add a Replicate component so that we can start replicating it
         // to other clients
         if let Some(mut e) = commands.get_entity(entity) {
-            let mut replicate = Replicate {
-                // we want to replicate back to the original client, since they are using a pre-predicted entity
-                replication_target: NetworkTarget::All,
-                // make sure that all entities that are predicted are part of the same replication group
-                replication_group: REPLICATION_GROUP,
-                ..default()
-            };
+            // we want to replicate back to the original client, since they are using a pre-predicted entity.
+            // The other clients predict or interpolate the player depending on the replication policy.
+            // The spectators interpolate it, whatever the policy
+            let mut replicate =
+                with_spectators(player_replicate(client_id, &policy), slots.spectators());
             // We don't want to replicate the ActionState to the original client, since they are updating it with
             // their own inputs (if you replicate it to the original client, it will be added on the Confirmed entity,
             // which will keep syncing it to the Predicted entity because the ActionState gets updated every tick)!
@@ -150,20 +292,18 @@ pub fn replicate_players(
             // if we receive a pre-predicted entity, only send the prepredicted component back
             // to the original client
             replicate.add_target::<PrePredicted>(NetworkTarget::Single(client_id));
-            if global.predict_all {
-                replicate.prediction_target = NetworkTarget::All;
-                // // if we predict other players, we need to replicate their actions to all clients other than the original one
-                // // (the original client will apply the actions locally)
-                // replicate.disable_replicate_once::<ActionState<PlayerActions>>();
-            } else {
-                // we want the other clients to apply interpolation for the player
-                replicate.interpolation_target = NetworkTarget::AllExceptSingle(client_id);
-            }
             e.insert((
                 replicate,
                 // not all physics components are replicated over the network, so add them on the server as well
-                PhysicsBundle::player(),
+                PhysicsBundle::player(&physics.player),
             ));
+            if let Some(name) = slots.name(client_id) {
+                e.insert(PlayerName(name.to_string()));
+            }
+            // the client already uses this color, but don't trust it
+            if let Some(color) = slots.color(client_id) {
+                e.insert(ColorComponent(color));
+            }
         }
     }
 }
@@ -2,10 +2,15 @@
 use bevy::utils::Duration;
 use std::net::{Ipv4Addr, SocketAddr};
 
+use super::colors::Palette;
+use super::replay::settings_hash;
 #[cfg(not(target_family = "wasm"))]
 use super::server::Certificate;
+use super::slots::max_connections;
 use super::{client, server};
 use async_compat::Compat;
+use bevy::asset::ron;
+use bevy::prelude::{Color, Resource};
 use bevy::tasks::IoTaskPool;
 use lightyear::prelude::client::Authentication;
 #[cfg(not(target_family = "wasm"))]
@@ -46,7 +51,7 @@ pub enum ServerTransports {
     },
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Conditioner {
     /// One way latency in milliseconds
     pub latency_ms: u16,
@@ -56,7 +61,48 @@ pub struct Conditioner {
     pub packet_loss: f32,
 }
 
+/// Typical network conditions, to quickly switch between them from the dev panel
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConditionerPreset {
+    Lan,
+    WiFi,
+    Mobile4G,
+    Bad,
+}
+
+impl ConditionerPreset {
+    pub const ALL: [ConditionerPreset; 4] = [
+        ConditionerPreset::Lan,
+        ConditionerPreset::WiFi,
+        ConditionerPreset::Mobile4G,
+        ConditionerPreset::Bad,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConditionerPreset::Lan => "LAN",
+            ConditionerPreset::WiFi => "Wi-Fi",
+            ConditionerPreset::Mobile4G => "mobile 4G",
+            ConditionerPreset::Bad => "bad",
+        }
+    }
+}
+
 impl Conditioner {
+    pub fn from_preset(preset: ConditionerPreset) -> Self {
+        let (latency_ms, jitter_ms, packet_loss) = match preset {
+            ConditionerPreset::Lan => (1, 0, 0.0),
+            ConditionerPreset::WiFi => (10, 5, 0.005),
+            ConditionerPreset::Mobile4G => (50, 20, 0.01),
+            ConditionerPreset::Bad => (150, 50, 0.05),
+        };
+        Self {
+            latency_ms,
+            jitter_ms,
+            packet_loss,
+        }
+    }
+
     pub fn build(&self) -> LinkConditionerConfig {
         LinkConditionerConfig {
             incoming_latency: bevy::utils::Duration::from_millis(self.latency_ms as u64),
@@ -66,6 +112,23 @@ impl Conditioner {
     }
 }
 
+/// How well the bots play
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BotDifficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+/// Bots controlled by the server, to test the game or to fill empty matches
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct BotSettings {
+    /// Number of bots spawned when the server starts
+    pub count: u64,
+
+    pub difficulty: BotDifficulty,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ServerSettings {
     /// If true, disable any rendering-related plugins
@@ -74,19 +137,90 @@ pub struct ServerSettings {
     /// If true, enable bevy_inspector_egui
     pub inspector: bool,
 
-    /// If true, we will predict the client's entities, but also the ball and other clients' entities!
-    /// This is what is done by RocketLeague (see [video](https://www.youtube.com/watch?v=ueEmiDM94IE))
-    ///
-    /// If false, we will predict the client's entities but simple interpolate everything else.
-    pub predict_all: bool,
-
     /// Possibly add a conditioner to simulate network conditions
     pub conditioner: Option<Conditioner>,
 
+    /// If set, the server records the match and saves the replay in this directory when it stops.
+    /// The replays contain the inputs applied on every tick, so they can be re-simulated to check
+    /// that the simulation is deterministic
+    pub replay_dir: Option<String>,
+
+    /// If set, the server keeps the cumulative stats and the rating of every player in this directory,
+    /// so that they survive restarts
+    pub data_dir: Option<String>,
+
+    /// Server-side bots
+    pub bots: BotSettings,
+
+    /// Maximum number of clients with a player slot, on all the transports together.
+    /// The bots don't take a slot
+    pub max_players: usize,
+
+    /// If true, the clients that connect when the server is full wait in a queue (and watch the game)
+    /// until a slot is free; otherwise they are disconnected. The queue is limited to `slots::MAX_QUEUED`
+    pub join_queue: bool,
+
+    /// Colors given to the players that didn't pick one, or picked one too close to the color of
+    /// another player. `OkabeIto` and `TolBright` are safe for colorblind players
+    pub palette: Palette,
+
+    /// Length of a match, in seconds. At the end the clients get the results, and the stats of the
+    /// players are reset for the next match. If `None`, the match never ends
+    pub match_duration_secs: Option<u64>,
+
+    /// Time that the players have to get ready in the lobby before each match, in seconds. The match
+    /// starts earlier if every player is ready. If `None`, there is no lobby
+    pub lobby_duration_secs: Option<u64>,
+
     /// Which transport to use
     pub transport: Vec<ServerTransports>,
 }
 
+/// How the inputs of the other players are applied to their predicted boxes.
+///
+/// We only receive the inputs of the other players with a delay, so when we predict their boxes we have
+/// to guess what they are pressing right now.
+#[derive(Resource, Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RemoteInputStrategy {
+    /// Keep applying the last received input
+    Repeat,
+    /// Scale the last received input down to zero over this many ticks
+    Decay { ticks: u16 },
+    /// Don't apply any input; the boxes keep moving with their current velocity
+    VelocityOnly,
+}
+
+/// Bounds and speed of the adaptive input delay.
+///
+/// The target delay is the RTT plus a safety margin proportional to the jitter, so that most inputs
+/// reach the server before the client needs them to be confirmed; it is then clamped between the bounds.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct AdaptiveInputDelay {
+    /// Minimum input delay, in ticks. The delay never goes below one tick anyway: lightyear only
+    /// runs its input delay systems when the delay is not zero, so it must not reach zero at runtime
+    pub min_ticks: u16,
+
+    /// Maximum input delay, in ticks. Above a few ticks the game starts to feel sluggish,
+    /// so it is better to accept some mispredictions
+    pub max_ticks: u16,
+
+    /// How many times the jitter is added to the RTT as a safety margin
+    pub jitter_margin: f32,
+
+    /// How long the target delay must differ from the current one before it changes, in milliseconds.
+    /// lightyear only takes a new delay into account when it syncs, so every change reconnects the
+    /// client and must stay rare
+    pub step_interval_ms: u64,
+}
+
+impl AdaptiveInputDelay {
+    /// Clamp a delay between the bounds, and above zero
+    pub fn clamp(&self, ticks: u16) -> u16 {
+        let min = self.min_ticks.max(1);
+        ticks.clamp(min, self.max_ticks.max(min))
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ClientSettings {
     /// If true, enable bevy_inspector_egui
@@ -95,6 +229,14 @@ pub struct ClientSettings {
     /// The client id
     pub client_id: u64,
 
+    /// Display name of the player, shown above its boxes.
+    /// The server makes it unique by adding a number, so several clients can use the same name
+    pub name: String,
+
+    /// Color requested for the boxes of the player. The server gives another one if it is too close to
+    /// the color of another player, or one of its palette if `None`
+    pub color: Option<Color>,
+
     /// The client port to listen on
     pub client_port: u16,
 
@@ -107,6 +249,13 @@ pub struct ClientSettings {
     /// See [this article](https://www.snapnet.dev/docs/core-concepts/input-delay-vs-rollback/) for more information.
     pub input_delay_ticks: u16,
 
+    /// If set, the input delay is adapted at runtime to the measured RTT and jitter,
+    /// and `input_delay_ticks`, clamped between its bounds, is only used as the starting value
+    pub adaptive_input_delay: Option<AdaptiveInputDelay>,
+
+    /// How to apply the inputs of the other players when their boxes are predicted
+    pub remote_input: RemoteInputStrategy,
+
     /// If visual correction is enabled, we don't instantly snapback to the corrected position
     /// when we need to rollback. Instead we interpolated between the current position and the
     /// corrected position.
@@ -122,6 +271,79 @@ pub struct ClientSettings {
 
     /// Possibly add a conditioner to simulate network conditions
     pub conditioner: Option<Conditioner>,
+
+    /// Possibly add a conditioner to simulate network conditions on the packets sent by the client.
+    /// Only applied with the UDP transport, where both conditioners can be changed from the dev panel
+    pub outgoing_conditioner: Option<Conditioner>,
+}
+
+/// Physical material of a kind of body
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct BodyMaterial {
+    /// How bouncy collisions are: 0.0 means no bounce, 1.0 means perfectly elastic
+    pub restitution: f32,
+
+    /// Friction coefficient, used for both static and dynamic friction
+    pub friction: f32,
+
+    /// How fast the linear velocity decays on its own
+    pub linear_damping: f32,
+
+    /// How fast the angular velocity decays on its own
+    pub angular_damping: f32,
+}
+
+/// Physical materials per entity kind.
+/// These are part of the shared settings so that the client predicts collisions
+/// with the exact same materials as the server simulates them.
+#[derive(Resource, Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct PhysicsSettings {
+    pub player: BodyMaterial,
+    pub ball: BodyMaterial,
+    pub wall: BodyMaterial,
+}
+
+/// How the clients see a replicated entity
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ReplicationMode {
+    /// The entity is simulated ahead of the server, in the present of the client
+    Predicted,
+    /// The entity is displayed in the past, by interpolating between the server updates
+    Interpolated,
+    /// The entity only gets the raw server updates
+    Confirmed,
+}
+
+/// How the clients see each class of entities.
+///
+/// Predicting everything is what is done by RocketLeague (see [video](https://www.youtube.com/watch?v=ueEmiDM94IE)):
+/// collisions between predicted entities feel right, but the other players' inputs are only known with a delay.
+/// Interpolating the other entities shows them exactly as the server simulated them, but in the past.
+#[derive(Resource, Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct ReplicationPolicy {
+    /// The boxes of the client. They are pre-spawned by the client, so they have to be predicted
+    pub own_player: ReplicationMode,
+
+    /// The boxes of the other clients
+    pub other_players: ReplicationMode,
+
+    /// The ball
+    pub ball: ReplicationMode,
+
+    /// Any other server-authoritative entity
+    pub props: ReplicationMode,
+}
+
+impl ReplicationPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.own_player != ReplicationMode::Predicted {
+            anyhow::bail!(
+                "the players are pre-predicted by their own client, so `own_player` must be `Predicted`, got {:?}",
+                self.own_player
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
@@ -131,6 +353,21 @@ pub struct SharedSettings {
 
     /// a 32-byte array to authenticate via the Netcode.io protocol
     pub private_key: [u8; 32],
+
+    /// Physical materials of the players, the ball and the walls
+    pub physics: PhysicsSettings,
+
+    /// How the clients see the players, the ball and the props
+    pub replication: ReplicationPolicy,
+}
+
+impl SharedSettings {
+    /// Hash of the settings that change the simulation, stored in the replays
+    pub fn simulation_hash(&self) -> u64 {
+        let serialized = ron::to_string(&(self.physics, self.replication))
+            .expect("the settings can always be serialized");
+        settings_hash(&serialized)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -226,7 +463,12 @@ pub fn get_server_net_configs(settings: &Settings) -> Vec<server::NetConfig> {
                     server_ip: *server_ip,
                     game_port: *game_port,
                     query_port: *query_port,
-                    max_clients: 16,
+                    // the queued clients and the spectators are connected too, and the extra clients must
+                    // connect to receive the reason of their rejection: the player slots do the limiting
+                    max_clients: max_connections(
+                        settings.server.max_players,
+                        settings.server.join_queue,
+                    ),
                     version: "1.0".to_string(),
                 },
                 conditioner: settings
@@ -14,12 +14,22 @@ use lightyear::prelude::TickManager;
 use lightyear::prelude::*;
 use lightyear::transport::io::IoDiagnosticsPlugin;
 
+use crate::audio::GameAudioPlugin;
+
+use super::camera::GameCameraPlugin;
+use super::debug::PredictionDebugPlugin;
 use super::protocol::*;
+use super::render::VisualsPlugin;
+use super::settings::{PhysicsSettings, ReplicationPolicy};
 
 const FRAME_HZ: f64 = 60.0;
-const FIXED_TIMESTEP_HZ: f64 = 64.0;
+pub const FIXED_TIMESTEP_HZ: f64 = 64.0;
 const MAX_VELOCITY: f32 = 200.0;
-const WALL_SIZE: f32 = 350.0;
+pub const WALL_SIZE: f32 = 350.0;
+/// Name of the arena, stored in the replays
+pub const MAP_NAME: &str = "arena";
+/// The clients display the interpolated entities this many server send intervals in the past
+pub const INTERPOLATION_SEND_INTERVAL_RATIO: f32 = 2.0;
 
 pub fn shared_config(mode: Mode) -> SharedConfig {
     SharedConfig {
@@ -41,20 +51,24 @@ pub enum FixedSet {
     Physics,
 }
 
-pub struct SharedPlugin;
+pub struct SharedPlugin {
+    pub physics: PhysicsSettings,
+    pub replication: ReplicationPolicy,
+}
 
 impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
+        // the client and the server must use the same materials, otherwise predicted collisions
+        // won't match the authoritative ones
+        app.insert_resource(self.physics);
+        app.insert_resource(self.replication);
         if app.is_plugin_added::<RenderPlugin>() {
-            app.add_systems(Startup, init_camera);
-
-            // draw after interpolation is done
-            app.add_systems(
-                PostUpdate,
-                draw_elements
-                    .after(InterpolationSet::Interpolate)
-                    .after(PredictionSet::VisualCorrection),
-            );
+            app.add_plugins((
+                GameCameraPlugin,
+                VisualsPlugin,
+                GameAudioPlugin,
+                PredictionDebugPlugin,
+            ));
             app.add_plugins(LogDiagnosticsPlugin {
                 filter: Some(vec![
                     IoDiagnosticsPlugin::BYTES_IN,
@@ -69,7 +83,14 @@ impl Plugin for SharedPlugin {
         app.add_systems(Startup, init);
 
         // physics
-        app.add_plugins(PhysicsPlugins::new(FixedUpdate))
+        let physics_plugins = PhysicsPlugins::new(FixedUpdate).build();
+        // the debug rendering needs the gizmos, which headless apps don't have
+        let physics_plugins = if app.is_plugin_added::<RenderPlugin>() {
+            physics_plugins
+        } else {
+            physics_plugins.disable::<PhysicsDebugPlugin>()
+        };
+        app.add_plugins(physics_plugins)
             .insert_resource(Time::new_with(Physics::fixed_once_hz(FIXED_TIMESTEP_HZ)))
             .insert_resource(Gravity(Vec2::ZERO));
         app.configure_sets(
@@ -116,50 +137,60 @@ pub fn color_from_id(client_id: ClientId) -> Color {
     Color::hsl(h, s, l)
 }
 
-fn init_camera(mut commands: Commands) {
-    commands.spawn(Camera2dBundle::default());
-}
-
-pub fn init(mut commands: Commands) {
+pub fn init(mut commands: Commands, physics: Res<PhysicsSettings>) {
     commands.spawn(WallBundle::new(
         Vec2::new(-WALL_SIZE, -WALL_SIZE),
         Vec2::new(-WALL_SIZE, WALL_SIZE),
         Color::WHITE,
+        &physics,
     ));
     commands.spawn(WallBundle::new(
         Vec2::new(-WALL_SIZE, WALL_SIZE),
         Vec2::new(WALL_SIZE, WALL_SIZE),
         Color::WHITE,
+        &physics,
     ));
     commands.spawn(WallBundle::new(
         Vec2::new(WALL_SIZE, WALL_SIZE),
         Vec2::new(WALL_SIZE, -WALL_SIZE),
         Color::WHITE,
+        &physics,
     ));
     commands.spawn(WallBundle::new(
         Vec2::new(WALL_SIZE, -WALL_SIZE),
         Vec2::new(-WALL_SIZE, -WALL_SIZE),
         Color::WHITE,
+        &physics,
     ));
 }
 
 // This system defines how we update the player's positions when we receive an input
 pub fn shared_movement_behaviour(
+    velocity: Mut<LinearVelocity>,
+    action: &ActionState<PlayerActions>,
+) {
+    shared_movement_behaviour_scaled(velocity, action, 1.0);
+}
+
+/// Same as [`shared_movement_behaviour`], but the input only has `scale` times its normal effect.
+/// This is used to decay the inputs of the other players when we predict them
+pub fn shared_movement_behaviour_scaled(
     mut velocity: Mut<LinearVelocity>,
     action: &ActionState<PlayerActions>,
+    scale: f32,
 ) {
-    const MOVE_SPEED: f32 = 10.0;
+    let move_speed = 10.0 * scale;
     if action.pressed(&PlayerActions::Up) {
-        velocity.y += MOVE_SPEED;
+        velocity.y += move_speed;
     }
     if action.pressed(&PlayerActions::Down) {
-        velocity.y -= MOVE_SPEED;
+        velocity.y -= move_speed;
     }
     if action.pressed(&PlayerActions::Left) {
-        velocity.x -= MOVE_SPEED;
+        velocity.x -= move_speed;
     }
     if action.pressed(&PlayerActions::Right) {
-        velocity.x += MOVE_SPEED;
+        velocity.x += move_speed;
     }
     *velocity = LinearVelocity(velocity.clamp_length_max(MAX_VELOCITY));
 }
@@ -227,7 +258,7 @@ pub fn log() {
     debug!("run physics schedule!");
 }
 
-/// System that draws the player's boxes and cursors
+/// Debug system that draws the physics shapes of the players, the ball and the walls
 pub fn draw_elements(
     mut gizmos: Gizmos,
     players: Query<(&Position, &Rotation, &ColorComponent), (Without<Confirmed>, With<PlayerId>)>,
@@ -260,19 +291,20 @@ pub struct WallBundle {
 
 #[derive(Component)]
 pub struct Wall {
-    start: Vec2,
-    end: Vec2,
+    pub start: Vec2,
+    pub end: Vec2,
 }
 
 impl WallBundle {
-    pub fn new(start: Vec2, end: Vec2, color: Color) -> Self {
+    pub fn new(start: Vec2, end: Vec2, color: Color, physics: &PhysicsSettings) -> Self {
         Self {
             color: ColorComponent(color),
-            physics: PhysicsBundle {
-                collider: Collider::segment(start, end),
-                collider_density: ColliderDensity(1.0),
-                rigid_body: RigidBody::Static,
-            },
+            physics: PhysicsBundle::new(
+                Collider::segment(start, end),
+                1.0,
+                RigidBody::Static,
+                &physics.wall,
+            ),
             wall: Wall { start, end },
         }
     }
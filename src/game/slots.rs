@@ -0,0 +1,405 @@
+//! Player slots of the server.
+//!
+//! The netcode transports accept every client, so the number of players is limited here, for all
+//! the transports together. The transports that do have a limit (Steam) get [`max_connections`],
+//! which is higher than the number of slots, so that the extra clients still connect and learn why
+//! they can't play.
+//! A client sends a `JoinRequest` when it connects, and gets a slot if one is free
+//! (`JoinAccepted`); only then does it spawn its boxes. When the server is full, the client either
+//! waits in the join queue (`JoinQueued`) and gets the next free slot, or receives the reason
+//! (`JoinRejected`) and is disconnected.
+//!
+//! The name of the `JoinRequest` is validated first, and made unique among the players; the server
+//! also picks the color of the player, close to the requested one (see `colors`).
+//! Spectators never take a slot: they are accepted right away, and every entity is interpolated
+//! for them.
+//!
+//! The entities are only replicated to the clients of the [`ARENA_ROOM`], which the clients enter
+//! once they joined. This way the replication targets of a new spectator are known before the
+//! entities get spawned on its side, so nothing that already exists is predicted by it.
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use lightyear::server::room::RoomSystemSets;
+use std::collections::VecDeque;
+
+use super::colors::{assign_color, Palette};
+use super::names::{unique_name, validate_name};
+use super::protocol::*;
+
+/// Rejected clients are disconnected after this delay, so that they receive the reason first
+const REJECT_DELAY: Duration = Duration::from_millis(500);
+/// Maximum number of clients waiting in the join queue
+pub const MAX_QUEUED: usize = 16;
+/// Maximum number of spectators
+pub const MAX_SPECTATORS: usize = 16;
+/// Connections kept free for the clients that only connect to be told that the server is full
+const REJECT_MARGIN: usize = 4;
+
+/// Number of clients that the transports must accept, so that the server does the rejecting
+pub fn max_connections(max_players: usize, join_queue: bool) -> usize {
+    let queued = if join_queue { MAX_QUEUED } else { 0 };
+    max_players + queued + MAX_SPECTATORS + REJECT_MARGIN
+}
+
+pub struct PlayerSlotsPlugin {
+    pub max_players: usize,
+    pub join_queue: bool,
+    pub palette: Palette,
+}
+
+impl Plugin for PlayerSlotsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PlayerSlots {
+            max_players: self.max_players,
+            join_queue: self.join_queue,
+            palette: self.palette,
+            players: Vec::new(),
+            queue: VecDeque::new(),
+            spectators: Vec::new(),
+            names: HashMap::new(),
+            colors: HashMap::new(),
+            pending_kicks: Vec::new(),
+        });
+        app.add_systems(Startup, spawn_slots_text);
+        app.add_systems(
+            Update,
+            (
+                free_slots,
+                assign_slots,
+                disconnect_rejected,
+                enter_arena,
+                update_slots_text,
+            )
+                .chain(),
+        );
+        app.add_systems(
+            PostUpdate,
+            add_entities_to_arena.before(RoomSystemSets::UpdateReplicationCaches),
+        );
+    }
+}
+
+#[derive(Resource)]
+pub struct PlayerSlots {
+    max_players: usize,
+    join_queue: bool,
+    /// Colors given to the players that didn't request one, or requested a taken one
+    palette: Palette,
+    /// Clients with a slot, in the order they joined
+    players: Vec<ClientId>,
+    /// Clients waiting for a slot, first in first out
+    queue: VecDeque<ClientId>,
+    /// Clients that only watch the game
+    spectators: Vec<ClientId>,
+    /// Names of the players and of the clients in the queue
+    names: HashMap<ClientId, String>,
+    /// Colors of the players and of the clients in the queue
+    colors: HashMap<ClientId, Color>,
+    /// Rejected clients, with the time at which they get disconnected
+    pending_kicks: Vec<(ClientId, Duration)>,
+}
+
+impl PlayerSlots {
+    pub fn has_slot(&self, client_id: ClientId) -> bool {
+        self.players.contains(&client_id)
+    }
+
+    pub fn players(&self) -> &[ClientId] {
+        &self.players
+    }
+
+    pub fn queue(&self) -> impl Iterator<Item = &ClientId> {
+        self.queue.iter()
+    }
+
+    pub fn spectators(&self) -> &[ClientId] {
+        &self.spectators
+    }
+
+    /// Validated name of a player, or of a client in the queue
+    pub fn name(&self, client_id: ClientId) -> Option<&str> {
+        self.names.get(&client_id).map(String::as_str)
+    }
+
+    /// Color assigned to a player, or to a client in the queue
+    pub fn color(&self, client_id: ClientId) -> Option<Color> {
+        self.colors.get(&client_id).copied()
+    }
+
+    fn is_full(&self) -> bool {
+        self.players.len() >= self.max_players
+    }
+
+    /// Tell the client why it can't join, and disconnect it a bit later
+    fn reject(
+        &mut self,
+        time: &Time,
+        connection: &mut ServerConnectionManager,
+        client_id: ClientId,
+        reason: RejectReason,
+    ) {
+        send(connection, client_id, JoinMessage::Rejected(reason));
+        self.pending_kicks
+            .push((client_id, time.elapsed() + REJECT_DELAY));
+    }
+}
+
+/// The join messages that the server sends to a client
+enum JoinMessage {
+    Accepted(Option<Color>),
+    Queued(u32),
+    Rejected(RejectReason),
+}
+
+fn send(connection: &mut ServerConnectionManager, client_id: ClientId, message: JoinMessage) {
+    let target = NetworkTarget::Single(client_id);
+    let result = match message {
+        JoinMessage::Accepted(color) => connection
+            .send_message_to_target::<Channel1, JoinAccepted>(JoinAccepted { color }, target),
+        JoinMessage::Queued(position) => {
+            connection.send_message_to_target::<Channel1, JoinQueued>(JoinQueued(position), target)
+        }
+        JoinMessage::Rejected(reason) => connection
+            .send_message_to_target::<Channel1, JoinRejected>(JoinRejected(reason), target),
+    };
+    if let Err(e) = result {
+        error!(?e, ?client_id, "failed to send a join message");
+    }
+}
+
+/// Tell every client in the queue its position
+fn send_queue_positions(slots: &PlayerSlots, connection: &mut ServerConnectionManager) {
+    for (i, client_id) in slots.queue.iter().enumerate() {
+        send(connection, *client_id, JoinMessage::Queued(i as u32 + 1));
+    }
+}
+
+fn assign_slots(
+    time: Res<Time>,
+    mut requests: EventReader<MessageEvent<JoinRequest>>,
+    mut slots: ResMut<PlayerSlots>,
+    mut connection: ResMut<ServerConnectionManager>,
+    bots: Query<&ColorComponent, With<BotMarker>>,
+) {
+    for request in requests.read() {
+        let client_id = *request.context();
+        if slots.has_slot(client_id)
+            || slots.spectators.contains(&client_id)
+            || slots.queue.contains(&client_id)
+        {
+            warn!(?client_id, "ignoring a second join request");
+            continue;
+        }
+        if request.message().spectator {
+            if slots.spectators.len() >= MAX_SPECTATORS {
+                info!(?client_id, "too many spectators, client rejected");
+                slots.reject(&time, &mut connection, client_id, RejectReason::ServerFull);
+                continue;
+            }
+            info!(?client_id, "spectator joined");
+            slots.spectators.push(client_id);
+            send(&mut connection, client_id, JoinMessage::Accepted(None));
+            continue;
+        }
+        let name = match validate_name(&request.message().name) {
+            Ok(name) => unique_name(&name, slots.names.values().map(String::as_str)),
+            Err(e) => {
+                info!(?client_id, %e, "invalid name, client rejected");
+                slots.reject(
+                    &time,
+                    &mut connection,
+                    client_id,
+                    RejectReason::InvalidName(e),
+                );
+                continue;
+            }
+        };
+        if slots.is_full() && (!slots.join_queue || slots.queue.len() >= MAX_QUEUED) {
+            info!(?client_id, "server full, client rejected");
+            slots.reject(&time, &mut connection, client_id, RejectReason::ServerFull);
+            continue;
+        }
+        let taken: Vec<Color> = slots
+            .colors
+            .values()
+            .copied()
+            .chain(bots.iter().map(|color| color.0))
+            .collect();
+        let color = assign_color(request.message().color, &taken, slots.palette);
+        slots.names.insert(client_id, name.clone());
+        slots.colors.insert(client_id, color);
+        if !slots.is_full() {
+            info!(?client_id, ?name, ?color, "player slot assigned");
+            slots.players.push(client_id);
+            send(
+                &mut connection,
+                client_id,
+                JoinMessage::Accepted(Some(color)),
+            );
+        } else {
+            slots.queue.push_back(client_id);
+            info!(
+                ?client_id,
+                position = slots.queue.len(),
+                "server full, client queued"
+            );
+            send(
+                &mut connection,
+                client_id,
+                JoinMessage::Queued(slots.queue.len() as u32),
+            );
+        }
+    }
+}
+
+/// Free the slots of the clients that left, and give them to the clients of the queue
+fn free_slots(
+    mut disconnect_events: EventReader<DisconnectEvent>,
+    mut slots: ResMut<PlayerSlots>,
+    mut connection: ResMut<ServerConnectionManager>,
+) {
+    let mut queue_changed = false;
+    for event in disconnect_events.read() {
+        let client_id = *event.context();
+        slots.players.retain(|id| *id != client_id);
+        slots.spectators.retain(|id| *id != client_id);
+        slots.names.remove(&client_id);
+        slots.colors.remove(&client_id);
+        let queue_len = slots.queue.len();
+        slots.queue.retain(|id| *id != client_id);
+        queue_changed |= slots.queue.len() != queue_len;
+        slots.pending_kicks.retain(|(id, _)| *id != client_id);
+    }
+    while !slots.is_full() {
+        let Some(client_id) = slots.queue.pop_front() else {
+            break;
+        };
+        info!(?client_id, "player slot assigned from the queue");
+        slots.players.push(client_id);
+        let color = slots.color(client_id);
+        send(&mut connection, client_id, JoinMessage::Accepted(color));
+        queue_changed = true;
+    }
+    if queue_changed {
+        send_queue_positions(&slots, &mut connection);
+    }
+}
+
+fn disconnect_rejected(
+    time: Res<Time>,
+    mut slots: ResMut<PlayerSlots>,
+    mut connections: ResMut<ServerConnections>,
+) {
+    let now = time.elapsed();
+    slots.pending_kicks.retain(|(client_id, kick_at)| {
+        if *kick_at > now {
+            return true;
+        }
+        for server in connections.servers.iter_mut() {
+            if server.connected_client_ids().contains(client_id) {
+                if let Err(e) = server.disconnect(*client_id) {
+                    error!(?e, ?client_id, "failed to disconnect the rejected client");
+                }
+            }
+        }
+        false
+    });
+}
+
+/// Every replicated entity is in this room
+pub const ARENA_ROOM: RoomId = RoomId(0);
+
+/// Add the new replicated entities to the arena room
+fn add_entities_to_arena(
+    mut room_manager: ResMut<RoomManager>,
+    replicated: Query<Entity, Added<Replicate>>,
+) {
+    for entity in replicated.iter() {
+        room_manager.room_mut(ARENA_ROOM).add_entity(entity);
+    }
+}
+
+/// Let the clients that joined enter the arena room, so that the entities get spawned for them.
+/// The replication targets are updated for the new spectators first, so that the entities are
+/// interpolated for them from the start
+fn enter_arena(
+    slots: Res<PlayerSlots>,
+    mut room_manager: ResMut<RoomManager>,
+    mut replicated: Query<&mut Replicate>,
+) {
+    let outside_arena = |id: &&ClientId| !room_manager.room(ARENA_ROOM).has_client_id(**id);
+    let new_spectators: Vec<ClientId> = slots
+        .spectators
+        .iter()
+        .filter(outside_arena)
+        .copied()
+        .collect();
+    if !new_spectators.is_empty() {
+        for mut replicate in replicated.iter_mut() {
+            let current = std::mem::take(&mut *replicate);
+            *replicate = with_spectators(current, &new_spectators);
+        }
+    }
+    let new_clients: Vec<ClientId> = slots
+        .players
+        .iter()
+        .chain(slots.queue.iter())
+        .chain(slots.spectators.iter())
+        .filter(outside_arena)
+        .copied()
+        .collect();
+    for client_id in new_clients {
+        room_manager.room_mut(ARENA_ROOM).add_client(client_id);
+    }
+}
+
+/// Lists the players, the join queue and the spectators on the server screen
+#[derive(Component)]
+struct SlotsText;
+
+fn spawn_slots_text(mut commands: Commands) {
+    commands.spawn((
+        SlotsText,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(4.0),
+            left: Val::Px(4.0),
+            ..default()
+        }),
+    ));
+}
+
+fn update_slots_text(slots: Res<PlayerSlots>, mut texts: Query<&mut Text, With<SlotsText>>) {
+    if !slots.is_changed() {
+        return;
+    }
+    let list = |ids: &mut dyn Iterator<Item = &ClientId>| {
+        ids.map(|id| match slots.name(*id) {
+            Some(name) => format!("{name} ({id})"),
+            None => id.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+    };
+    let value = format!(
+        "Players ({}/{}): {}\nQueue: {}\nSpectators: {}",
+        slots.players.len(),
+        slots.max_players,
+        list(&mut slots.players.iter()),
+        list(&mut slots.queue.iter()),
+        list(&mut slots.spectators.iter()),
+    );
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = value.clone();
+    }
+}
@@ -0,0 +1,139 @@
+//! Spectator mode of the client.
+//!
+//! A spectator connects like any other client, but asks the server for no player slot and never spawns
+//! boxes. The server makes it interpolate every entity.
+//!
+//! Controls: C to switch between framing everything and the free camera, F to follow the next player
+//! (Tab shows the scoreboard, like for the players).
+//! The free camera moves with WASD or the arrows and zooms with the mouse wheel.
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use lightyear::prelude::client::Confirmed;
+use lightyear::prelude::ClientId;
+
+use super::camera::{CameraController, CameraMode};
+use super::protocol::*;
+
+const FREE_CAMERA_SPEED: f32 = 400.0;
+const ZOOM_STEP: f32 = 0.1;
+
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Spectating);
+        app.add_systems(Startup, spawn_spectator_text);
+        app.add_systems(Update, (camera_controls, update_spectator_text).chain());
+    }
+}
+
+/// Exists when the client only watches the game
+#[derive(Resource)]
+pub struct Spectating;
+
+#[derive(Component)]
+struct SpectatorText;
+
+/// Clients whose boxes are on screen, in a stable order
+fn visible_players(players: &Query<&PlayerId, Without<Confirmed>>) -> Vec<ClientId> {
+    let mut ids: Vec<ClientId> = players.iter().map(|player_id| player_id.0).collect();
+    ids.sort_by_key(|id| id.to_bits());
+    ids.dedup();
+    ids
+}
+
+fn camera_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut wheel: EventReader<MouseWheel>,
+    players: Query<&PlayerId, Without<Confirmed>>,
+    mut controllers: Query<&mut CameraController>,
+) {
+    let zoom: f32 = wheel.read().map(|event| event.y).sum();
+    let mut direction = Vec2::ZERO;
+    for (keys_for_direction, delta) in [
+        ([KeyCode::KeyW, KeyCode::ArrowUp], Vec2::Y),
+        ([KeyCode::KeyS, KeyCode::ArrowDown], Vec2::NEG_Y),
+        ([KeyCode::KeyA, KeyCode::ArrowLeft], Vec2::NEG_X),
+        ([KeyCode::KeyD, KeyCode::ArrowRight], Vec2::X),
+    ] {
+        if keys.any_pressed(keys_for_direction) {
+            direction += delta;
+        }
+    }
+
+    for mut controller in controllers.iter_mut() {
+        if keys.just_pressed(KeyCode::KeyC) {
+            controller.mode = match controller.mode {
+                CameraMode::Free => CameraMode::FrameAll,
+                _ => CameraMode::Free,
+            };
+        }
+        if keys.just_pressed(KeyCode::KeyF) {
+            let ids = visible_players(&players);
+            let next = match controller.mode {
+                CameraMode::FollowPlayer(current) => ids
+                    .iter()
+                    .position(|id| *id == current)
+                    .map_or(0, |index| index + 1),
+                _ => 0,
+            };
+            controller.mode = match ids.get(next) {
+                Some(id) => CameraMode::FollowPlayer(*id),
+                // after the last player, go back to framing everything
+                None => CameraMode::FrameAll,
+            };
+        }
+        // moving or zooming by hand switches to the free camera
+        if direction != Vec2::ZERO || zoom != 0.0 {
+            controller.mode = CameraMode::Free;
+        }
+        if controller.mode == CameraMode::Free {
+            controller.zoom(1.0 - zoom * ZOOM_STEP);
+            let scale = controller.scale();
+            controller.pan(
+                direction.normalize_or_zero() * FREE_CAMERA_SPEED * scale * time.delta_seconds(),
+            );
+        }
+    }
+}
+
+fn spawn_spectator_text(mut commands: Commands) {
+    commands.spawn((
+        SpectatorText,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(4.0),
+            left: Val::Px(4.0),
+            ..default()
+        }),
+    ));
+}
+
+fn update_spectator_text(
+    controllers: Query<&CameraController, Changed<CameraController>>,
+    mut texts: Query<&mut Text, With<SpectatorText>>,
+) {
+    let Some(controller) = controllers.iter().next() else {
+        return;
+    };
+    let camera = match controller.mode {
+        CameraMode::FollowPlayer(id) => format!("following {id}"),
+        CameraMode::Free => "free camera".to_string(),
+        _ => "whole arena".to_string(),
+    };
+    let value = format!("SPECTATING ({camera})  [C] free camera  [F] next player");
+    for mut text in texts.iter_mut() {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    }
+}
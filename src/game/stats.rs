@@ -0,0 +1,423 @@
+//! Statistics of the players during a match, measured by the server.
+//!
+//! Every player (a client, or a bot) gets an entity with its `PlayerStats` when its first box appears,
+//! on the side with the fewest players. The stats are replicated to every client, which shows them in the
+//! scoreboard. When the match ends, the server sends the final stats to the clients (`MatchEnded`); the
+//! other server plugins get them with the `MatchFinished` event. The next match starts with a `StartMatch`
+//! event, sent right away or by the lobby, and the players are split into sides of similar ratings.
+//! The boxes of a side are moved to its half of the arena when they appear and when a match starts.
+//! Nothing is counted between two matches.
+//!
+//! There are no goals: the side with the most ball touches per player wins, so the ratings measure
+//! how much the players touch the ball rather than how they score.
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap, HashSet};
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+
+use super::bot::Bot;
+use super::protocol::*;
+use super::rating::{balance_sides, Outcome, INITIAL_RATING};
+use super::records::PlayerRecords;
+use super::server::Teleport;
+use super::shared::{Wall, WALL_SIZE};
+
+pub struct StatsPlugin {
+    /// If `None`, the match never ends
+    pub match_duration: Option<Duration>,
+    /// Wait for a `StartMatch` event (from the lobby) before each match, instead of starting
+    /// the next one as soon as a match ends
+    pub lobby: bool,
+}
+
+impl Plugin for StatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<MatchFinished>();
+        app.add_event::<StartMatch>();
+        app.init_resource::<StatsEntities>();
+        app.insert_resource(MatchConfig {
+            duration: self.match_duration,
+            lobby: self.lobby,
+        });
+        app.insert_resource(if self.lobby {
+            MatchState::Waiting
+        } else {
+            MatchState::playing(self.match_duration)
+        });
+        app.configure_sets(Update, (MatchSet::End, MatchSet::Start).chain());
+        // after the physics step, so that the velocities are the ones of this tick
+        app.add_systems(FixedPostUpdate, track_movement.run_if(match_playing));
+        app.add_systems(
+            Update,
+            (
+                spawn_stats.before(MatchSet::End),
+                (track_collisions, track_time)
+                    .run_if(match_playing)
+                    .before(MatchSet::End),
+                end_match.in_set(MatchSet::End),
+                start_match.in_set(MatchSet::Start),
+            ),
+        );
+    }
+}
+
+/// The systems that handle the `MatchFinished` event, or send `StartMatch`, run between these sets
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum MatchSet {
+    /// Send the results of the match
+    End,
+    /// Split the players into sides for the next match
+    Start,
+}
+
+/// Number of ball touches of the players of the side
+fn side_touches(results: &[PlayerStats], side: Side) -> u32 {
+    results
+        .iter()
+        .filter(|stats| stats.side == side)
+        .map(|stats| stats.ball_touches)
+        .sum()
+}
+
+fn side_players(results: &[PlayerStats], side: Side) -> u32 {
+    results.iter().filter(|stats| stats.side == side).count() as u32
+}
+
+/// Ball touches per player of the side
+pub fn side_score(results: &[PlayerStats], side: Side) -> f32 {
+    side_touches(results, side) as f32 / side_players(results, side).max(1) as f32
+}
+
+/// Outcome of the match for the left side. The sides are compared by ball touches per player, so that the
+/// side that gets the odd player doesn't win just because it has one more player touching the ball
+pub fn left_outcome(results: &[PlayerStats]) -> Outcome {
+    // compare the averages without rounding: l / nl > r / nr <=> l * nr > r * nl
+    Outcome::from_points(
+        side_touches(results, Side::Left) * side_players(results, Side::Right).max(1),
+        side_touches(results, Side::Right) * side_players(results, Side::Left).max(1),
+    )
+}
+
+/// Rating of the player, from its record
+fn rating(records: &PlayerRecords, client_id: ClientId) -> f32 {
+    records
+        .get(client_id)
+        .map_or(INITIAL_RATING, |record| record.rating)
+}
+
+/// Sent on the server when a match ends, with the final stats of every player
+#[derive(Event, Debug)]
+pub struct MatchFinished {
+    pub results: Vec<PlayerStats>,
+}
+
+/// Sent on the server to start the next match
+#[derive(Event, Debug)]
+pub struct StartMatch {
+    /// If `None`, the match never ends
+    pub duration: Option<Duration>,
+}
+
+#[derive(Resource)]
+struct MatchConfig {
+    duration: Option<Duration>,
+    lobby: bool,
+}
+
+/// Entity holding the `PlayerStats` of each player of the current match
+#[derive(Resource, Default)]
+struct StatsEntities(HashMap<ClientId, Entity>);
+
+#[derive(Resource)]
+enum MatchState {
+    /// Between two matches
+    Waiting,
+    /// With the time left in the match, if it ends
+    Playing(Option<Timer>),
+}
+
+impl MatchState {
+    fn playing(duration: Option<Duration>) -> Self {
+        MatchState::Playing(duration.map(|duration| Timer::new(duration, TimerMode::Once)))
+    }
+}
+
+fn match_playing(state: Res<MatchState>) -> bool {
+    matches!(*state, MatchState::Playing(_))
+}
+
+/// The players simulated by the server; in host-server mode the entities of the local client are skipped
+type ServerPlayer = (Without<Confirmed>, Without<Predicted>);
+
+/// Where the box at `index` of the `count` boxes of a side starts: in the middle of the half of the
+/// side, spread vertically like the bots
+fn start_position(side: Side, index: usize, count: usize) -> Vec2 {
+    let y = (index as f32 + 0.5) / count.max(1) as f32 * WALL_SIZE - WALL_SIZE / 2.0;
+    Vec2::new(side.sign() * WALL_SIZE / 2.0, y)
+}
+
+fn spawn_stats(
+    mut commands: Commands,
+    mut entities: ResMut<StatsEntities>,
+    records: Res<PlayerRecords>,
+    mut players: Query<
+        (Entity, &PlayerId, &Position, Option<&mut Bot>),
+        (Added<PlayerId>, ServerPlayer),
+    >,
+    stats: Query<&PlayerStats>,
+) {
+    // number of players and total rating of each side
+    let mut sides: HashMap<Side, (usize, f32)> = HashMap::new();
+    for stats in stats.iter() {
+        let side = sides.entry(stats.side).or_default();
+        *side = (side.0 + 1, side.1 + stats.rating);
+    }
+    // sides of the players whose stats are spawned in this frame
+    let mut new_sides: HashMap<ClientId, Side> = HashMap::new();
+    for (entity, player_id, position, bot) in players.iter_mut() {
+        let client_id = player_id.0;
+        if entities.0.contains_key(&client_id) {
+            // the other box of the player goes to the same half
+            let side = new_sides.get(&client_id).copied().or_else(|| {
+                let entity = entities.0[&client_id];
+                stats.get(entity).ok().map(|stats| stats.side)
+            });
+            if let Some(side) = side {
+                move_to_side(&mut commands, entity, side, position, bot);
+            }
+            continue;
+        }
+        // join the side with the fewest players, or the weakest one
+        let side = Side::ALL
+            .into_iter()
+            .min_by(|a, b| {
+                let (a, b) = (sides.get(a), sides.get(b));
+                let (a, b) = (
+                    a.copied().unwrap_or_default(),
+                    b.copied().unwrap_or_default(),
+                );
+                a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+            })
+            .unwrap_or(Side::Left);
+        let rating = rating(&records, client_id);
+        let entry = sides.entry(side).or_default();
+        *entry = (entry.0 + 1, entry.1 + rating);
+        move_to_side(&mut commands, entity, side, position, bot);
+        new_sides.insert(client_id, side);
+        let stats_entity = commands
+            .spawn((
+                PlayerStats::new(client_id, side, rating),
+                Replicate {
+                    replication_target: NetworkTarget::All,
+                    replication_mode: ReplicationMode::Room,
+                    ..default()
+                },
+            ))
+            .id();
+        entities.0.insert(client_id, stats_entity);
+    }
+}
+
+/// Move a box that joins during a match to the half of its side, at the same height. The offset
+/// from the middle of the arena is kept (within a quarter of the half), so that the two boxes of a
+/// client don't land on each other
+fn move_to_side(
+    commands: &mut Commands,
+    entity: Entity,
+    side: Side,
+    position: &Position,
+    bot: Option<Mut<Bot>>,
+) {
+    let offset = position.x.clamp(-WALL_SIZE / 4.0, WALL_SIZE / 4.0);
+    let x = side.sign() * WALL_SIZE / 2.0 + offset;
+    commands
+        .entity(entity)
+        .insert(Teleport(Vec2::new(x, position.y)));
+    if let Some(mut bot) = bot {
+        bot.side = side.sign();
+    }
+}
+
+fn track_movement(
+    time: Res<Time>,
+    entities: Res<StatsEntities>,
+    players: Query<(&PlayerId, &LinearVelocity), ServerPlayer>,
+    mut stats: Query<&mut PlayerStats>,
+) {
+    for (player_id, velocity) in players.iter() {
+        let Some(mut stats) = entities
+            .0
+            .get(&player_id.0)
+            .and_then(|entity| stats.get_mut(*entity).ok())
+        else {
+            continue;
+        };
+        let speed = velocity.length();
+        stats.distance += speed * time.delta_seconds();
+        if speed > stats.top_speed {
+            stats.top_speed = speed;
+        }
+    }
+}
+
+fn track_collisions(
+    mut collisions: EventReader<CollisionStarted>,
+    entities: Res<StatsEntities>,
+    players: Query<&PlayerId, ServerPlayer>,
+    balls: Query<(), With<BallMarker>>,
+    walls: Query<(), With<Wall>>,
+    mut stats: Query<&mut PlayerStats>,
+) {
+    for CollisionStarted(a, b) in collisions.read() {
+        // each side of the collision counts it for its own player
+        for (entity, other) in [(*a, *b), (*b, *a)] {
+            let Ok(player_id) = players.get(entity) else {
+                continue;
+            };
+            let Some(mut stats) = entities
+                .0
+                .get(&player_id.0)
+                .and_then(|entity| stats.get_mut(*entity).ok())
+            else {
+                continue;
+            };
+            if balls.contains(other) {
+                stats.ball_touches += 1;
+            } else if walls.contains(other) {
+                stats.wall_collisions += 1;
+            } else if players
+                .get(other)
+                .is_ok_and(|other_id| other_id.0 != player_id.0)
+            {
+                stats.player_collisions += 1;
+            }
+        }
+    }
+}
+
+fn track_time(
+    time: Res<Time>,
+    entities: Res<StatsEntities>,
+    players: Query<&PlayerId, ServerPlayer>,
+    mut stats: Query<&mut PlayerStats>,
+) {
+    let in_arena: HashSet<ClientId> = players.iter().map(|player_id| player_id.0).collect();
+    for client_id in in_arena {
+        if let Some(mut stats) = entities
+            .0
+            .get(&client_id)
+            .and_then(|entity| stats.get_mut(*entity).ok())
+        {
+            stats.time_connected += time.delta_seconds();
+        }
+    }
+}
+
+/// Send the results to the clients, and start the next match right away if there is no lobby
+fn end_match(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<MatchConfig>,
+    mut state: ResMut<MatchState>,
+    mut entities: ResMut<StatsEntities>,
+    mut connection: ResMut<ServerConnectionManager>,
+    mut finished: EventWriter<MatchFinished>,
+    mut start: EventWriter<StartMatch>,
+    players: Query<&PlayerId, ServerPlayer>,
+    stats: Query<&PlayerStats>,
+) {
+    let MatchState::Playing(Some(timer)) = state.as_mut() else {
+        return;
+    };
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    *state = MatchState::Waiting;
+    let results: Vec<PlayerStats> = entities
+        .0
+        .values()
+        .filter_map(|entity| stats.get(*entity).ok().cloned())
+        .collect();
+    info!(players = results.len(), "match ended");
+    if let Err(e) = connection.send_message_to_target::<Channel1, MatchEnded>(
+        MatchEnded(results.clone()),
+        NetworkTarget::All,
+    ) {
+        error!(?e, "failed to send the match results");
+    }
+    finished.send(MatchFinished { results });
+    if !config.lobby {
+        start.send(StartMatch {
+            duration: config.duration,
+        });
+    }
+
+    // the players that left don't take part in the next match
+    let in_arena: HashSet<ClientId> = players.iter().map(|player_id| player_id.0).collect();
+    entities.0.retain(|client_id, entity| {
+        let stays = in_arena.contains(client_id);
+        if !stays {
+            commands.entity(*entity).despawn();
+        }
+        stays
+    });
+}
+
+/// Reset the stats, balance the sides with the ratings updated by the last match, and move the boxes
+/// of each side to its half
+fn start_match(
+    mut commands: Commands,
+    mut start: EventReader<StartMatch>,
+    mut state: ResMut<MatchState>,
+    entities: Res<StatsEntities>,
+    records: Res<PlayerRecords>,
+    mut stats: Query<&mut PlayerStats>,
+    mut boxes: Query<(Entity, &PlayerId, Option<&mut Bot>), ServerPlayer>,
+) {
+    let Some(start) = start.read().last() else {
+        return;
+    };
+    *state = MatchState::playing(start.duration);
+    let mut players: Vec<(ClientId, f32)> = entities
+        .0
+        .keys()
+        .map(|client_id| (*client_id, rating(&records, *client_id)))
+        .collect();
+    // the same players always get the same sides
+    players.sort_by_key(|(client_id, _)| client_id.to_bits());
+    let (left, right) = balance_sides(&players);
+    let mut sides = HashMap::new();
+    for (side, client_ids) in [(Side::Left, left), (Side::Right, right)] {
+        for client_id in client_ids {
+            sides.insert(client_id, side);
+            let Some(mut stats) = entities
+                .0
+                .get(&client_id)
+                .and_then(|entity| stats.get_mut(*entity).ok())
+            else {
+                continue;
+            };
+            *stats = PlayerStats::new(client_id, side, rating(&records, client_id));
+        }
+    }
+    for side in Side::ALL {
+        let mut side_boxes: Vec<_> = boxes
+            .iter_mut()
+            .filter(|(_, player_id, _)| sides.get(&player_id.0) == Some(&side))
+            .collect();
+        // the same boxes always get the same positions
+        side_boxes.sort_by_key(|(_, player_id, _)| player_id.0.to_bits());
+        let count = side_boxes.len();
+        for (index, (entity, _, bot)) in side_boxes.into_iter().enumerate() {
+            commands
+                .entity(entity)
+                .insert(Teleport(start_position(side, index, count)));
+            if let Some(mut bot) = bot {
+                bot.side = side.sign();
+            }
+        }
+    }
+    info!(?players, duration = ?start.duration, "new match started");
+}
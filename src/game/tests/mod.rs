@@ -0,0 +1,692 @@
+//! Headless integration tests: a server and several clients connected in memory.
+use bevy::app::AppExit;
+use bevy::asset::ron;
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_xpbd_2d::prelude::*;
+use lightyear::prelude::client::{ClientConnectionManager, Confirmed, Interpolated, Predicted};
+use lightyear::prelude::ClientId;
+use std::path::PathBuf;
+
+use super::bot::Bot;
+use super::colors::{too_similar, Palette};
+use super::determinism::verify_replay;
+use super::lag_compensation::ViewTicks;
+use super::latency::Latency;
+use super::leaderboard::{request_leaderboard, LeaderboardTable};
+use super::lobby_screen::send_lobby_request;
+use super::protocol::*;
+use super::replay::{EntityKind, Replay};
+use super::scoreboard::MatchResults;
+use super::server::ServerConfig;
+use super::settings::{BotDifficulty, Conditioner, ReplicationMode, Settings};
+use super::slots::max_connections;
+use stepper::MultiStepper;
+
+mod stepper;
+
+/// Enough frames for the spawned entities to be replicated back and forth
+const REPLICATION_FRAMES: usize = 60;
+
+/// Number of player entities that `client_id` owns on the server
+fn server_player_count(stepper: &mut MultiStepper, client_id: ClientId) -> usize {
+    stepper
+        .server_app
+        .world
+        .query::<&PlayerId>()
+        .iter(&stepper.server_app.world)
+        .filter(|player_id| player_id.0 == client_id)
+        .count()
+}
+
+fn connected_stepper(num_clients: usize) -> MultiStepper {
+    let mut stepper = MultiStepper::new(num_clients);
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    stepper
+}
+
+#[test]
+fn clients_connect() {
+    let mut stepper = MultiStepper::new(2);
+    stepper.wait_for_connection();
+    assert!(stepper.is_connected(0));
+    assert!(stepper.is_connected(1));
+}
+
+#[test]
+fn clients_measure_their_rtt() {
+    // interpolate the ball, so that the client has an interpolation delay
+    let mut stepper = MultiStepper::with_settings(1, |settings| {
+        settings.shared.replication.ball = ReplicationMode::Interpolated;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let latency = stepper.client_apps[0].world.resource::<Latency>();
+    // the pongs come back in a later frame, even without any network delay
+    assert!(latency.rtt() > Duration::ZERO);
+    // lightyear interpolates the entities some ticks behind the latest server tick
+    assert!(latency.interpolation_delay() > Duration::ZERO);
+}
+
+#[test]
+fn clients_report_their_view_tick() {
+    let mut stepper = MultiStepper::with_settings(1, |s| {
+        s.shared.replication.ball = ReplicationMode::Interpolated;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let view_ticks = stepper.server_app.world.resource::<ViewTicks>();
+    // the client sees the ball at least one interpolation delay in the past
+    assert!(view_ticks.rewind_ticks(MultiStepper::client_id(0)) > 0);
+    assert_eq!(view_ticks.rewind_ticks(MultiStepper::client_id(1)), 0);
+}
+
+/// The inputs that the server applies were pressed about one RTT (in both directions) plus one
+/// interpolation delay after the tick of the ball that the client was seeing, however the RTT is split
+#[test]
+fn rewind_covers_an_asymmetric_rtt() {
+    let to_server = Duration::from_millis(100);
+    let mut stepper = MultiStepper::with_settings(1, |s| {
+        s.shared.replication.ball = ReplicationMode::Interpolated;
+        // the conditioners delay the packets that they receive
+        s.server.conditioner = Some(Conditioner {
+            latency_ms: to_server.as_millis() as u16,
+            jitter_ms: 0,
+            packet_loss: 0.0,
+        });
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(3 * REPLICATION_FRAMES);
+
+    let interpolation_delay = stepper.client_apps[0]
+        .world
+        .resource::<Latency>()
+        .interpolation_delay();
+    let tick_duration = stepper
+        .server_app
+        .world
+        .resource::<ServerConfig>()
+        .shared
+        .tick
+        .tick_duration;
+    let rewind = tick_duration
+        * stepper
+            .server_app
+            .world
+            .resource::<ViewTicks>()
+            .rewind_ticks(MultiStepper::client_id(0)) as u32;
+    // half the RTT plus the interpolation delay would be about 50ms short; allow two ticks for the
+    // rounding of the ticks
+    assert!(
+        rewind + 2 * tick_duration >= to_server + interpolation_delay,
+        "rewind of {rewind:?} for an interpolation delay of {interpolation_delay:?}"
+    );
+}
+
+#[test]
+fn pre_predicted_players_are_spawned_on_the_server() {
+    let mut stepper = connected_stepper(2);
+    for index in 0..2 {
+        let client_id = MultiStepper::client_id(index);
+        // every client spawns two boxes
+        assert_eq!(server_player_count(&mut stepper, client_id), 2);
+
+        // the pre-predicted boxes of the client are now confirmed by the server
+        let client_world = &mut stepper.client_apps[index].world;
+        let predicted = client_world
+            .query_filtered::<&PlayerId, With<Predicted>>()
+            .iter(client_world)
+            .filter(|player_id| player_id.0 == client_id)
+            .count();
+        assert_eq!(predicted, 2);
+        let confirmed = client_world
+            .query_filtered::<&PlayerId, With<Confirmed>>()
+            .iter(client_world)
+            .filter(|player_id| player_id.0 == client_id)
+            .count();
+        assert_eq!(confirmed, 2);
+    }
+}
+
+#[test]
+fn players_are_replicated_to_the_other_clients() {
+    let mut stepper = connected_stepper(2);
+    let other_id = MultiStepper::client_id(1);
+    let client_world = &mut stepper.client_apps[0].world;
+    let others: Vec<ColorComponent> = client_world
+        .query_filtered::<(&PlayerId, &ColorComponent), With<Confirmed>>()
+        .iter(client_world)
+        .filter(|(player_id, _)| player_id.0 == other_id)
+        .map(|(_, color)| color.clone())
+        .collect();
+    assert_eq!(others.len(), 2);
+    // the color chosen by the server is replicated
+    let server_color = server_player_color(&mut stepper, other_id);
+    for color in others {
+        assert_eq!(color.0, server_color);
+    }
+}
+
+/// Color of the players of `client_id` on the server
+fn server_player_color(stepper: &mut MultiStepper, client_id: ClientId) -> Color {
+    let world = &mut stepper.server_app.world;
+    world
+        .query::<(&PlayerId, &ColorComponent)>()
+        .iter(world)
+        .find(|(player_id, _)| player_id.0 == client_id)
+        .map(|(_, color)| color.0)
+        .expect("the client has players on the server")
+}
+
+#[test]
+fn players_cannot_pick_similar_colors() {
+    let red = Color::rgb(1.0, 0.0, 0.0);
+    let mut stepper = MultiStepper::with_settings(2, |settings| settings.client.color = Some(red));
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    let first = server_player_color(&mut stepper, MultiStepper::client_id(0));
+    let second = server_player_color(&mut stepper, MultiStepper::client_id(1));
+    // one of them gets the palette color closest to the requested one, the other one a different color
+    let palette_red = Palette::Classic.colors()[0];
+    assert!(first == palette_red || second == palette_red);
+    assert!(!too_similar(first, second));
+}
+
+#[test]
+fn disconnected_players_are_despawned() {
+    let mut stepper = connected_stepper(2);
+    let disconnected_id = MultiStepper::client_id(1);
+    stepper.disconnect(1);
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    assert_eq!(server_player_count(&mut stepper, disconnected_id), 0);
+    assert_eq!(
+        server_player_count(&mut stepper, MultiStepper::client_id(0)),
+        2
+    );
+    // the despawn is replicated to the remaining client
+    let client_world = &mut stepper.client_apps[0].world;
+    let remaining = client_world
+        .query::<&PlayerId>()
+        .iter(client_world)
+        .filter(|player_id| player_id.0 == disconnected_id)
+        .count();
+    assert_eq!(remaining, 0);
+}
+
+#[test]
+fn queued_players_get_the_next_free_slot() {
+    let mut stepper = MultiStepper::with_settings(2, |settings| {
+        settings.server.max_players = 1;
+        settings.server.join_queue = true;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let queued_id = MultiStepper::client_id(1);
+    let (player_id, queued_id) = if server_player_count(&mut stepper, queued_id) == 0 {
+        (0, queued_id)
+    } else {
+        // the clients can connect in any order
+        (1, MultiStepper::client_id(0))
+    };
+    assert_eq!(
+        server_player_count(&mut stepper, MultiStepper::client_id(player_id)),
+        2
+    );
+    assert_eq!(server_player_count(&mut stepper, queued_id), 0);
+
+    stepper.disconnect(player_id);
+    stepper.frame_steps(REPLICATION_FRAMES);
+    assert_eq!(server_player_count(&mut stepper, queued_id), 2);
+}
+
+#[test]
+fn extra_clients_are_rejected_by_the_slots() {
+    // the transports let the extra client connect, so that it learns why it can't play
+    assert!(max_connections(1, false) > 1);
+    let mut stepper = MultiStepper::with_settings(2, |settings| {
+        settings.server.max_players = 1;
+        settings.server.join_queue = false;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let connected = (0..2).filter(|i| stepper.is_connected(*i)).count();
+    assert_eq!(connected, 1);
+}
+
+#[test]
+fn spectators_do_not_take_a_slot() {
+    let mut stepper = MultiStepper::with_spectators(1, 1, |settings| {
+        settings.server.max_players = 1;
+        settings.server.join_queue = false;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let spectator_id = MultiStepper::client_id(1);
+    // the spectator is not rejected, and spawns no box
+    assert!(stepper.is_connected(1));
+    assert_eq!(server_player_count(&mut stepper, spectator_id), 0);
+
+    // it still sees the boxes of the player
+    let player_id = MultiStepper::client_id(0);
+    let client_world = &mut stepper.client_apps[1].world;
+    let players = client_world
+        .query_filtered::<&PlayerId, With<Confirmed>>()
+        .iter(client_world)
+        .filter(|id| id.0 == player_id)
+        .count();
+    assert_eq!(players, 2);
+}
+
+#[test]
+fn spectators_interpolate_existing_entities() {
+    // the ball and the boxes of the player exist before the spectator joins
+    let mut stepper = MultiStepper::with_spectators(1, 1, |_| {});
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    let client_world = &mut stepper.client_apps[1].world;
+    let interpolated = client_world
+        .query_filtered::<(), (With<Interpolated>, Or<(With<PlayerId>, With<BallMarker>)>)>()
+        .iter(client_world)
+        .count();
+    // the two boxes of the player and the ball
+    assert_eq!(interpolated, 3);
+    let predicted = client_world
+        .query_filtered::<(), With<Predicted>>()
+        .iter(client_world)
+        .count();
+    assert_eq!(predicted, 0);
+}
+
+#[test]
+fn players_get_unique_names() {
+    // every client uses the name of the settings
+    let mut stepper = connected_stepper(2);
+    let server_world = &mut stepper.server_app.world;
+    let mut names: Vec<String> = server_world
+        .query::<&PlayerName>()
+        .iter(server_world)
+        .map(|name| name.0.clone())
+        .collect();
+    names.sort();
+    names.dedup();
+    assert_eq!(names, vec!["Player".to_string(), "Player 2".to_string()]);
+
+    // the names are replicated to the clients
+    let client_world = &mut stepper.client_apps[0].world;
+    let replicated = client_world
+        .query_filtered::<&PlayerName, With<Confirmed>>()
+        .iter(client_world)
+        .count();
+    assert_eq!(replicated, 4);
+}
+
+#[test]
+fn ball_moves_after_a_collision() {
+    let mut stepper = connected_stepper(1);
+    let server_world = &mut stepper.server_app.world;
+    let ball_start = server_world
+        .query_filtered::<&Position, (With<BallMarker>, Without<Confirmed>)>()
+        .single(server_world)
+        .0;
+
+    // throw one of the boxes at the ball
+    let mut players = server_world
+        .query_filtered::<(&mut Position, &mut LinearVelocity), (With<PlayerId>, Without<BallMarker>)>();
+    let (mut position, mut velocity) = players.iter_mut(server_world).next().unwrap();
+    position.0 = ball_start - Vec2::new(PLAYER_SIZE / 2.0 + BALL_SIZE + 10.0, 0.0);
+    velocity.0 = Vec2::new(150.0, 0.0);
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    let server_world = &mut stepper.server_app.world;
+    let (ball_position, ball_velocity) = server_world
+        .query_filtered::<(&Position, &LinearVelocity), With<BallMarker>>()
+        .single(server_world);
+    assert!(ball_position.x > ball_start.x);
+    assert!(ball_velocity.x > 0.0);
+
+    // the clients receive the new position of the ball
+    let client_world = &mut stepper.client_apps[0].world;
+    let confirmed_ball = client_world
+        .query_filtered::<&Position, (With<BallMarker>, With<Confirmed>)>()
+        .single(client_world);
+    assert!(confirmed_ball.x > ball_start.x);
+}
+
+/// Give up steering the boxes at the ball after this many frames
+const MAX_STEERING_FRAMES: usize = 1000;
+
+/// An empty directory for the test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bevy_game-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn server_ball_position(stepper: &mut MultiStepper) -> Vec2 {
+    let server_world = &mut stepper.server_app.world;
+    server_world
+        .query_filtered::<&Position, With<BallMarker>>()
+        .single(server_world)
+        .0
+}
+
+/// Press the keys of the client at `index` that move its boxes towards the ball. Both boxes get the
+/// same inputs, so the closest one goes straight at the ball
+fn steer_at_ball(stepper: &mut MultiStepper, index: usize) {
+    let ball = server_ball_position(stepper);
+    let client_id = MultiStepper::client_id(index);
+    let server_world = &mut stepper.server_app.world;
+    let Some(closest) = server_world
+        .query::<(&PlayerId, &Position)>()
+        .iter(server_world)
+        .filter(|(player_id, _)| player_id.0 == client_id)
+        .map(|(_, position)| position.0)
+        .min_by(|a, b| a.distance(ball).total_cmp(&b.distance(ball)))
+    else {
+        return;
+    };
+    let direction = ball - closest;
+    let mut keys = stepper.client_apps[index]
+        .world
+        .resource_mut::<ButtonInput<KeyCode>>();
+    keys.release_all();
+    let pressed = [
+        (direction.y > 0.0, [KeyCode::KeyW, KeyCode::ArrowUp]),
+        (direction.y < 0.0, [KeyCode::KeyS, KeyCode::ArrowDown]),
+        (direction.x < 0.0, [KeyCode::KeyA, KeyCode::ArrowLeft]),
+        (direction.x > 0.0, [KeyCode::KeyD, KeyCode::ArrowRight]),
+    ];
+    for (_, key_codes) in pressed.into_iter().filter(|(pressed, _)| *pressed) {
+        for key_code in key_codes {
+            keys.press(key_code);
+        }
+    }
+}
+
+/// A match recorded by the server, with the inputs of a client hitting the ball, is re-simulated exactly
+#[test]
+fn server_replays_are_deterministic() {
+    let dir = test_dir("server_replays_are_deterministic");
+    let configure = |settings: &mut Settings| {
+        settings.server.replay_dir = Some(dir.to_string_lossy().into_owned());
+        // the determinism harness doesn't run the lag compensation
+        settings.shared.replication.ball = ReplicationMode::Predicted;
+    };
+    let mut stepper = MultiStepper::with_settings(1, configure);
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    let ball_start = server_ball_position(&mut stepper);
+    for _ in 0..MAX_STEERING_FRAMES {
+        if server_ball_position(&mut stepper).distance(ball_start) > 1.0 {
+            break;
+        }
+        steer_at_ball(&mut stepper, 0);
+        stepper.frame_step();
+    }
+    stepper.client_apps[0]
+        .world
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .release_all();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    // the recorder saves the end of the replay when the server stops
+    stepper.server_app.world.send_event(AppExit);
+    stepper.frame_step();
+
+    let mut replays = std::fs::read_dir(&dir).unwrap();
+    let path = replays.next().unwrap().unwrap().path();
+    let replay = Replay::load(&path).unwrap();
+    let ball_positions: Vec<Vec2> = replay
+        .frames
+        .iter()
+        .flat_map(|frame| &frame.entities)
+        .filter(|entity| entity.kind == EntityKind::Ball)
+        .map(|entity| entity.position)
+        .collect();
+    assert!(
+        ball_positions
+            .iter()
+            .any(|position| *position != ball_positions[0]),
+        "the boxes never hit the ball"
+    );
+    assert!(replay
+        .frames
+        .iter()
+        .any(|frame| frame.inputs.iter().any(|input| input.pressed != 0)));
+
+    let mut settings =
+        ron::de::from_str::<Settings>(include_str!("../../../assets/settings.ron")).unwrap();
+    configure(&mut settings);
+    verify_replay(&replay, &settings.shared).unwrap();
+}
+
+/// Stats of the player `client_id`, as replicated to the client at `index`
+fn replicated_stats(stepper: &mut MultiStepper, index: usize, client_id: ClientId) -> PlayerStats {
+    let client_world = &mut stepper.client_apps[index].world;
+    client_world
+        .query_filtered::<&PlayerStats, With<Confirmed>>()
+        .iter(client_world)
+        .find(|stats| stats.player == client_id)
+        .cloned()
+        .expect("the stats of the player are replicated")
+}
+
+#[test]
+fn player_stats_are_replicated() {
+    let mut stepper = connected_stepper(2);
+    for index in 0..2 {
+        for other in 0..2 {
+            let stats = replicated_stats(&mut stepper, index, MultiStepper::client_id(other));
+            assert!(stats.time_connected > 0.0);
+        }
+    }
+}
+
+#[test]
+fn players_are_split_into_sides() {
+    let mut stepper = connected_stepper(2);
+    let sides: Vec<Side> = (0..2)
+        .map(|index| replicated_stats(&mut stepper, 0, MultiStepper::client_id(index)).side)
+        .collect();
+    assert_ne!(sides[0], sides[1]);
+
+    // the boxes of each player are in the half of its side
+    let server_world = &mut stepper.server_app.world;
+    let mut players = server_world.query::<(&PlayerId, &Position)>();
+    for (player_id, position) in players.iter(server_world) {
+        let index = (0..2)
+            .find(|index| MultiStepper::client_id(*index) == player_id.0)
+            .unwrap();
+        assert_eq!(position.x.signum(), sides[index].sign());
+    }
+}
+
+#[test]
+fn ball_touches_are_counted() {
+    let mut stepper = connected_stepper(1);
+    let client_id = MultiStepper::client_id(0);
+    let server_world = &mut stepper.server_app.world;
+    let ball = server_world
+        .query_filtered::<&Position, (With<BallMarker>, Without<Confirmed>)>()
+        .single(server_world)
+        .0;
+    let mut players = server_world
+        .query_filtered::<(&mut Position, &mut LinearVelocity), (With<PlayerId>, Without<BallMarker>)>();
+    let (mut position, mut velocity) = players.iter_mut(server_world).next().unwrap();
+    position.0 = ball - Vec2::new(PLAYER_SIZE / 2.0 + BALL_SIZE + 10.0, 0.0);
+    velocity.0 = Vec2::new(150.0, 0.0);
+    stepper.frame_steps(REPLICATION_FRAMES);
+
+    let stats = replicated_stats(&mut stepper, 0, client_id);
+    assert!(stats.ball_touches >= 1);
+    assert!(stats.distance > 0.0);
+    assert!(stats.top_speed > 0.0);
+}
+
+#[test]
+fn match_results_are_sent_to_the_clients() {
+    let match_duration = 2;
+    let mut stepper = MultiStepper::with_settings(2, |settings| {
+        settings.server.match_duration_secs = Some(match_duration)
+    });
+    stepper.wait_for_connection();
+    // long enough for a full match with both players
+    stepper.frame_steps(60 * 5);
+
+    let results = stepper.client_apps[0].world.resource::<MatchResults>();
+    for index in 0..2 {
+        let client_id = MultiStepper::client_id(index);
+        assert!(results.stats.iter().any(|stats| stats.player == client_id));
+    }
+    // the stats are reset for the next match
+    let server_world = &mut stepper.server_app.world;
+    for stats in server_world.query::<&PlayerStats>().iter(server_world) {
+        assert!(stats.time_connected <= match_duration as f32);
+    }
+}
+
+#[test]
+fn leaderboard_has_the_finished_matches() {
+    let mut stepper =
+        MultiStepper::with_settings(2, |settings| settings.server.match_duration_secs = Some(2));
+    stepper.wait_for_connection();
+    stepper.frame_steps(60 * 5);
+
+    request_leaderboard(
+        &mut stepper.client_apps[0]
+            .world
+            .resource_mut::<ClientConnectionManager>(),
+    );
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let leaderboard = &stepper.client_apps[0]
+        .world
+        .resource::<LeaderboardTable>()
+        .0;
+    assert_eq!(leaderboard.len(), 2);
+    for entry in leaderboard {
+        assert!(entry.matches >= 1);
+        assert!(entry.time_played > 0.0);
+    }
+}
+
+/// Clients connected to a server with a lobby, which waits this long for them to get ready
+fn lobby_stepper(num_clients: usize, lobby_duration: u64) -> MultiStepper {
+    let mut stepper = MultiStepper::with_settings(num_clients, |settings| {
+        settings.server.lobby_duration_secs = Some(lobby_duration)
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    stepper
+}
+
+/// The lobby, as replicated to a client
+fn replicated_lobby(stepper: &mut MultiStepper, index: usize) -> Lobby {
+    let world = &mut stepper.client_apps[index].world;
+    world.query::<&Lobby>().single(world).clone()
+}
+
+fn send_to_lobby(stepper: &mut MultiStepper, index: usize, request: LobbyRequest) {
+    send_lobby_request(
+        &mut stepper.client_apps[index]
+            .world
+            .resource_mut::<ClientConnectionManager>(),
+        request,
+    );
+    stepper.frame_steps(REPLICATION_FRAMES);
+}
+
+#[test]
+fn match_starts_when_everyone_is_ready() {
+    let mut stepper = lobby_stepper(2, 60);
+    let lobby = replicated_lobby(&mut stepper, 1);
+    assert!(lobby.open);
+    assert_eq!(lobby.players.len(), 2);
+    // nothing is counted before the match
+    let client_id = MultiStepper::client_id(0);
+    assert_eq!(
+        replicated_stats(&mut stepper, 0, client_id).time_connected,
+        0.0
+    );
+
+    send_to_lobby(&mut stepper, 0, LobbyRequest::Ready(true));
+    let lobby = replicated_lobby(&mut stepper, 1);
+    assert!(lobby.open);
+    let ready: Vec<ClientId> = lobby
+        .players
+        .iter()
+        .filter(|player| player.ready)
+        .map(|player| player.player)
+        .collect();
+    assert_eq!(ready, vec![client_id]);
+
+    send_to_lobby(&mut stepper, 1, LobbyRequest::Ready(true));
+    assert!(!replicated_lobby(&mut stepper, 0).open);
+    assert!(replicated_stats(&mut stepper, 0, client_id).time_connected > 0.0);
+}
+
+#[test]
+fn match_starts_when_the_countdown_ends() {
+    let mut stepper = lobby_stepper(1, 1);
+    stepper.frame_steps(60 * 2);
+    assert!(!replicated_lobby(&mut stepper, 0).open);
+}
+
+#[test]
+fn only_the_host_changes_the_options() {
+    let mut stepper = lobby_stepper(2, 60);
+    let lobby = replicated_lobby(&mut stepper, 0);
+    let host = (0..2)
+        .find(|index| lobby.host == Some(MultiStepper::client_id(*index)))
+        .unwrap();
+    let options = MatchOptions {
+        duration: None,
+        bot_difficulty: BotDifficulty::Hard,
+    };
+    assert_ne!(lobby.options, options);
+
+    send_to_lobby(&mut stepper, 1 - host, LobbyRequest::Options(options));
+    assert_eq!(replicated_lobby(&mut stepper, 0).options, lobby.options);
+    send_to_lobby(&mut stepper, host, LobbyRequest::Options(options));
+    assert_eq!(replicated_lobby(&mut stepper, 0).options, options);
+}
+
+#[test]
+fn invalid_options_are_refused() {
+    let mut stepper = lobby_stepper(1, 60);
+    let lobby = replicated_lobby(&mut stepper, 0);
+    let options = MatchOptions {
+        duration: Some(Duration::ZERO),
+        ..lobby.options
+    };
+    send_to_lobby(&mut stepper, 0, LobbyRequest::Options(options));
+    assert_eq!(replicated_lobby(&mut stepper, 0).options, lobby.options);
+}
+
+#[test]
+fn bots_play_at_the_chosen_difficulty() {
+    let mut stepper = MultiStepper::with_settings(1, |settings| {
+        settings.server.lobby_duration_secs = Some(60);
+        settings.server.bots.count = 2;
+        settings.server.bots.difficulty = BotDifficulty::Easy;
+    });
+    stepper.wait_for_connection();
+    stepper.frame_steps(REPLICATION_FRAMES);
+    let options = MatchOptions {
+        duration: None,
+        bot_difficulty: BotDifficulty::Hard,
+    };
+    send_to_lobby(&mut stepper, 0, LobbyRequest::Options(options));
+    send_to_lobby(&mut stepper, 0, LobbyRequest::Ready(true));
+    assert!(!replicated_lobby(&mut stepper, 0).open);
+    let world = &mut stepper.server_app.world;
+    let difficulties: Vec<BotDifficulty> = world
+        .query::<&Bot>()
+        .iter(world)
+        .map(|bot| bot.difficulty)
+        .collect();
+    assert_eq!(difficulties, vec![BotDifficulty::Hard; 2]);
+}
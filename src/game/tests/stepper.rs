@@ -0,0 +1,231 @@
+//! Runs a server app and several client apps in the same process, without any window or socket.
+//!
+//! The apps are connected through in-memory channels, and the time only advances when we step them,
+//! so the tests are deterministic.
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use bevy::asset::ron;
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::client::{ClientConnection, ConnectEvent, NetClient};
+use lightyear::prelude::{ClientId, Mode, TransportConfig};
+
+use crate::game::client::ExampleClientPlugin;
+use crate::game::protocol::protocol;
+use crate::game::replay::ReplayRecorderPlugin;
+use crate::game::server::ExampleServerPlugin;
+use crate::game::settings::{build_client_netcode_config, build_server_netcode_config, Settings};
+use crate::game::shared::SharedPlugin;
+use crate::game::{build_client_config, build_server_config, client, server};
+
+/// Give up connecting after this many frames
+const MAX_CONNECTION_FRAMES: usize = 500;
+
+pub struct MultiStepper {
+    pub server_app: App,
+    pub client_apps: Vec<App>,
+    pub frame_duration: Duration,
+    current_time: Instant,
+}
+
+/// Set on the client app when it receives its `ConnectEvent`
+#[derive(Resource, Default)]
+pub struct Connected(pub bool);
+
+impl MultiStepper {
+    pub fn new(num_clients: usize) -> Self {
+        Self::with_settings(num_clients, |_| {})
+    }
+
+    /// Like `new`, with a chance to change the settings of the apps
+    pub fn with_settings(num_clients: usize, configure: impl FnOnce(&mut Settings)) -> Self {
+        Self::with_spectators(num_clients, 0, configure)
+    }
+
+    /// `num_players` clients that play, followed by `num_spectators` clients that only watch
+    pub fn with_spectators(
+        num_players: usize,
+        num_spectators: usize,
+        configure: impl FnOnce(&mut Settings),
+    ) -> Self {
+        let num_clients = num_players + num_spectators;
+        let settings_str = include_str!("../../../assets/settings.ron");
+        let mut settings = ron::de::from_str::<Settings>(settings_str).unwrap();
+        // the tests must not depend on the simulated network conditions, unless they set them
+        settings.client.conditioner = None;
+        settings.server.conditioner = None;
+        settings.client.input_delay_ticks = 0;
+        settings.client.adaptive_input_delay = None;
+        settings.server.bots.count = 0;
+        // the tests must not read or write the records on disk, nor record replays unless they check them
+        settings.server.data_dir = None;
+        settings.server.replay_dir = None;
+        // the matches start right away, unless a test is about the lobby
+        settings.server.lobby_duration_secs = None;
+        configure(&mut settings);
+
+        let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5000);
+        let mut channels = Vec::new();
+        let mut client_apps = Vec::new();
+        for i in 0..num_clients {
+            let client_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6000 + i as u16);
+            let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+            let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
+            channels.push((client_addr, to_server_recv, from_server_send));
+            let net_config = build_client_netcode_config(
+                Self::client_id(i).to_bits(),
+                server_addr,
+                settings.client.conditioner.as_ref(),
+                &settings.shared,
+                TransportConfig::LocalChannel {
+                    recv: from_server_recv,
+                    send: to_server_send,
+                },
+            );
+            client_apps.push(Self::client_app(&settings, net_config, i >= num_players));
+        }
+
+        let mut server_app = App::new();
+        server_app.add_plugins(headless_plugins());
+        let net_config = build_server_netcode_config(
+            settings.server.conditioner.as_ref(),
+            &settings.shared,
+            TransportConfig::Channels { channels },
+        );
+        let server_config = build_server_config(Mode::Separate, vec![net_config]);
+        server_app.add_plugins((
+            server::ServerPlugin::new(server::PluginConfig::new(server_config, protocol())),
+            ExampleServerPlugin {
+                bots: settings.server.bots,
+                max_players: settings.server.max_players,
+                join_queue: settings.server.join_queue,
+                palette: settings.server.palette,
+                match_duration: settings.server.match_duration_secs.map(Duration::from_secs),
+                lobby_duration: settings.server.lobby_duration_secs.map(Duration::from_secs),
+                data_dir: settings.server.data_dir.as_ref().map(PathBuf::from),
+            },
+            SharedPlugin {
+                physics: settings.shared.physics,
+                replication: settings.shared.replication,
+            },
+        ));
+        if let Some(replay_dir) = &settings.server.replay_dir {
+            server_app.add_plugins(ReplayRecorderPlugin {
+                dir: replay_dir.into(),
+                settings_hash: settings.shared.simulation_hash(),
+            });
+        }
+
+        let mut stepper = Self {
+            server_app,
+            client_apps,
+            frame_duration: Duration::from_secs_f64(1.0 / 60.0),
+            current_time: Instant::now(),
+        };
+        stepper.set_time();
+        stepper
+    }
+
+    /// Id of the client at this index
+    pub fn client_id(index: usize) -> ClientId {
+        ClientId::Netcode(index as u64 + 1)
+    }
+
+    fn client_app(settings: &Settings, net_config: client::NetConfig, spectator: bool) -> App {
+        let mut app = App::new();
+        app.add_plugins(headless_plugins());
+        let client_config = build_client_config(settings, Mode::Separate, net_config);
+        app.add_plugins((
+            client::ClientPlugin::new(client::PluginConfig::new(client_config, protocol())),
+            ExampleClientPlugin {
+                remote_input: settings.client.remote_input,
+                spectator,
+                name: settings.client.name.clone(),
+                color: settings.client.color,
+            },
+            SharedPlugin {
+                physics: settings.shared.physics,
+                replication: settings.shared.replication,
+            },
+        ));
+        app.init_resource::<Connected>();
+        app.add_systems(Update, record_connection);
+        app
+    }
+
+    fn set_time(&mut self) {
+        for app in self.apps() {
+            app.insert_resource(TimeUpdateStrategy::ManualInstant(self.current_time));
+        }
+    }
+
+    fn apps(&mut self) -> impl Iterator<Item = &mut App> {
+        std::iter::once(&mut self.server_app).chain(self.client_apps.iter_mut())
+    }
+
+    /// Advance the time by one frame and update every app
+    pub fn frame_step(&mut self) {
+        self.current_time += self.frame_duration;
+        // the bevy time of every app, and the lightyear time, which uses `mock_instant`
+        self.set_time();
+        mock_instant::MockClock::advance(self.frame_duration);
+        for app in self.client_apps.iter_mut() {
+            app.update();
+        }
+        self.server_app.update();
+    }
+
+    pub fn frame_steps(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.frame_step();
+        }
+    }
+
+    pub fn is_connected(&self, index: usize) -> bool {
+        self.client_apps[index].world.resource::<Connected>().0
+    }
+
+    /// Step until every client is connected
+    pub fn wait_for_connection(&mut self) {
+        for _ in 0..MAX_CONNECTION_FRAMES {
+            if (0..self.client_apps.len()).all(|i| self.is_connected(i)) {
+                return;
+            }
+            self.frame_step();
+        }
+        panic!("the clients didn't connect after {MAX_CONNECTION_FRAMES} frames");
+    }
+
+    pub fn disconnect(&mut self, index: usize) {
+        self.client_apps[index]
+            .world
+            .resource_mut::<ClientConnection>()
+            .disconnect()
+            .expect("failed to disconnect");
+        self.client_apps[index].world.resource_mut::<Connected>().0 = false;
+    }
+}
+
+/// The plugins that the game needs, without any rendering or window
+fn headless_plugins() -> (
+    MinimalPlugins,
+    InputPlugin,
+    TransformPlugin,
+    HierarchyPlugin,
+) {
+    (
+        MinimalPlugins,
+        InputPlugin,
+        TransformPlugin,
+        HierarchyPlugin,
+    )
+}
+
+fn record_connection(mut events: EventReader<ConnectEvent>, mut connected: ResMut<Connected>) {
+    if events.read().count() > 0 {
+        connected.0 = true;
+    }
+}
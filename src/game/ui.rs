@@ -0,0 +1,26 @@
+//! Building blocks shared by the screens of the client (scoreboard, leaderboard...).
+use bevy::prelude::*;
+
+/// Text of a table cell; the cells of a column have the same width, so that they line up
+pub fn spawn_cell(
+    parent: &mut ChildBuilder,
+    value: &str,
+    width: f32,
+    font_size: f32,
+    color: Color,
+) {
+    parent.spawn(
+        TextBundle::from_section(
+            value,
+            TextStyle {
+                font_size,
+                color,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            width: Val::Px(width),
+            ..default()
+        }),
+    );
+}
@@ -1,7 +1,7 @@
 #![allow(clippy::type_complexity)]
 
 // mod actions;
-// mod audio;
+mod audio;
 mod game;
 mod loading;
 mod menu;
@@ -10,10 +10,13 @@ mod menu;
 use std::time::Duration;
 
 // use crate::actions::ActionsPlugin;
-// use crate::audio::InternalAudioPlugin;
+use crate::audio::InternalAudioPlugin;
 use crate::loading::LoadingPlugin;
 use crate::menu::MenuPlugin;
 // use crate::player::PlayerPlugin;
+use crate::game::colors::Palette;
+pub use crate::game::determinism;
+pub use crate::game::loadtest;
 use crate::game::GamePlugin;
 
 use bevy::app::App;
@@ -32,6 +35,10 @@ enum GameState {
     Menu,
     /// Looking for a match after menu actions
     Matchmaking,
+    /// Playing back a recorded match
+    Replay,
+    /// Best players of the server hosted on this machine
+    Leaderboard,
 }
 
 #[derive(States, Default, Clone, Eq, PartialEq, Debug, Hash)]
@@ -42,22 +49,43 @@ enum ClientTypeState {
     HostServer { client_id: Option<u64> },
     /// The program will act as a client
     Client { client_id: Option<u64> },
+    /// The program will act as a client that only watches the game
+    Spectator { client_id: Option<u64> },
     #[default]
     /// Game is not running
     NotInGame,
 }
 
-pub struct GameSetupPlugin;
+/// Choices made in the menu, used when the game starts
+#[derive(Resource, Default)]
+struct PlayerProfile {
+    /// Display name; if empty, the name of the settings is used
+    name: String,
+    /// Requested color; if `None`, the color of the settings is used
+    color: Option<Color>,
+    /// Palette of the color picker
+    palette: Palette,
+}
+
+#[derive(Default)]
+pub struct GameSetupPlugin {
+    /// Skip the menu and join the server of the settings as a spectator
+    pub spectate: bool,
+}
 
 impl Plugin for GameSetupPlugin {
     fn build(&self, app: &mut App) {
+        if self.spectate {
+            app.insert_state(ClientTypeState::Spectator { client_id: None });
+        }
         app.init_state::<GameState>()
             .init_state::<ClientTypeState>()
+            .init_resource::<PlayerProfile>()
             .add_plugins((
                 LoadingPlugin,
                 MenuPlugin,
                 GamePlugin,
-                // InternalAudioPlugin,
+                InternalAudioPlugin,
                 // ActionsPlugin,
                 // PlayerPlugin,
             ));
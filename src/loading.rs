@@ -26,6 +26,14 @@ impl Plugin for LoadingPlugin {
 pub struct AudioAssets {
     #[asset(path = "audio/flying.ogg")]
     pub flying: Handle<AudioSource>,
+    #[asset(path = "audio/impact.wav")]
+    pub impact: Handle<AudioSource>,
+    #[asset(path = "audio/click.wav")]
+    pub click: Handle<AudioSource>,
+    #[asset(path = "audio/connect.wav")]
+    pub connect: Handle<AudioSource>,
+    #[asset(path = "audio/disconnect.wav")]
+    pub disconnect: Handle<AudioSource>,
 }
 
 #[derive(AssetCollection, Resource)]
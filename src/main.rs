@@ -7,10 +7,19 @@ use bevy::window::PrimaryWindow;
 use bevy::winit::WinitWindows;
 use bevy::DefaultPlugins;
 use bevy_game::GameSetupPlugin; // ToDo: Replace bevy_game with your new crate name.
+use clap::Parser;
 use std::io::Cursor;
 use winit::window::Icon;
 
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Skip the menu and watch the game of the server in the settings, without playing
+    #[arg(long)]
+    spectate: bool,
+}
+
 fn main() {
+    let cli = Cli::parse();
     App::new()
         .insert_resource(Msaa::Sample8)
         .insert_resource(AssetMetaCheck::Never)
@@ -27,7 +36,9 @@ fn main() {
             ..default()
         }))
         .add_plugins(bevy_framepace::FramepacePlugin)
-        .add_plugins(GameSetupPlugin)
+        .add_plugins(GameSetupPlugin {
+            spectate: cli.spectate,
+        })
         .add_systems(Startup, set_window_icon)
         .run();
 }
@@ -1,8 +1,10 @@
+use crate::audio::{AudioVolumes, VolumeKind};
+use crate::game::colors::Palette;
 use crate::GameState;
-use crate::{loading::TextureAssets, ClientTypeState};
+use crate::{loading::TextureAssets, ClientTypeState, PlayerProfile};
 use bevy::prelude::*;
 use bevy_simple_text_input::{
-    TextInputBundle, TextInputPlugin, TextInputSubmitEvent, TextInputValue,
+    TextInputBundle, TextInputInactive, TextInputPlugin, TextInputSubmitEvent, TextInputValue,
 };
 use local_ip_address::local_ip;
 
@@ -12,20 +14,31 @@ pub struct MenuPlugin;
 /// The menu is only drawn during the State `GameState::Menu` and is removed when that state is exited
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(OnEnter(GameState::Menu), setup_menu)
-            .add_systems(
-                Update,
-                (button_system, button_style_system).run_if(in_state(GameState::Menu)),
+        app.add_systems(
+            OnEnter(GameState::Menu),
+            (setup_menu, join_from_command_line),
+        )
+        .add_systems(
+            Update,
+            (
+                focus_text_inputs,
+                button_system,
+                button_style_system,
+                color_swatch_system,
+                volume_text_system,
             )
-            .add_plugins(TextInputPlugin)
-            .add_systems(OnExit(GameState::Menu), cleanup_menu);
+                .run_if(in_state(GameState::Menu)),
+        )
+        .add_plugins(TextInputPlugin)
+        .add_systems(OnExit(GameState::Menu), cleanup_menu);
     }
 }
 
-const BACKGROUND_DIM: Color = Color::rgb(0.2, 0.2, 0.2);
-const BACKGROUND: Color = Color::rgb(0.0, 0.0, 0.0);
-const FOREGROUND_DIM: Color = Color::rgb(0.5, 0.5, 0.5);
-const FOREGROUND: Color = Color::rgb(0.9, 0.9, 0.9);
+// also used by the in-game screens, such as the lobby
+pub(crate) const BACKGROUND_DIM: Color = Color::rgb(0.2, 0.2, 0.2);
+pub(crate) const BACKGROUND: Color = Color::rgb(0.0, 0.0, 0.0);
+pub(crate) const FOREGROUND_DIM: Color = Color::rgb(0.5, 0.5, 0.5);
+pub(crate) const FOREGROUND: Color = Color::rgb(0.9, 0.9, 0.9);
 
 #[derive(Component)]
 struct Menu;
@@ -33,17 +46,42 @@ struct Menu;
 #[derive(Component)]
 struct JoinButton;
 
+#[derive(Component)]
+struct AddressInput;
+
+#[derive(Component)]
+struct NameInput;
+
+/// Button picking the color of this index in the palette of the profile
+#[derive(Component)]
+struct ColorSwatch(usize);
+
+#[derive(Component)]
+struct PaletteText;
+
+/// Volume changes when pressing the `-` and `+` buttons
+const VOLUME_STEP: f64 = 0.1;
+
 enum MenuAction {
     Host,
     Join,
+    Spectate,
+    Replay,
+    Leaderboard,
+    Volume { kind: VolumeKind, delta: f64 },
+    Palette,
+    Color(usize),
 }
 
+#[derive(Component)]
+struct VolumeText(VolumeKind);
+
 #[derive(Component)]
 struct MenuButton {
     action: MenuAction,
 }
 
-fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>) {
+fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>, profile: Res<PlayerProfile>) {
     info!("menu");
     commands.spawn(Camera2dBundle::default());
 
@@ -91,7 +129,7 @@ fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>) {
             Menu,
         ))
         .with_children(|node| {
-            // address input field
+            // name input field, focused first
             node.spawn((
                 NodeBundle {
                     style: Style {
@@ -103,11 +141,102 @@ fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>) {
                     background_color: BACKGROUND.into(),
                     ..default()
                 },
-                TextInputBundle::default().with_text_style(TextStyle {
-                    font_size: 24.,
-                    color: FOREGROUND,
+                TextInputBundle::default()
+                    .with_text_style(TextStyle {
+                        font_size: 24.,
+                        color: FOREGROUND,
+                        ..default()
+                    })
+                    .with_value(profile.name.clone()),
+                Interaction::None,
+                NameInput,
+            ));
+
+            // color picker: the server may give another color if it is taken
+            node.spawn(NodeBundle {
+                style: Style {
+                    width: Val::Px(300.0),
+                    column_gap: Val::Px(4.0),
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|row| {
+                row.spawn((
+                    ButtonBundle {
+                        style: Style {
+                            flex_grow: 1.0,
+                            height: Val::Px(32.0),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        background_color: BACKGROUND.into(),
+                        ..Default::default()
+                    },
+                    MenuButton {
+                        action: MenuAction::Palette,
+                    },
+                ))
+                .with_children(|parent| {
+                    parent.spawn((
+                        TextBundle::from_section(
+                            "",
+                            TextStyle {
+                                font_size: 16.0,
+                                color: FOREGROUND,
+                                ..default()
+                            },
+                        ),
+                        PaletteText,
+                    ));
+                });
+                let max_colors = Palette::ALL
+                    .iter()
+                    .map(|palette| palette.colors().len())
+                    .max()
+                    .unwrap_or_default();
+                for index in 0..max_colors {
+                    row.spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(20.0),
+                                height: Val::Px(20.0),
+                                border: UiRect::all(Val::Px(2.0)),
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                        MenuButton {
+                            action: MenuAction::Color(index),
+                        },
+                        ColorSwatch(index),
+                    ));
+                }
+            });
+
+            // address input field
+            node.spawn((
+                NodeBundle {
+                    style: Style {
+                        width: Val::Px(300.0),
+                        border: UiRect::all(Val::Px(1.0)),
+                        padding: UiRect::all(Val::Px(4.0)),
+                        ..default()
+                    },
+                    background_color: BACKGROUND.into(),
                     ..default()
-                }),
+                },
+                TextInputBundle::default()
+                    .with_text_style(TextStyle {
+                        font_size: 24.,
+                        color: FOREGROUND,
+                        ..default()
+                    })
+                    .with_inactive(true),
+                Interaction::None,
+                AddressInput,
             ));
 
             // join button
@@ -142,6 +271,38 @@ fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>) {
                 ));
             });
 
+            // spectate button
+            node.spawn((
+                ButtonBundle {
+                    style: Style {
+                        margin: UiRect {
+                            bottom: Val::Px(12.0),
+                            ..default()
+                        },
+                        width: Val::Px(300.0),
+                        height: Val::Px(32.0),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    background_color: BACKGROUND.into(),
+                    ..Default::default()
+                },
+                MenuButton {
+                    action: MenuAction::Spectate,
+                },
+            ))
+            .with_children(|parent| {
+                parent.spawn(TextBundle::from_section(
+                    "SPECTATE",
+                    TextStyle {
+                        font_size: 24.0,
+                        color: FOREGROUND,
+                        ..default()
+                    },
+                ));
+            });
+
             // host button
             node.spawn((
                 ButtonBundle {
@@ -169,6 +330,142 @@ fn setup_menu(mut commands: Commands, textures: Res<TextureAssets>) {
                     },
                 ));
             });
+
+            // leaderboard button
+            node.spawn((
+                ButtonBundle {
+                    style: Style {
+                        width: Val::Px(300.0),
+                        height: Val::Px(32.0),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    background_color: BACKGROUND.into(),
+                    ..Default::default()
+                },
+                MenuButton {
+                    action: MenuAction::Leaderboard,
+                },
+            ))
+            .with_children(|parent| {
+                parent.spawn(TextBundle::from_section(
+                    "LEADERBOARD",
+                    TextStyle {
+                        font_size: 24.0,
+                        color: FOREGROUND,
+                        ..default()
+                    },
+                ));
+            });
+
+            // replay button
+            node.spawn((
+                ButtonBundle {
+                    style: Style {
+                        margin: UiRect {
+                            bottom: Val::Px(12.0),
+                            ..default()
+                        },
+                        width: Val::Px(300.0),
+                        height: Val::Px(32.0),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    background_color: BACKGROUND.into(),
+                    ..Default::default()
+                },
+                MenuButton {
+                    action: MenuAction::Replay,
+                },
+            ))
+            .with_children(|parent| {
+                parent.spawn(TextBundle::from_section(
+                    "REPLAY",
+                    TextStyle {
+                        font_size: 24.0,
+                        color: FOREGROUND,
+                        ..default()
+                    },
+                ));
+            });
+
+            // volume controls
+            for (kind, label) in [
+                (VolumeKind::Master, "MASTER"),
+                (VolumeKind::Sfx, "SFX"),
+                (VolumeKind::Music, "MUSIC"),
+            ] {
+                node.spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Px(300.0),
+                        column_gap: Val::Px(8.0),
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|row| {
+                    row.spawn(
+                        TextBundle::from_section(
+                            label,
+                            TextStyle {
+                                font_size: 24.0,
+                                color: FOREGROUND,
+                                ..default()
+                            },
+                        )
+                        .with_style(Style {
+                            flex_grow: 1.0,
+                            ..default()
+                        }),
+                    );
+                    spawn_volume_button(row, kind, -VOLUME_STEP, "-");
+                    row.spawn((
+                        TextBundle::from_section(
+                            "",
+                            TextStyle {
+                                font_size: 24.0,
+                                color: FOREGROUND,
+                                ..default()
+                            },
+                        ),
+                        VolumeText(kind),
+                    ));
+                    spawn_volume_button(row, kind, VOLUME_STEP, "+");
+                });
+            }
+        });
+}
+
+fn spawn_volume_button(parent: &mut ChildBuilder, kind: VolumeKind, delta: f64, label: &str) {
+    parent
+        .spawn((
+            ButtonBundle {
+                style: Style {
+                    width: Val::Px(32.0),
+                    height: Val::Px(32.0),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..Default::default()
+                },
+                background_color: BACKGROUND.into(),
+                ..Default::default()
+            },
+            MenuButton {
+                action: MenuAction::Volume { kind, delta },
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                label,
+                TextStyle {
+                    font_size: 24.0,
+                    color: FOREGROUND,
+                    ..default()
+                },
+            ));
         });
 }
 
@@ -176,12 +473,19 @@ fn button_system(
     interaction_query: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
     mut next_game_state: ResMut<NextState<GameState>>,
     mut next_client_type_state: ResMut<NextState<ClientTypeState>>,
-    text_input_query: Query<&TextInputValue>,
+    text_input_query: Query<&TextInputValue, With<AddressInput>>,
+    name_input_query: Query<&TextInputValue, With<NameInput>>,
+    mut profile: ResMut<PlayerProfile>,
+    mut volumes: ResMut<AudioVolumes>,
 ) {
     for (interaction, menu_button) in &interaction_query {
         if !matches!(interaction, Interaction::Pressed) {
             continue;
         }
+        // the server validates the name; an empty one means the name of the settings
+        if let Ok(name) = name_input_query.get_single() {
+            profile.name = name.0.trim().to_string();
+        }
 
         match menu_button.action {
             MenuAction::Host => {
@@ -197,14 +501,67 @@ fn button_system(
                 next_game_state.set(GameState::Matchmaking);
                 next_client_type_state.set(ClientTypeState::Client { client_id: () });
             }
+            MenuAction::Spectate => {
+                next_game_state.set(GameState::Matchmaking);
+                next_client_type_state.set(ClientTypeState::Spectator { client_id: None });
+            }
+            MenuAction::Replay => {
+                next_game_state.set(GameState::Replay);
+            }
+            MenuAction::Leaderboard => {
+                next_game_state.set(GameState::Leaderboard);
+            }
+            MenuAction::Volume { kind, delta } => {
+                volumes.change(kind, delta);
+            }
+            MenuAction::Palette => {
+                profile.palette = profile.palette.next();
+            }
+            MenuAction::Color(index) => {
+                let color = profile.palette.colors().get(index).copied();
+                // clicking the selected color lets the server choose
+                profile.color = if profile.color == color { None } else { color };
+            }
         }
     }
 }
 
+/// Only the clicked text input receives the typed text
+fn focus_text_inputs(
+    clicked: Query<(Entity, &Interaction), (Changed<Interaction>, With<TextInputValue>)>,
+    mut inputs: Query<(Entity, &mut TextInputInactive)>,
+) {
+    let Some(focused) = clicked
+        .iter()
+        .find(|(_, interaction)| **interaction == Interaction::Pressed)
+        .map(|(entity, _)| entity)
+    else {
+        return;
+    };
+    for (entity, mut inactive) in inputs.iter_mut() {
+        inactive.0 = entity != focused;
+    }
+}
+
+/// The client type is already chosen when the game is started with `--spectate`; only the first time the
+/// menu opens, so that leaving the matchmaking screen comes back to the menu
+fn join_from_command_line(
+    client_type_state: Res<State<ClientTypeState>>,
+    mut next_game_state: ResMut<NextState<GameState>>,
+    mut opened: Local<bool>,
+) {
+    if std::mem::replace(&mut *opened, true) {
+        return;
+    }
+    if matches!(client_type_state.get(), ClientTypeState::Spectator { .. }) {
+        next_game_state.set(GameState::Matchmaking);
+    }
+}
+
 fn button_style_system(
     mut interaction_query: Query<
         (&Interaction, &mut BorderColor, &mut BackgroundColor),
-        (Changed<Interaction>, With<Button>),
+        (Changed<Interaction>, With<Button>, Without<ColorSwatch>),
     >,
 ) {
     for (interaction, mut border_color, mut background_color) in &mut interaction_query {
@@ -222,6 +579,49 @@ fn button_style_system(
     }
 }
 
+/// Show the colors of the palette of the profile, with a border around the selected one
+fn color_swatch_system(
+    profile: Res<PlayerProfile>,
+    mut swatches: Query<(
+        &ColorSwatch,
+        &mut Style,
+        &mut BackgroundColor,
+        &mut BorderColor,
+    )>,
+    mut palette_text: Query<&mut Text, With<PaletteText>>,
+    new_swatches: Query<(), Added<ColorSwatch>>,
+) {
+    if !profile.is_changed() && new_swatches.is_empty() {
+        return;
+    }
+    let colors = profile.palette.colors();
+    for (swatch, mut style, mut background_color, mut border_color) in &mut swatches {
+        let Some(color) = colors.get(swatch.0) else {
+            style.display = Display::None;
+            continue;
+        };
+        style.display = Display::Flex;
+        *background_color = (*color).into();
+        *border_color = if profile.color == Some(*color) {
+            FOREGROUND.into()
+        } else {
+            BACKGROUND.into()
+        };
+    }
+    for mut text in &mut palette_text {
+        text.sections[0].value = profile.palette.name().to_string();
+    }
+}
+
+fn volume_text_system(volumes: Res<AudioVolumes>, mut text_query: Query<(&mut Text, &VolumeText)>) {
+    for (mut text, volume_text) in &mut text_query {
+        let value = format!("{:>3.0}%", volumes.get(volume_text.0) * 100.0);
+        if text.sections[0].value != value {
+            text.sections[0].value = value;
+        }
+    }
+}
+
 fn cleanup_menu(mut commands: Commands, menu: Query<Entity, With<Menu>>) {
     for entity in menu.iter() {
         commands.entity(entity).despawn_recursive();